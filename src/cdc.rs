@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use redis::{Arg, Cmd};
+use redis_event::{Event, EventHandler};
+
+use crate::checkpoint::Flush;
+use crate::command;
+use crate::command::CommandConverter;
+use crate::conflict::Conflict;
+use crate::filter::Filter;
+use crate::rename::Renamer;
+use crate::restore::Restore;
+use crate::source::SourceReader;
+
+const BASE64_CHARS: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BUFFER_SIZE: usize = 64 * 1024;
+
+type Output = Arc<Mutex<Box<dyn Write + Send>>>;
+
+lazy_static! {
+    // 有多个源时, 各个源的handler共用同一个输出, 每次写入的都是完整的行
+    static ref OUTPUTS: Mutex<HashMap<String, Output>> = Mutex::new(HashMap::new());
+}
+
+// --output-json: 不写入目的Redis, 而是将每个RDB对象及AOF命令(经过过滤、db映射及重命名之后)以JSON格式逐行输出至
+// 标准输出、文件或Unix socket, 如:
+// {"source":"127.0.0.1:6379","phase":"aof","db":0,"command":"SET","key":"foo","args":["foo","bar"],"offset":1024,"timestamp":1628217470123}
+// RDB对象与写入目的Redis时相同, 转换为SET/RPUSH/SADD/ZADD/HMSET/XADD等命令输出.
+// offset为处理该命令时listener已向源Redis确认的复制offset(由relay记录), 不会超过该命令之后的offset,
+// 从此offset继续复制不会遗漏之后的命令; RDB阶段及尚未确认过offset时为-1.
+// 源Redis使用TLS时无法经由relay获取, 为最近一次listener停止时的offset
+pub(crate) struct JsonEventHandler {
+    output: Output,
+    buf: Vec<u8>,
+    source: String,
+    base64: bool,
+    filter: Filter,
+    renamer: Renamer,
+    chunk_size: usize,
+    offset: Arc<AtomicI64>,
+    phase: &'static str,
+    db: i64,
+    flush_interval: Duration,
+    flushed: Instant,
+}
+
+impl EventHandler for JsonEventHandler {
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::RDB(rdb) => {
+                self.phase = "rdb";
+                self.handle_rdb(rdb);
+            }
+            Event::AOF(cmd) => {
+                self.phase = "aof";
+                self.handle_aof(cmd);
+            }
+        }
+        if self.flushed.elapsed() >= self.flush_interval {
+            self.flush();
+        }
+    }
+}
+
+impl Drop for JsonEventHandler {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl Flush for JsonEventHandler {
+    fn flush(&mut self) {
+        let mut output = self.output.lock().unwrap();
+        if let Err(err) = output.write_all(&self.buf).and_then(|_| output.flush()) {
+            panic!("输出JSON失败: {}", err)
+        }
+        self.buf.clear();
+        self.flushed = Instant::now();
+    }
+}
+
+impl CommandConverter for JsonEventHandler {
+    fn execute(&mut self, cmd: Cmd, _: Option<&[u8]>) {
+        let line = self.to_json(&cmd);
+        self.buf.extend_from_slice(line.as_bytes());
+        if self.buf.len() >= BUFFER_SIZE {
+            self.flush();
+        }
+    }
+
+    fn filter(&mut self) -> &mut Filter {
+        &mut self.filter
+    }
+
+    fn renamer(&self) -> &Renamer {
+        &self.renamer
+    }
+
+    fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    fn restore(&self) -> Option<&Restore> {
+        None
+    }
+
+    fn conflict(&mut self) -> Option<&mut Conflict> {
+        None
+    }
+
+    fn source(&mut self) -> Option<&mut SourceReader> {
+        None
+    }
+
+    fn swap_db(&mut self, db: i32) {
+        self.db = db as i64;
+    }
+}
+
+impl JsonEventHandler {
+    fn to_json(&self, cmd: &Cmd) -> String {
+        let args: Vec<&[u8]> = cmd
+            .args_iter()
+            .filter_map(|arg| match arg {
+                Arg::Simple(arg) => Some(arg),
+                Arg::Cursor => None,
+            })
+            .collect();
+        let name = args
+            .get(0)
+            .map_or(String::new(), |name| String::from_utf8_lossy(name).to_ascii_uppercase());
+        let key = match command::routing_key(cmd) {
+            Some(key) => self.encode(key),
+            None => "null".to_string(),
+        };
+        let args: Vec<String> = args.iter().skip(1).map(|arg| self.encode(arg)).collect();
+        format!(
+            "{{\"source\":{},\"phase\":\"{}\",\"db\":{},\"command\":{},\"key\":{},\"args\":[{}],\"offset\":{},\"timestamp\":{}}}\n",
+            quote(&self.source),
+            self.phase,
+            self.db,
+            quote(&name),
+            key,
+            args.join(","),
+            self.offset(),
+            chrono::Local::now().timestamp_millis()
+        )
+    }
+
+    fn offset(&self) -> i64 {
+        if self.phase == "rdb" {
+            -1
+        } else {
+            self.offset.load(Ordering::Relaxed)
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> String {
+        if self.base64 {
+            format!("\"{}\"", base64(data))
+        } else {
+            quote(&String::from_utf8_lossy(data))
+        }
+    }
+}
+
+// JSON字符串
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let n =
+            (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_CHARS[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// "-"表示标准输出, "unix:<path>"表示Unix socket, 否则为文件(追加写入)
+fn open(output: &str) -> io::Result<Box<dyn Write + Send>> {
+    if output == "-" {
+        return Ok(Box::new(io::stdout()));
+    }
+    if let Some(path) = output.strip_prefix("unix:") {
+        return connect(path);
+    }
+    let file = OpenOptions::new().create(true).append(true).open(output)?;
+    Ok(Box::new(file))
+}
+
+#[cfg(unix)]
+fn connect(path: &str) -> io::Result<Box<dyn Write + Send>> {
+    Ok(Box::new(std::os::unix::net::UnixStream::connect(path)?))
+}
+
+#[cfg(not(unix))]
+fn connect(_: &str) -> io::Result<Box<dyn Write + Send>> {
+    Err(io::Error::new(io::ErrorKind::Other, "当前平台不支持Unix socket"))
+}
+
+pub(crate) fn new(
+    output: &str, source: &str, base64: bool, flush_interval: u64, filter: Filter, renamer: Renamer, chunk_size: usize,
+    offset: Arc<AtomicI64>,
+) -> JsonEventHandler {
+    let mut outputs = OUTPUTS.lock().unwrap();
+    let output = match outputs.get(output) {
+        Some(output) => Arc::clone(output),
+        None => match open(output) {
+            Ok(writer) => {
+                let writer = Arc::new(Mutex::new(writer));
+                outputs.insert(output.to_string(), Arc::clone(&writer));
+                writer
+            }
+            Err(err) => panic!("打开JSON输出{}失败: {}", output, err),
+        },
+    };
+    JsonEventHandler {
+        output,
+        buf: Vec::with_capacity(BUFFER_SIZE),
+        source: source.to_string(),
+        base64,
+        filter,
+        renamer,
+        chunk_size,
+        offset,
+        phase: "rdb",
+        db: 0,
+        flush_interval: Duration::from_millis(flush_interval),
+        flushed: Instant::now(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote(""), r#""""#);
+        assert_eq!(quote("key"), r#""key""#);
+        assert_eq!(quote("a\"b\\c"), r#""a\"b\\c""#);
+        assert_eq!(quote("\n\r\t"), r#""\n\r\t""#);
+        // 其他控制字符以\u转义
+        assert_eq!(quote("\u{1}\u{1f}"), r#""\u0001\u001f""#);
+        assert_eq!(quote("\u{7f}é"), "\"\u{7f}é\"");
+    }
+
+    #[test]
+    fn test_base64() {
+        // RFC 4648中的示例
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xff, 0xfe, 0xfd]), "//79");
+        assert_eq!(base64(&[0x00]), "AA==");
+    }
+}
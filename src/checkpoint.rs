@@ -0,0 +1,227 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use redis_event::rdb::Object;
+use redis_event::{Event, EventHandler};
+
+use crate::metrics;
+use crate::relay::Progress;
+use crate::verify::Targets;
+use crate::worker::{Message, MessageSender};
+
+const T_NAME: &'static str = "copy_redis::checkpoint";
+// 开启--metrics时更新已写入的复制offset的间隔
+const METRICS_INTERVAL: Duration = Duration::from_secs(1);
+
+// 由各个handler实现, 阻塞至已接收的命令全部被worker写入目的Redis为止
+pub(crate) trait Flush {
+    fn flush(&mut self);
+
+    // 不阻塞地通知worker写入已接收的命令, 返回的Marker在这些命令全部写入之后完成.
+    // 没有worker的handler直接写入
+    fn mark(&mut self) -> Marker {
+        self.flush();
+        Marker(Vec::new())
+    }
+}
+
+// 每个worker对Flush消息的确认, worker已退出时其确认也视为完成
+pub(crate) struct Marker(Vec<Receiver<()>>);
+
+impl Marker {
+    // 向各个worker发送Flush消息
+    pub(crate) fn send<'a, I>(senders: I) -> Marker
+    where
+        I: IntoIterator<Item = &'a MessageSender>,
+    {
+        let mut acks = Vec::new();
+        for sender in senders {
+            let (ack, done) = mpsc::channel();
+            if let Err(_) = sender.send(Message::Flush(ack)) {}
+            acks.push(done);
+        }
+        Marker(acks)
+    }
+
+    pub(crate) fn done(&mut self) -> bool {
+        self.0
+            .retain(|done| matches!(done.try_recv(), Err(TryRecvError::Empty)));
+        self.0.is_empty()
+    }
+
+    pub(crate) fn wait(self) {
+        for done in self.0 {
+            if let Err(_) = done.recv() {}
+        }
+    }
+}
+
+// --flush-target-on-full-sync: 每次全量同步开始时(写入第一个RDB对象之前), 先等待之前的命令全部写入,
+// 再清空目的Redis的所有节点, 以免目的Redis中残留的数据与新的RDB混在一起
+pub(crate) struct TargetFlusher {
+    pub(crate) handler: Rc<RefCell<dyn EventHandler>>,
+    pub(crate) target: Rc<RefCell<dyn Flush>>,
+    pub(crate) targets: Targets,
+    // 为None时FLUSHALL, 否则只FLUSHDB复制的db
+    pub(crate) dbs: Option<Vec<i64>>,
+}
+
+impl EventHandler for TargetFlusher {
+    fn handle(&mut self, event: Event) {
+        if let Event::RDB(Object::BOR) = &event {
+            self.target.borrow_mut().flush();
+            match self.targets.flush(self.dbs.as_deref()) {
+                Ok(nodes) => warn!("全量同步开始, 已清空目的Redis: {:?}", nodes),
+                Err(err) => panic!("清空目的Redis失败: {}", err),
+            }
+        }
+        self.handler.borrow_mut().handle(event);
+    }
+}
+
+// 在AOF阶段定时保存PSYNC信息及更新已写入的复制offset(--metrics), 不中断与源Redis的复制连接:
+// 处理命令时取relay记录的listener已确认的offset, 该offset之前的命令此时都已交给handler,
+// 再通知worker写入已接收的命令, 全部写入目的Redis之后保存该offset
+pub(crate) struct Checkpoint {
+    handler: Rc<RefCell<dyn EventHandler>>,
+    target: Rc<RefCell<dyn Flush>>,
+    progress: Progress,
+    // PSYNC信息按此地址保存, 源Cluster发生故障转移后为新的master
+    pub(crate) source: String,
+    // 保存PSYNC信息的间隔, 为None时只更新metrics
+    interval: Option<Duration>,
+    metrics: bool,
+    marked: Instant,
+    saved: Instant,
+    // 等待写入完成的repl id与offset, 以及写入之后是否保存
+    pending: Option<(String, i64, Marker, bool)>,
+}
+
+impl Checkpoint {
+    pub(crate) fn new(
+        handler: Rc<RefCell<dyn EventHandler>>, target: Rc<RefCell<dyn Flush>>, progress: Progress, source: &str,
+        interval: Option<Duration>, metrics: bool,
+    ) -> Checkpoint {
+        Checkpoint {
+            handler,
+            target,
+            progress,
+            source: source.to_string(),
+            interval,
+            metrics,
+            marked: Instant::now(),
+            saved: Instant::now(),
+            pending: None,
+        }
+    }
+
+    fn advance(&mut self) {
+        if let Some((id, offset, marker, save)) = self.pending.as_mut() {
+            if !marker.done() {
+                return;
+            }
+            if self.metrics {
+                metrics::set_repl_offset(&self.source, *offset);
+            }
+            if *save {
+                match crate::save_repl_meta(&self.source, id, *offset) {
+                    Ok(_) => info!(
+                        target: T_NAME,
+                        "PSYNC信息已保存, source: {}, id: {}, offset: {}", self.source, id, offset
+                    ),
+                    Err(err) => error!(target: T_NAME, "保存PSYNC信息失败:{}", err),
+                }
+            }
+            self.pending = None;
+        }
+        let due = |timer: &Instant, interval: Option<Duration>| interval.map_or(false, |i| timer.elapsed() >= i);
+        let save = due(&self.saved, self.interval);
+        if !save && !(self.metrics && due(&self.marked, Some(METRICS_INTERVAL))) {
+            return;
+        }
+        // listener还未确认过offset时(刚建立连接), 等下一条命令再取
+        if let Some((id, offset)) = self.progress.acked() {
+            let marker = self.target.borrow_mut().mark();
+            self.pending = Some((id, offset, marker, save));
+            self.marked = Instant::now();
+            if save {
+                self.saved = self.marked;
+            }
+        }
+    }
+}
+
+impl EventHandler for Checkpoint {
+    fn handle(&mut self, event: Event) {
+        let aof = matches!(&event, Event::AOF(_));
+        if let Event::RDB(Object::BOR) = &event {
+            // 开始全量同步, 之前的复制进度已失效
+            self.pending = None;
+        }
+        self.handler.borrow_mut().handle(event);
+        if aof {
+            self.advance();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use redis_event::cmd::Command;
+
+    use super::*;
+
+    struct Noop;
+
+    impl EventHandler for Noop {
+        fn handle(&mut self, _: Event) {}
+    }
+
+    // 保留发送端, 在测试通过ack发送确认之前Marker不会完成
+    struct Pending {
+        acks: Vec<mpsc::Sender<()>>,
+    }
+
+    impl Flush for Pending {
+        fn flush(&mut self) {}
+
+        fn mark(&mut self) -> Marker {
+            let (ack, done) = mpsc::channel();
+            self.acks.push(ack);
+            Marker(vec![done])
+        }
+    }
+
+    #[test]
+    fn test_save_after_written() {
+        let source = "checkpoint-test:1";
+        crate::save_repl_meta(source, "?", 100).unwrap();
+        let target = Rc::new(RefCell::new(Pending { acks: Vec::new() }));
+        let progress = Progress::new();
+        let mut checkpoint = Checkpoint::new(
+            Rc::new(RefCell::new(Noop)),
+            target.clone(),
+            progress.clone(),
+            source,
+            Some(Duration::from_secs(0)),
+            false,
+        );
+
+        progress.offset.store(200, Ordering::SeqCst);
+        checkpoint.handle(Event::AOF(Command::MULTI));
+        checkpoint.handle(Event::AOF(Command::EXEC));
+        assert_eq!(target.borrow().acks.len(), 1);
+        // 命令还未全部写入, 保留之前的offset
+        assert_eq!(crate::load_repl_meta(source).unwrap(), ("?".to_string(), 100));
+
+        target.borrow().acks[0].send(()).unwrap();
+        checkpoint.handle(Event::AOF(Command::MULTI));
+        assert_eq!(crate::load_repl_meta(source).unwrap(), ("?".to_string(), 200));
+    }
+}
@@ -1,138 +1,841 @@
+use std::collections::{BTreeMap, HashMap};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Sender;
-use std::sync::{mpsc, Arc};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use log::{error, info};
-use r2d2_redis::redis::cluster::ClusterClient;
-use redis::Cmd;
+use log::{error, info, warn};
+use redis::{
+    Arg, Cmd, Connection, ConnectionAddr, ConnectionInfo, ErrorKind, IntoConnectionInfo, RedisError, RedisResult, Value,
+};
 use redis_event::cmd::Command;
 use redis_event::{Event, EventHandler};
 
+use crate::checkpoint::{Flush, Marker};
+use crate::command;
 use crate::command::CommandConverter;
-use crate::worker::{Message, Worker};
+use crate::conflict::Conflict;
+use crate::filter::Filter;
+use crate::metrics;
+use crate::rename::Renamer;
+use crate::restore::Restore;
+use crate::source;
+use crate::source::SourceReader;
+use crate::worker::{channel, is_retryable, DeadLetter, Message, MessageSender, RetryConfig, Unit, Workers};
+use crate::Abort;
 
 pub(crate) struct ClusterEventHandlerImpl {
-    worker: Worker,
-    sender: Sender<Message>,
+    sender: MessageSender,
+    filter: Filter,
+    renamer: Renamer,
+    chunk_size: usize,
+    restore: Option<Restore>,
+    conflict: Option<Conflict>,
+    source: SourceReader,
+    // MULTI之后尚未EXEC的命令
+    transaction: Option<Vec<Cmd>>,
+    abort: Abort,
 }
 
 impl EventHandler for ClusterEventHandlerImpl {
     fn handle(&mut self, event: Event) {
         match event {
             Event::RDB(rdb) => self.handle_rdb(rdb),
+            Event::AOF(aof) if !self.in_accepted_db(&aof) => {}
+            Event::AOF(aof) if command::is_multi_key(&aof) => self.handle_multi_key(aof),
             Event::AOF(aof) => match aof {
                 Command::DEL(del) => {
                     for key in &del.keys {
+                        if !self.filter.accept(key) {
+                            continue;
+                        }
                         let mut cmd = redis::cmd("DEL");
-                        cmd.arg(key.as_slice());
+                        cmd.arg(self.rename(key));
                         self.execute(cmd, None);
                     }
                 }
                 Command::MSET(mset) => {
                     for kv in &mset.key_values {
+                        if !self.filter.accept(kv.key) {
+                            continue;
+                        }
                         let mut cmd = redis::cmd("SET");
-                        cmd.arg(kv.key).arg(kv.value);
+                        cmd.arg(self.rename(kv.key)).arg(kv.value);
                         self.execute(cmd, None);
                     }
                 }
                 Command::MSETNX(msetnx) => {
                     for kv in &msetnx.key_values {
+                        if !self.filter.accept(kv.key) {
+                            continue;
+                        }
                         let mut cmd = redis::cmd("SETNX");
-                        cmd.arg(kv.key).arg(kv.value);
+                        cmd.arg(self.rename(kv.key)).arg(kv.value);
                         self.execute(cmd, None);
                     }
                 }
                 Command::PFCOUNT(pfcount) => {
                     for key in &pfcount.keys {
+                        if !self.filter.accept(key) {
+                            continue;
+                        }
                         let mut cmd = redis::cmd("PFCOUNT");
-                        cmd.arg(*key);
+                        cmd.arg(self.rename(key));
                         self.execute(cmd, None);
                     }
                 }
                 Command::UNLINK(unlink) => {
                     for key in &unlink.keys {
+                        if !self.filter.accept(key) {
+                            continue;
+                        }
                         let mut cmd = redis::cmd("UNLINK");
-                        cmd.arg(*key);
+                        cmd.arg(self.rename(key));
                         self.execute(cmd, None);
                     }
                 }
-                Command::BITOP(_)
-                | Command::EVAL(_)
-                | Command::EVALSHA(_)
-                | Command::MULTI
-                | Command::EXEC
-                | Command::PFMERGE(_)
-                | Command::SDIFFSTORE(_)
-                | Command::SINTERSTORE(_)
-                | Command::SUNIONSTORE(_)
-                | Command::ZUNIONSTORE(_)
-                | Command::ZINTERSTORE(_)
-                | Command::PUBLISH(_) => {}
+                Command::MULTI => self.transaction = Some(Vec::new()),
+                Command::EXEC => self.commit(),
                 _ => self.handle_aof(aof),
             },
         };
     }
 }
 
-impl Drop for ClusterEventHandlerImpl {
-    fn drop(&mut self) {
-        if let Err(_) = self.sender.send(Message::Terminate) {}
-        if let Some(thread) = self.worker.thread.take() {
-            if let Err(_) = thread.join() {}
+impl ClusterEventHandlerImpl {
+    // 所有key都属于同一个slot时直接执行, 否则从源Redis读取命令所修改的key, 再写入目的Cluster
+    fn handle_multi_key(&mut self, cmd: Command) {
+        if !self.accept_keys(&cmd) {
+            return;
+        }
+        let slots: Vec<u16> = command::keys(&cmd).iter().map(|key| slot(&self.rename(key))).collect();
+        if slots.windows(2).all(|pair| pair[0] == pair[1]) {
+            self.handle_aof(cmd);
+            return;
+        }
+        let keys: Vec<(&[u8], Vec<u8>)> = command::written_keys(&cmd)
+            .into_iter()
+            .map(|key| (key, self.rename(key)))
+            .collect();
+        match self.source.restore(self.filter.current_db(), &keys) {
+            Ok(cmds) => {
+                for restore in cmds {
+                    self.execute(restore, None);
+                }
+            }
+            Err(err) => source::report_dropped(&cmd, &format!("从源Redis读取key失败: {}", err)),
+        }
+    }
+
+    // 事务中的命令都属于同一个slot时, 以MULTI/EXEC原子地写入; 否则只能逐条写入各个节点
+    fn commit(&mut self) {
+        let cmds = match self.transaction.take() {
+            Some(cmds) if !cmds.is_empty() => cmds,
+            _ => return,
+        };
+        let slots: Vec<Option<u16>> = cmds
+            .iter()
+            .map(|cmd| match route(cmd) {
+                Route::Slot(slot) => Some(slot),
+                Route::AllMasters => None,
+            })
+            .collect();
+        if slots[0].is_some() && slots.windows(2).all(|pair| pair[0] == pair[1]) {
+            if let Err(err) = self.sender.send(Message::Transaction(cmds, None)) {
+                self.abort.abort(&format!("worker已退出: {}", err));
+            }
+        } else {
+            warn!("事务中的命令分布在多个slot中, 将逐条写入, 无法保证原子性");
+            for cmd in cmds {
+                if let Err(err) = self.sender.send(Message::Cmd(cmd, None)) {
+                    self.abort.abort(&format!("worker已退出: {}", err));
+                }
+            }
         }
     }
 }
 
+impl Flush for ClusterEventHandlerImpl {
+    fn flush(&mut self) {
+        self.mark().wait();
+    }
+
+    fn mark(&mut self) -> Marker {
+        Marker::send(Some(&self.sender))
+    }
+}
+
 impl CommandConverter for ClusterEventHandlerImpl {
     fn execute(&mut self, cmd: Cmd, _: Option<&[u8]>) {
-        if let Err(err) = self.sender.send(Message::Cmd(cmd)) {
-            panic!("{}", err)
+        if let Some(transaction) = self.transaction.as_mut() {
+            transaction.push(cmd);
+            return;
+        }
+        if let Err(err) = self.sender.send(Message::Cmd(cmd, None)) {
+            self.abort.abort(&format!("worker已退出: {}", err));
         }
     }
 
+    fn filter(&mut self) -> &mut Filter {
+        &mut self.filter
+    }
+
+    fn renamer(&self) -> &Renamer {
+        &self.renamer
+    }
+
+    fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    fn restore(&self) -> Option<&Restore> {
+        self.restore.as_ref()
+    }
+
+    fn conflict(&mut self) -> Option<&mut Conflict> {
+        self.conflict.as_mut()
+    }
+
+    fn source(&mut self) -> Option<&mut SourceReader> {
+        Some(&mut self.source)
+    }
+
     fn swap_db(&mut self, _: i32) {}
 }
 
-pub(crate) fn new_cluster(target: Vec<String>, running: Arc<AtomicBool>) -> ClusterEventHandlerImpl {
-    let (sender, receiver) = mpsc::channel();
-    let worker_thread = thread::spawn(move || {
-        info!(target: "cluster::worker", "Worker thread started");
-        let mut shutdown = false;
-        let client = match ClusterClient::open(target) {
-            Ok(client) => client,
-            Err(err) => {
+// 各个源共用的worker, Cluster只有db 0, 因此不需要区分命令所属的db
+pub(crate) fn new_workers(
+    target: Vec<String>, batch_size: i32, flush_interval: u64, queue_size: usize, retry: RetryConfig,
+    running: Arc<AtomicBool>,
+) -> Workers {
+    let worker_name = "cluster::worker";
+    let (sender, receiver) = channel(worker_name, queue_size);
+    let worker_thread = thread::Builder::new()
+        .name(worker_name.into())
+        .spawn(move || {
+            let handle = thread::current();
+            let t_name = handle.name().unwrap();
+            info!(target: t_name, "Worker thread started");
+            let mut writer = ClusterWriter::new(&target, t_name);
+            if let Err(err) = writer.refresh_slots() {
                 running.store(false, Ordering::SeqCst);
-                panic!(err);
+                panic!("获取Cluster的slot分布失败: {}", err);
             }
-        };
-        let mut conn = client.get_connection().expect("获取ClusterConnection失败");
-        loop {
-            match receiver.recv_timeout(Duration::from_millis(10)) {
-                Ok(Message::Cmd(cmd)) => {
-                    match cmd.query(&mut conn) {
-                        Err(err) => {
-                            error!(target: "cluster::worker", "数据写入失败: {}", err);
+            let mut dead_letter = DeadLetter::new(&retry.dead_letter_dir, t_name);
+            let depth = metrics::QUEUE_DEPTH.with_label_values(&[t_name]);
+            let mut batch: Vec<Unit> = Vec::new();
+            let mut timer = Instant::now();
+            let interval = Duration::from_millis(flush_interval);
+            let mut shutdown = false;
+            loop {
+                if (batch_size < 0) || ((batch.len() as i32) < batch_size) {
+                    let message = receiver.recv_timeout(Duration::from_millis(10));
+                    if message.is_ok() {
+                        depth.dec();
+                    }
+                    match message {
+                        Ok(Message::Cmd(cmd, _)) => batch.push(Unit::Cmd(cmd)),
+                        Ok(Message::Transaction(cmds, _)) => batch.push(Unit::Transaction(cmds)),
+                        Ok(Message::Flush(ack)) => {
+                            if !batch.is_empty() {
+                                writer.flush(&mut batch, &retry, &mut dead_letter);
+                                timer = Instant::now();
+                            }
+                            if let Err(_) = ack.send(()) {}
+                        }
+                        Ok(Message::Terminate) => {
+                            shutdown = true;
                         }
-                        Ok(()) => {}
+                        Err(_) => {}
+                    }
+                }
+                if (timer.elapsed() >= interval || shutdown) && !batch.is_empty() {
+                    writer.flush(&mut batch, &retry, &mut dead_letter);
+                    timer = Instant::now();
+                }
+                if shutdown {
+                    break;
+                }
+            }
+            info!(target: t_name, "Worker thread terminated");
+        })
+        .unwrap();
+    let mut workers = Workers::new();
+    workers.add(worker_name, sender, worker_thread);
+    workers
+}
+
+pub(crate) fn new_cluster(
+    source: Option<&str>, senders: &BTreeMap<String, MessageSender>, filter: Filter, renamer: Renamer,
+    chunk_size: usize, restore: Option<Restore>, conflict: Option<Conflict>, abort: Abort,
+) -> ClusterEventHandlerImpl {
+    ClusterEventHandlerImpl {
+        sender: senders.values().next().unwrap().clone(),
+        filter,
+        renamer,
+        chunk_size,
+        restore,
+        conflict,
+        source: SourceReader::new(source),
+        transaction: None,
+        abort,
+    }
+}
+
+// 单次flush中, 因MOVED/ASK而重新发送命令的最大轮数
+const MAX_REDIRECTS: u32 = 5;
+const SLOTS: usize = 16384;
+
+enum Route {
+    Slot(u16),
+    // FLUSHALL, SCRIPT LOAD等与key无关的命令, 需要在每个master上执行
+    AllMasters,
+}
+
+// 事务中的命令都属于同一个slot, 以第一条命令为准
+fn route_unit(unit: &Unit) -> Route {
+    match unit {
+        Unit::Cmd(cmd) => route(cmd),
+        Unit::Transaction(cmds) => route(&cmds[0]),
+    }
+}
+
+struct Pending {
+    unit: Unit,
+    slot: Option<u16>,
+    // 通过ASK重定向或广播而指定的节点
+    node: Option<String>,
+    asking: bool,
+}
+
+// 按照slot的分布将命令分组, 以pipeline的方式分别写入各个master节点
+pub(crate) struct ClusterWriter {
+    seeds: Vec<ConnectionInfo>,
+    slots: Vec<Option<String>>,
+    conns: HashMap<String, Connection>,
+    stale: bool,
+    t_name: String,
+}
+
+impl ClusterWriter {
+    pub(crate) fn new(target: &[String], t_name: &str) -> ClusterWriter {
+        let seeds = target
+            .iter()
+            .map(|node| node.as_str().into_connection_info().expect("解析Cluster节点地址失败"))
+            .collect();
+        ClusterWriter {
+            seeds,
+            slots: vec![None; SLOTS],
+            conns: HashMap::new(),
+            stale: true,
+            t_name: t_name.to_string(),
+        }
+    }
+
+    // 通过CLUSTER SLOTS获取最新的slot分布, 依次尝试已知的节点
+    pub(crate) fn refresh_slots(&mut self) -> RedisResult<()> {
+        let mut nodes: Vec<String> = self.conns.keys().cloned().collect();
+        for seed in &self.seeds {
+            match seed.addr.as_ref() {
+                ConnectionAddr::Tcp(host, port) => nodes.push(format!("{}:{}", host, port)),
+                ConnectionAddr::TcpTls { host, port, .. } => nodes.push(format!("{}:{}", host, port)),
+                _ => {
+                    return Err(RedisError::from((
+                        ErrorKind::InvalidClientConfig,
+                        "Cluster节点不支持unix socket地址",
+                    )))
+                }
+            }
+        }
+        let mut last_err = None;
+        for node in nodes {
+            let result = self
+                .connection(&node)
+                .and_then(|conn| redis::cmd("CLUSTER").arg("SLOTS").query::<Value>(conn));
+            match result.and_then(|value| parse_slots(&value, &node)) {
+                Ok(slots) => {
+                    self.slots = slots;
+                    self.stale = false;
+                    info!(target: &self.t_name, "已获取Cluster的slot分布, master数量: {}", self.masters().len());
+                    return Ok(());
+                }
+                Err(err) => {
+                    warn!(target: &self.t_name, "从{}获取slot分布失败: {}", node, err);
+                    self.conns.remove(&node);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| RedisError::from((ErrorKind::ClientError, "没有可用的Cluster节点"))))
+    }
+
+    pub(crate) fn masters(&self) -> Vec<String> {
+        let mut masters: Vec<String> = self.slots.iter().filter_map(|node| node.clone()).collect();
+        masters.sort();
+        masters.dedup();
+        masters
+    }
+
+    // 当前负责key所属slot的master
+    pub(crate) fn owner(&mut self, key: &[u8]) -> RedisResult<String> {
+        if self.stale {
+            self.refresh_slots()?;
+        }
+        match &self.slots[slot(key) as usize] {
+            Some(node) => Ok(node.clone()),
+            None => Err(RedisError::from((ErrorKind::ClientError, "slot未被分配"))),
+        }
+    }
+
+    // 获取与节点的连接, 沿用第一个种子节点的认证与TLS配置
+    pub(crate) fn connection(&mut self, node: &str) -> RedisResult<&mut Connection> {
+        if !self.conns.contains_key(node) {
+            let (host, port) = match node.rfind(':') {
+                Some(i) => (&node[..i], node[i + 1..].parse::<u16>().unwrap_or(6379)),
+                None => (node, 6379),
+            };
+            let template = &self.seeds[0];
+            let addr = match template.addr.as_ref() {
+                ConnectionAddr::TcpTls { insecure, .. } => ConnectionAddr::TcpTls {
+                    host: host.to_string(),
+                    port,
+                    insecure: *insecure,
+                },
+                _ => ConnectionAddr::Tcp(host.to_string(), port),
+            };
+            let info = ConnectionInfo {
+                addr: Box::new(addr),
+                db: 0,
+                username: template.username.clone(),
+                passwd: template.passwd.clone(),
+            };
+            let conn = redis::Client::open(info)?.get_connection()?;
+            self.conns.insert(node.to_string(), conn);
+        }
+        Ok(self.conns.get_mut(node).unwrap())
+    }
+
+    fn flush(&mut self, batch: &mut Vec<Unit>, retry: &RetryConfig, dead_letter: &mut DeadLetter) {
+        let t_name = self.t_name.clone();
+        let t_name = t_name.as_str();
+        if self.stale {
+            if let Err(err) = self.refresh_slots() {
+                error!(target: t_name, "刷新slot分布失败: {}", err);
+            }
+        }
+        let mut pending = Vec::new();
+        for unit in batch.drain(..) {
+            match (route_unit(&unit), unit) {
+                (Route::Slot(slot), unit) => pending.push(Pending {
+                    unit,
+                    slot: Some(slot),
+                    node: None,
+                    asking: false,
+                }),
+                (Route::AllMasters, Unit::Cmd(cmd)) => {
+                    for node in self.masters() {
+                        pending.push(Pending {
+                            unit: Unit::Cmd(cmd.clone()),
+                            slot: None,
+                            node: Some(node),
+                            asking: false,
+                        });
+                    }
+                }
+                (Route::AllMasters, Unit::Transaction(_)) => unreachable!("事务中的命令只能属于同一个slot"),
+            }
+        }
+
+        let (mut attempts, mut redirects) = (0, 0);
+        while !pending.is_empty() {
+            if self.stale {
+                if let Err(err) = self.refresh_slots() {
+                    error!(target: t_name, "刷新slot分布失败: {}", err);
+                }
+            }
+            let mut groups: BTreeMap<String, Vec<Pending>> = BTreeMap::new();
+            let mut retries = Vec::new();
+            let mut redirected = Vec::new();
+            for entry in pending {
+                let node = match (&entry.node, entry.slot) {
+                    (Some(node), _) => Some(node.clone()),
+                    (None, Some(slot)) => self.slots[slot as usize].clone(),
+                    (None, None) => None,
+                };
+                match node {
+                    Some(node) => groups.entry(node).or_insert_with(Vec::new).push(entry),
+                    None => {
+                        // slot暂时没有节点负责, 刷新slot分布后重试
+                        self.stale = true;
+                        retries.push(entry);
+                    }
+                }
+            }
+
+            for (node, entries) in groups {
+                let results = match self.connection(&node) {
+                    Ok(conn) => {
+                        let timer = metrics::FLUSH_LATENCY.with_label_values(&[t_name]).start_timer();
+                        let results = write_pipeline(conn, &entries, t_name);
+                        timer.observe_duration();
+                        results
+                    }
+                    Err(err) => {
+                        error!(target: t_name, "连接{}失败: {}", node, err);
+                        entries.iter().map(|_| None).collect()
+                    }
+                };
+                if results.iter().any(|result| result.is_none()) {
+                    self.conns.remove(&node);
+                    self.stale = true;
+                }
+                let succeeded: usize = entries
+                    .iter()
+                    .zip(&results)
+                    .filter(|(_, result)| matches!(result, Some(Ok(_))))
+                    .map(|(entry, _)| entry.unit.len())
+                    .sum();
+                if succeeded > 0 {
+                    info!(target: t_name, "写入{}成功: {}", node, succeeded);
+                    metrics::COMMANDS_WRITTEN
+                        .with_label_values(&[t_name])
+                        .inc_by(succeeded as u64);
+                }
+                for (mut entry, result) in entries.into_iter().zip(results) {
+                    let err = match result {
+                        Some(Ok(_)) => continue,
+                        // 连接中断, 无法确定命令是否已执行
+                        None => {
+                            retries.push(entry);
+                            continue;
+                        }
+                        Some(Err(err)) => err,
                     };
+                    match (err.kind(), redirection(&err)) {
+                        (ErrorKind::Moved, Some((slot, addr))) => {
+                            self.slots[slot as usize] = Some(addr);
+                            self.stale = true;
+                            entry.node = None;
+                            entry.asking = false;
+                            redirected.push(entry);
+                        }
+                        (ErrorKind::Ask, Some((_, addr))) => {
+                            entry.node = Some(addr);
+                            entry.asking = true;
+                            redirected.push(entry);
+                        }
+                        _ if is_retryable(&err) => {
+                            warn!(target: t_name, "命令写入失败, 稍后重试: {}", err);
+                            if err.kind() == ErrorKind::ClusterDown || err.kind() == ErrorKind::TryAgain {
+                                self.stale = true;
+                            }
+                            retries.push(entry);
+                        }
+                        _ => {
+                            error!(target: t_name, "数据写入失败: {}", err);
+                            entry.unit.write_dead_letter(0, dead_letter);
+                        }
+                    }
                 }
-                Ok(Message::Terminate) => {
-                    shutdown = true;
+            }
+
+            if !redirected.is_empty() {
+                redirects += 1;
+                if redirects > MAX_REDIRECTS {
+                    error!(target: t_name, "重定向次数过多, 放弃写入: {}", redirected.len());
+                    for entry in redirected.drain(..) {
+                        entry.unit.write_dead_letter(0, dead_letter);
+                    }
                 }
-                _ => {}
             }
-            if shutdown {
+            if !retries.is_empty() {
+                attempts += 1;
+                if attempts > retry.max_retries {
+                    for entry in retries.drain(..) {
+                        entry.unit.write_dead_letter(0, dead_letter);
+                    }
+                } else {
+                    let backoff = retry.backoff(attempts);
+                    warn!(target: t_name, "{}ms后进行第{}次重试, 命令数量: {}", backoff.as_millis(), attempts, retries.len());
+                    thread::sleep(backoff);
+                }
+            }
+            pending = retries;
+            pending.extend(redirected);
+        }
+    }
+}
+
+// 将同一个节点的命令以pipeline的方式发送, 并逐条读取响应. 连接中断之后的命令无法确定是否已执行, 以None表示.
+// 事务中的命令在排队时出错(如MOVED)会导致EXEC失败, 此时以第一个错误作为整个事务的结果
+fn write_pipeline(conn: &mut Connection, entries: &[Pending], t_name: &str) -> Vec<Option<RedisResult<Value>>> {
+    let mut packed = Vec::new();
+    for entry in entries {
+        if entry.asking {
+            packed.extend(redis::cmd("ASKING").get_packed_command());
+        }
+        entry.unit.pack(&mut packed);
+    }
+    if let Err(err) = conn.send_packed_command(&packed) {
+        error!(target: t_name, "数据写入失败: {}", err);
+        return entries.iter().map(|_| None).collect();
+    }
+    let mut results = Vec::with_capacity(entries.len());
+    let mut broken = false;
+    for entry in entries {
+        if !broken && entry.asking {
+            if let Err(err) = conn.recv_response() {
+                broken = err.is_io_error();
+            }
+        }
+        let mut result = None;
+        for _ in 0..entry.unit.replies() {
+            if broken {
                 break;
-            };
+            }
+            match conn.recv_response() {
+                Err(err) if err.is_io_error() => {
+                    error!(target: t_name, "数据写入失败: {}", err);
+                    broken = true;
+                }
+                _ if matches!(result, Some(Err(_))) => {}
+                response => result = Some(response),
+            }
         }
-        info!(target: "cluster::worker", "Worker thread terminated");
-    });
-    ClusterEventHandlerImpl {
-        worker: Worker {
-            thread: Option::Some(worker_thread),
+        results.push(if broken { None } else { result });
+    }
+    results
+}
+
+// 解析MOVED/ASK错误中的slot与节点地址, 如: MOVED 3999 127.0.0.1:6381
+fn redirection(err: &RedisError) -> Option<(u16, String)> {
+    let err = err.to_string();
+    let mut parts = err.split_whitespace().rev();
+    let addr = parts.next()?;
+    let slot = parts.next()?.parse::<u16>().ok()?;
+    if (slot as usize) < SLOTS && addr.contains(':') {
+        Some((slot, addr.to_string()))
+    } else {
+        None
+    }
+}
+
+fn parse_slots(value: &Value, node: &str) -> RedisResult<Vec<Option<String>>> {
+    let mut slots = vec![None; SLOTS];
+    let ranges = match value {
+        Value::Bulk(ranges) => ranges,
+        _ => return Err(RedisError::from((ErrorKind::TypeError, "无法解析CLUSTER SLOTS的响应"))),
+    };
+    for range in ranges {
+        let items = match range {
+            Value::Bulk(items) if items.len() >= 3 => items,
+            _ => return Err(RedisError::from((ErrorKind::TypeError, "无法解析CLUSTER SLOTS的响应"))),
+        };
+        let start: usize = redis::from_redis_value(&items[0])?;
+        let end: usize = redis::from_redis_value(&items[1])?;
+        let master = match &items[2] {
+            Value::Bulk(master) if master.len() >= 2 => master,
+            _ => return Err(RedisError::from((ErrorKind::TypeError, "无法解析CLUSTER SLOTS的响应"))),
+        };
+        let mut host: String = redis::from_redis_value(&master[0])?;
+        let port: u16 = redis::from_redis_value(&master[1])?;
+        if host.is_empty() {
+            // 节点不知道自身的IP时返回空字符串, 此时使用所查询节点的地址
+            host = node[..node.rfind(':').unwrap_or(node.len())].to_string();
+        }
+        let addr = format!("{}:{}", host, port);
+        for slot in start..=end.min(SLOTS - 1) {
+            slots[slot] = Some(addr.clone());
+        }
+    }
+    Ok(slots)
+}
+
+fn route(cmd: &Cmd) -> Route {
+    if let Some(key) = command::routing_key(cmd) {
+        return Route::Slot(slot(key));
+    }
+    let name = match cmd.args_iter().next() {
+        Some(Arg::Simple(name)) => name.to_ascii_uppercase(),
+        _ => Vec::new(),
+    };
+    match name.as_slice() {
+        b"FLUSHALL" | b"FLUSHDB" | b"SCRIPT" => Route::AllMasters,
+        // 不包含key的命令(如没有key的EVAL)任选一个节点执行
+        _ => Route::Slot(0),
+    }
+}
+
+// 计算key所属的slot, 若key中包含{hash tag}, 则只使用hash tag计算
+pub(crate) fn slot(key: &[u8]) -> u16 {
+    let key = match key.iter().position(|b| *b == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|b| *b == b'}') {
+            Some(close) if close > 0 => &key[open + 1..open + 1 + close],
+            _ => key,
         },
-        sender,
+        None => key,
+    };
+    crc16(key) % SLOTS as u16
+}
+
+// CRC16-CCITT (XMODEM)
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::cluster::{slot, ClusterWriter, SLOTS};
+    use crate::tests::fake_redis;
+    use crate::tests::fake_redis::FakeRedis;
+    use crate::worker::{DeadLetter, RetryConfig, Unit};
+
+    // CLUSTER SLOTS的响应, ranges为(起始slot, 结束slot, 节点地址)
+    fn slots_reply(ranges: &[(usize, usize, &str)]) -> String {
+        let mut reply = format!("*{}\r\n", ranges.len());
+        for (start, end, addr) in ranges {
+            let (host, port) = addr.split_at(addr.rfind(':').unwrap());
+            reply.push_str(&format!(
+                "*3\r\n:{}\r\n:{}\r\n*2\r\n${}\r\n{}\r\n:{}\r\n",
+                start,
+                end,
+                host.len(),
+                host,
+                &port[1..]
+            ));
+        }
+        reply
+    }
+
+    // 以shared中的slot分布回复CLUSTER SLOTS, 其他命令交由respond处理
+    fn node<F>(shared: Arc<Mutex<String>>, mut respond: F) -> FakeRedis
+    where
+        F: FnMut(&[String]) -> Option<String> + Send + 'static,
+    {
+        fake_redis::start(move |_, args| {
+            if args[0] == "CLUSTER" {
+                Some(shared.lock().unwrap().clone())
+            } else {
+                respond(args)
+            }
+        })
+    }
+
+    fn set(key: &str) -> Unit {
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(key).arg(1);
+        Unit::Cmd(cmd)
+    }
+
+    fn retry() -> RetryConfig {
+        RetryConfig {
+            max_retries: 2,
+            interval: 1,
+            dead_letter_dir: None,
+        }
+    }
+
+    fn keys(redis: &FakeRedis) -> Vec<String> {
+        let received = redis.received.lock().unwrap();
+        received
+            .iter()
+            .filter(|(_, args)| args[0] != "CLUSTER")
+            .map(|(_, args)| {
+                args.get(1)
+                    .map_or(args[0].clone(), |key| format!("{} {}", args[0], key))
+            })
+            .collect()
+    }
+
+    // 指定slot范围内的一个key
+    fn key_in(range: std::ops::Range<u16>, prefix: &str) -> String {
+        (0..)
+            .map(|i| format!("{}{}", prefix, i))
+            .find(|key| range.contains(&slot(key.as_bytes())))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_pipeline_per_owner() {
+        let slots = Arc::new(Mutex::new(String::new()));
+        let a = node(Arc::clone(&slots), |_| Some("+OK\r\n".to_string()));
+        let b = node(Arc::clone(&slots), |_| Some("+OK\r\n".to_string()));
+        *slots.lock().unwrap() = slots_reply(&[(0, 8191, &a.addr), (8192, SLOTS - 1, &b.addr)]);
+
+        let (a1, a2) = (key_in(0..8192, "a"), key_in(0..8192, "aa"));
+        let (b1, b2) = (key_in(8192..16384, "b"), key_in(8192..16384, "bb"));
+        let mut writer = ClusterWriter::new(&[format!("redis://{}", a.addr)], "cluster_test");
+        let mut batch = vec![set(&a1), set(&b1), set(&a2), set(&b2)];
+        writer.flush(&mut batch, &retry(), &mut DeadLetter::new(&None, "cluster_test"));
+
+        // 每个节点只收到自己负责的slot中的命令, 且保持原有的顺序
+        assert_eq!(keys(&a), vec![format!("SET {}", a1), format!("SET {}", a2)]);
+        assert_eq!(keys(&b), vec![format!("SET {}", b1), format!("SET {}", b2)]);
+    }
+
+    #[test]
+    fn test_moved() {
+        let slots = Arc::new(Mutex::new(String::new()));
+        let b = node(Arc::clone(&slots), |_| Some("+OK\r\n".to_string()));
+        let b_addr = b.addr.clone();
+        let a = node(Arc::clone(&slots), move |args| {
+            let slot = slot(args[1].as_bytes());
+            Some(format!("-MOVED {} {}\r\n", slot, b_addr))
+        });
+        *slots.lock().unwrap() = slots_reply(&[(0, SLOTS - 1, &a.addr)]);
+
+        let mut writer = ClusterWriter::new(&[format!("redis://{}", a.addr)], "cluster_test");
+        writer.refresh_slots().unwrap();
+        // 之后的CLUSTER SLOTS返回迁移之后的分布
+        *slots.lock().unwrap() = slots_reply(&[(0, SLOTS - 1, &b.addr)]);
+        let mut batch = vec![set("moved1"), set("moved2")];
+        writer.flush(&mut batch, &retry(), &mut DeadLetter::new(&None, "cluster_test"));
+
+        assert_eq!(keys(&a), vec!["SET moved1", "SET moved2"]);
+        assert_eq!(keys(&b), vec!["SET moved1", "SET moved2"]);
+        assert_eq!(writer.owner(b"moved1").unwrap(), b.addr);
+    }
+
+    #[test]
+    fn test_ask() {
+        let slots = Arc::new(Mutex::new(String::new()));
+        let b = node(Arc::clone(&slots), |_| Some("+OK\r\n".to_string()));
+        let b_addr = b.addr.clone();
+        let a = node(Arc::clone(&slots), move |args| {
+            if args[1] == "migrating" {
+                Some(format!("-ASK {} {}\r\n", slot(args[1].as_bytes()), b_addr))
+            } else {
+                Some("+OK\r\n".to_string())
+            }
+        });
+        *slots.lock().unwrap() = slots_reply(&[(0, SLOTS - 1, &a.addr)]);
+
+        let mut writer = ClusterWriter::new(&[format!("redis://{}", a.addr)], "cluster_test");
+        let mut batch = vec![set("migrating"), set("stable")];
+        writer.flush(&mut batch, &retry(), &mut DeadLetter::new(&None, "cluster_test"));
+
+        // ASK只对本次命令生效: 需先发送ASKING, 且不改变slot的分布
+        assert_eq!(keys(&a), vec!["SET migrating", "SET stable"]);
+        assert_eq!(keys(&b), vec!["ASKING", "SET migrating"]);
+        assert_eq!(writer.owner(b"migrating").unwrap(), a.addr);
+    }
+
+    #[test]
+    fn test_unix_socket_seed() {
+        let mut writer = ClusterWriter::new(&["redis+unix:///tmp/redis.sock".to_string()], "cluster_test");
+        assert!(writer.refresh_slots().is_err());
     }
 }
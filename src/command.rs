@@ -1,4 +1,5 @@
-use redis::Cmd;
+use log::warn;
+use redis::{Arg, Cmd};
 use redis_event::cmd::keys::ORDER;
 use redis_event::cmd::lists::POSITION;
 use redis_event::cmd::sorted_sets::AGGREGATE;
@@ -7,68 +8,148 @@ use redis_event::cmd::Command;
 use redis_event::rdb;
 use redis_event::rdb::Object;
 
+use crate::conflict::{Conflict, Policy};
+use crate::export::stream_id;
+use crate::filter::{parse_db, Filter};
+use crate::rename::Renamer;
+use crate::restore::Restore;
+use crate::source::{SourceReader, StreamState};
+
 pub trait CommandConverter {
     fn handle_rdb(&mut self, rdb: Object) {
+        let (key, db) = match &rdb {
+            Object::String(kv) => (kv.key, kv.meta.db),
+            Object::List(list) => (list.key, list.meta.db),
+            Object::Set(set) => (set.key, set.meta.db),
+            Object::SortedSet(sorted_set) => (sorted_set.key, sorted_set.meta.db),
+            Object::Hash(hash) => (hash.key, hash.meta.db),
+            Object::Stream(key, stream) => (key.as_slice(), stream.meta.db),
+            _ => return,
+        };
+        // RDB中没有SELECT命令, db的切换以每个key所在的db为准
+        if !self.select_db(db) || !self.filter().accept(key) {
+            return;
+        }
+        let new_key = self.rename(key);
+        let target_db = self.filter().map_db(db);
+        let policy = match self.conflict() {
+            Some(conflict) => {
+                if !conflict.accept(target_db, &new_key) {
+                    return;
+                }
+                conflict.policy()
+            }
+            None => Policy::Append,
+        };
+        // RDB对象中只有stream的消息及消费组的last id, 其余的状态(PEL、消费者等)从源Redis读取
+        let stream_state = match (&rdb, self.source()) {
+            (Object::Stream(_, stream), Some(source)) => match source.stream(db, key) {
+                Ok(Some(state)) => stream_commands(&state, &new_key, stream),
+                Ok(None) => Vec::new(),
+                Err(err) => {
+                    warn!("读取stream的状态失败: {}, key: {}", err, String::from_utf8_lossy(key));
+                    Vec::new()
+                }
+            },
+            _ => Vec::new(),
+        };
+        if let Some(restore) = self.restore() {
+            if let Some(cmd) = restore.command(&new_key, &rdb) {
+                self.execute(cmd, Some(new_key.as_slice()));
+                for cmd in stream_state {
+                    self.execute(cmd, Some(new_key.as_slice()));
+                }
+                return;
+            }
+        }
+        // SET本身即会覆盖已有的key
+        if policy == Policy::Replace && !matches!(rdb, Object::String(_)) {
+            let mut cmd = redis::cmd("DEL");
+            cmd.arg(new_key.as_slice());
+            self.execute(cmd, Some(new_key.as_slice()));
+        }
+        // 大key按chunk_size拆分为多条命令写入, 过期时间在最后一条命令之后设置
+        let chunk_size = match self.chunk_size() {
+            0 => usize::MAX,
+            size => size,
+        };
         match rdb {
             Object::String(kv) => {
                 let mut cmd = redis::cmd("set");
-                cmd.arg(kv.key).arg(kv.value);
+                cmd.arg(self.rename(kv.key)).arg(kv.value);
                 self.execute(cmd, None);
                 self.handle_expire(kv.key, &kv.meta.expire);
             }
             Object::List(list) => {
-                let mut cmd = redis::cmd("rpush");
-                cmd.arg(list.key);
-                for val in list.values {
-                    cmd.arg(val.as_slice());
+                let new_key = self.rename(list.key);
+                for values in list.values.chunks(chunk_size) {
+                    let mut cmd = redis::cmd("rpush");
+                    cmd.arg(new_key.as_slice());
+                    for val in values {
+                        cmd.arg(val.as_slice());
+                    }
+                    self.execute(cmd, Some(new_key.as_slice()));
                 }
-                self.execute(cmd, None);
                 self.handle_expire(list.key, &list.meta.expire);
             }
             Object::Set(set) => {
-                let mut cmd = redis::cmd("sadd");
-                cmd.arg(set.key);
-                for member in set.members {
-                    cmd.arg(member.as_slice());
+                let new_key = self.rename(set.key);
+                for members in set.members.chunks(chunk_size) {
+                    let mut cmd = redis::cmd("sadd");
+                    cmd.arg(new_key.as_slice());
+                    for member in members {
+                        cmd.arg(member.as_slice());
+                    }
+                    self.execute(cmd, Some(new_key.as_slice()));
                 }
-                self.execute(cmd, None);
                 self.handle_expire(set.key, &set.meta.expire);
             }
             Object::SortedSet(sorted_set) => {
-                let mut cmd = redis::cmd("zadd");
-                cmd.arg(sorted_set.key);
-                for item in sorted_set.items {
-                    cmd.arg(item.score).arg(item.member.as_slice());
+                let new_key = self.rename(sorted_set.key);
+                for items in sorted_set.items.chunks(chunk_size) {
+                    let mut cmd = redis::cmd("zadd");
+                    cmd.arg(new_key.as_slice());
+                    for item in items {
+                        cmd.arg(item.score).arg(item.member.as_slice());
+                    }
+                    self.execute(cmd, Some(new_key.as_slice()));
                 }
-                self.execute(cmd, None);
                 self.handle_expire(sorted_set.key, &sorted_set.meta.expire);
             }
             Object::Hash(hash) => {
-                let mut cmd = redis::cmd("hmset");
-                cmd.arg(hash.key);
-                for field in hash.fields {
-                    cmd.arg(field.name.as_slice()).arg(field.value.as_slice());
+                let new_key = self.rename(hash.key);
+                for fields in hash.fields.chunks(chunk_size) {
+                    let mut cmd = redis::cmd("hmset");
+                    cmd.arg(new_key.as_slice());
+                    for field in fields {
+                        cmd.arg(field.name.as_slice()).arg(field.value.as_slice());
+                    }
+                    self.execute(cmd, Some(new_key.as_slice()));
                 }
-                self.execute(cmd, None);
                 self.handle_expire(hash.key, &hash.meta.expire);
             }
             Object::Stream(key, stream) => {
+                let new_key = self.rename(&key);
                 for (id, entry) in stream.entries {
                     let mut cmd = redis::cmd("XADD");
-                    cmd.arg(key.as_slice());
+                    cmd.arg(new_key.as_slice());
                     cmd.arg(id.to_string());
                     for (field, value) in entry.fields {
                         cmd.arg(field).arg(value);
                     }
-                    self.execute(cmd, Some(key.as_slice()));
+                    self.execute(cmd, Some(new_key.as_slice()));
                 }
                 for group in stream.groups {
                     let mut cmd = redis::cmd("XGROUP");
                     cmd.arg("CREATE")
-                        .arg(key.as_slice())
+                        .arg(new_key.as_slice())
                         .arg(group.name)
-                        .arg(group.last_id.to_string());
-                    self.execute(cmd, Some(key.as_slice()));
+                        .arg(group.last_id.to_string())
+                        .arg("MKSTREAM");
+                    self.execute(cmd, Some(new_key.as_slice()));
+                }
+                for cmd in stream_state {
+                    self.execute(cmd, Some(new_key.as_slice()));
                 }
                 self.handle_expire(key.as_slice(), &stream.meta.expire);
             }
@@ -77,15 +158,18 @@ pub trait CommandConverter {
     }
 
     fn handle_aof(&mut self, cmd: Command) {
+        if !self.in_accepted_db(&cmd) || !self.accept_keys(&cmd) {
+            return;
+        }
         match cmd {
             Command::APPEND(append) => {
                 let mut cmd = redis::cmd("APPEND");
-                cmd.arg(append.key).arg(append.value);
+                cmd.arg(self.rename(append.key)).arg(append.value);
                 self.execute(cmd, None);
             }
             Command::BITFIELD(bitfield) => {
                 let mut cmd = redis::cmd("BITFIELD");
-                cmd.arg(bitfield.key);
+                cmd.arg(self.rename(bitfield.key));
                 if let Some(statement) = &bitfield.statements {
                     for op in statement {
                         match op {
@@ -137,41 +221,47 @@ pub trait CommandConverter {
                         cmd.arg("NOT");
                     }
                 }
-                cmd.arg(bitop.dest_key);
+                cmd.arg(self.rename(bitop.dest_key));
                 for key in &bitop.keys {
-                    cmd.arg(key.as_slice());
+                    cmd.arg(self.rename(key));
                 }
                 self.execute(cmd, None);
             }
             Command::BRPOPLPUSH(brpoplpush) => {
                 let mut cmd = redis::cmd("BRPOPLPUSH");
-                cmd.arg(brpoplpush.source)
-                    .arg(brpoplpush.destination)
+                cmd.arg(self.rename(brpoplpush.source))
+                    .arg(self.rename(brpoplpush.destination))
                     .arg(brpoplpush.timeout);
                 self.execute(cmd, None);
             }
             Command::DECR(decr) => {
                 let mut cmd = redis::cmd("DECR");
-                cmd.arg(decr.key);
+                cmd.arg(self.rename(decr.key));
                 self.execute(cmd, None);
             }
             Command::DECRBY(decrby) => {
                 let mut cmd = redis::cmd("DECRBY");
-                cmd.arg(decrby.key).arg(decrby.decrement);
+                cmd.arg(self.rename(decrby.key)).arg(decrby.decrement);
                 self.execute(cmd, None);
             }
             Command::DEL(del) => {
                 let mut cmd = redis::cmd("DEL");
+                let mut accepted = false;
                 for key in &del.keys {
-                    cmd.arg(key.as_slice());
+                    if self.filter().accept(key) {
+                        cmd.arg(self.rename(key));
+                        accepted = true;
+                    }
+                }
+                if accepted {
+                    self.execute(cmd, None);
                 }
-                self.execute(cmd, None);
             }
             Command::EVAL(eval) => {
                 let mut cmd = redis::cmd("EVAL");
                 cmd.arg(eval.script).arg(eval.num_keys);
                 for key in &eval.keys {
-                    cmd.arg(*key);
+                    cmd.arg(self.rename(key));
                 }
                 for arg in &eval.args {
                     cmd.arg(*arg);
@@ -182,7 +272,7 @@ pub trait CommandConverter {
                 let mut cmd = redis::cmd("EVALSHA");
                 cmd.arg(evalsha.sha1).arg(evalsha.num_keys);
                 for key in &evalsha.keys {
-                    cmd.arg(*key);
+                    cmd.arg(self.rename(key));
                 }
                 for arg in &evalsha.args {
                     cmd.arg(*arg);
@@ -191,25 +281,38 @@ pub trait CommandConverter {
             }
             Command::EXPIRE(expire) => {
                 let mut cmd = redis::cmd("EXPIRE");
-                cmd.arg(expire.key).arg(expire.seconds);
+                cmd.arg(self.rename(expire.key)).arg(expire.seconds);
                 self.execute(cmd, None);
             }
             Command::EXPIREAT(expireat) => {
                 let mut cmd = redis::cmd("EXPIREAT");
-                cmd.arg(expireat.key).arg(expireat.timestamp);
+                cmd.arg(self.rename(expireat.key)).arg(expireat.timestamp);
                 self.execute(cmd, None);
             }
             Command::EXEC => {
                 let cmd = redis::cmd("EXEC");
                 self.execute(cmd, None);
             }
-            Command::FLUSHALL(flushall) => {
-                let mut cmd = redis::cmd("FLUSHALL");
-                if flushall._async.is_some() {
-                    cmd.arg("ASYNC");
+            Command::FLUSHALL(flushall) => match self.filter().flushall_dbs() {
+                None => {
+                    let mut cmd = redis::cmd("FLUSHALL");
+                    if flushall._async.is_some() {
+                        cmd.arg("ASYNC");
+                    }
+                    self.execute(cmd, None);
+                }
+                Some(dbs) => {
+                    for db in dbs {
+                        self.swap_db(db as i32);
+                        let mut cmd = redis::cmd("FLUSHDB");
+                        if flushall._async.is_some() {
+                            cmd.arg("ASYNC");
+                        }
+                        self.execute(cmd, None);
+                    }
+                    self.restore_db();
                 }
-                self.execute(cmd, None);
-            }
+            },
             Command::FLUSHDB(flushdb) => {
                 let mut cmd = redis::cmd("FLUSHDB");
                 if flushdb._async.is_some() {
@@ -219,12 +322,12 @@ pub trait CommandConverter {
             }
             Command::GETSET(getset) => {
                 let mut cmd = redis::cmd("GETSET");
-                cmd.arg(getset.key).arg(getset.value);
+                cmd.arg(self.rename(getset.key)).arg(getset.value);
                 self.execute(cmd, None);
             }
             Command::HDEL(hdel) => {
                 let mut cmd = redis::cmd("HDEL");
-                cmd.arg(hdel.key);
+                cmd.arg(self.rename(hdel.key));
                 for field in &hdel.fields {
                     cmd.arg(*field);
                 }
@@ -232,12 +335,14 @@ pub trait CommandConverter {
             }
             Command::HINCRBY(hincrby) => {
                 let mut cmd = redis::cmd("HINCRBY");
-                cmd.arg(hincrby.key).arg(hincrby.field).arg(hincrby.increment);
+                cmd.arg(self.rename(hincrby.key))
+                    .arg(hincrby.field)
+                    .arg(hincrby.increment);
                 self.execute(cmd, None);
             }
             Command::HMSET(hmset) => {
                 let mut cmd = redis::cmd("HMSET");
-                cmd.arg(hmset.key);
+                cmd.arg(self.rename(hmset.key));
                 for field in &hmset.fields {
                     cmd.arg(field.name).arg(field.value);
                 }
@@ -245,7 +350,7 @@ pub trait CommandConverter {
             }
             Command::HSET(hset) => {
                 let mut cmd = redis::cmd("HSET");
-                cmd.arg(hset.key);
+                cmd.arg(self.rename(hset.key));
                 for field in &hset.fields {
                     cmd.arg(field.name).arg(field.value);
                 }
@@ -253,22 +358,22 @@ pub trait CommandConverter {
             }
             Command::HSETNX(hsetnx) => {
                 let mut cmd = redis::cmd("HSETNX");
-                cmd.arg(hsetnx.key).arg(hsetnx.field).arg(hsetnx.value);
+                cmd.arg(self.rename(hsetnx.key)).arg(hsetnx.field).arg(hsetnx.value);
                 self.execute(cmd, None);
             }
             Command::INCR(incr) => {
                 let mut cmd = redis::cmd("INCR");
-                cmd.arg(incr.key);
+                cmd.arg(self.rename(incr.key));
                 self.execute(cmd, None);
             }
             Command::INCRBY(incrby) => {
                 let mut cmd = redis::cmd("INCRBY");
-                cmd.arg(incrby.key).arg(incrby.increment);
+                cmd.arg(self.rename(incrby.key)).arg(incrby.increment);
                 self.execute(cmd, None);
             }
             Command::LINSERT(linsert) => {
                 let mut cmd = redis::cmd("LINSERT");
-                cmd.arg(linsert.key);
+                cmd.arg(self.rename(linsert.key));
                 match linsert.position {
                     POSITION::BEFORE => {
                         cmd.arg("BEFORE");
@@ -282,12 +387,12 @@ pub trait CommandConverter {
             }
             Command::LPOP(lpop) => {
                 let mut cmd = redis::cmd("LPOP");
-                cmd.arg(lpop.key);
+                cmd.arg(self.rename(lpop.key));
                 self.execute(cmd, None);
             }
             Command::LPUSH(lpush) => {
                 let mut cmd = redis::cmd("LPUSH");
-                cmd.arg(lpush.key);
+                cmd.arg(self.rename(lpush.key));
                 for element in &lpush.elements {
                     cmd.arg(*element);
                 }
@@ -295,7 +400,7 @@ pub trait CommandConverter {
             }
             Command::LPUSHX(lpushx) => {
                 let mut cmd = redis::cmd("LPUSHX");
-                cmd.arg(lpushx.key);
+                cmd.arg(self.rename(lpushx.key));
                 for element in &lpushx.elements {
                     cmd.arg(*element);
                 }
@@ -303,37 +408,57 @@ pub trait CommandConverter {
             }
             Command::LREM(lrem) => {
                 let mut cmd = redis::cmd("LREM");
-                cmd.arg(lrem.key).arg(lrem.count).arg(lrem.element);
+                cmd.arg(self.rename(lrem.key)).arg(lrem.count).arg(lrem.element);
                 self.execute(cmd, None);
             }
             Command::LSET(lset) => {
                 let mut cmd = redis::cmd("LSET");
-                cmd.arg(lset.key).arg(lset.index).arg(lset.element);
+                cmd.arg(self.rename(lset.key)).arg(lset.index).arg(lset.element);
                 self.execute(cmd, None);
             }
             Command::LTRIM(ltrim) => {
                 let mut cmd = redis::cmd("LTRIM");
-                cmd.arg(ltrim.key).arg(ltrim.start).arg(ltrim.stop);
+                cmd.arg(self.rename(ltrim.key)).arg(ltrim.start).arg(ltrim.stop);
                 self.execute(cmd, None);
             }
             Command::MOVE(_move) => {
-                let mut cmd = redis::cmd("MOVE");
-                cmd.arg(_move.key).arg(_move.db);
-                self.execute(cmd, None);
+                let db = parse_db(_move.db);
+                if self.filter().accept_db(db) {
+                    let mut cmd = redis::cmd("MOVE");
+                    cmd.arg(self.rename(_move.key)).arg(self.filter().map_db(db));
+                    self.execute(cmd, None);
+                } else {
+                    // key被移动至不需要复制的db, 相当于删除了key
+                    let mut cmd = redis::cmd("DEL");
+                    cmd.arg(self.rename(_move.key));
+                    self.execute(cmd, None);
+                }
             }
             Command::MSET(mset) => {
                 let mut cmd = redis::cmd("MSET");
+                let mut accepted = false;
                 for kv in &mset.key_values {
-                    cmd.arg(kv.key).arg(kv.value);
+                    if self.filter().accept(kv.key) {
+                        cmd.arg(self.rename(kv.key)).arg(kv.value);
+                        accepted = true;
+                    }
+                }
+                if accepted {
+                    self.execute(cmd, None);
                 }
-                self.execute(cmd, None);
             }
             Command::MSETNX(msetnx) => {
                 let mut cmd = redis::cmd("MSETNX");
+                let mut accepted = false;
                 for kv in &msetnx.key_values {
-                    cmd.arg(kv.key).arg(kv.value);
+                    if self.filter().accept(kv.key) {
+                        cmd.arg(self.rename(kv.key)).arg(kv.value);
+                        accepted = true;
+                    }
+                }
+                if accepted {
+                    self.execute(cmd, None);
                 }
-                self.execute(cmd, None);
             }
             Command::MULTI => {
                 let cmd = redis::cmd("MULTI");
@@ -341,22 +466,22 @@ pub trait CommandConverter {
             }
             Command::PERSIST(persist) => {
                 let mut cmd = redis::cmd("PERSIST");
-                cmd.arg(persist.key);
+                cmd.arg(self.rename(persist.key));
                 self.execute(cmd, None);
             }
             Command::PEXPIRE(pexpire) => {
                 let mut cmd = redis::cmd("PEXPIRE");
-                cmd.arg(pexpire.milliseconds);
+                cmd.arg(self.rename(pexpire.key)).arg(pexpire.milliseconds);
                 self.execute(cmd, None);
             }
             Command::PEXPIREAT(pexpireat) => {
                 let mut cmd = redis::cmd("PEXPIREAT");
-                cmd.arg(pexpireat.mill_timestamp);
+                cmd.arg(self.rename(pexpireat.key)).arg(pexpireat.mill_timestamp);
                 self.execute(cmd, None);
             }
             Command::PFADD(pfadd) => {
                 let mut cmd = redis::cmd("PFADD");
-                cmd.arg(pfadd.key);
+                cmd.arg(self.rename(pfadd.key));
                 for element in &pfadd.elements {
                     cmd.arg(*element);
                 }
@@ -364,22 +489,30 @@ pub trait CommandConverter {
             }
             Command::PFCOUNT(pfcount) => {
                 let mut cmd = redis::cmd("PFCOUNT");
+                let mut accepted = false;
                 for key in &pfcount.keys {
-                    cmd.arg(*key);
+                    if self.filter().accept(key) {
+                        cmd.arg(self.rename(key));
+                        accepted = true;
+                    }
+                }
+                if accepted {
+                    self.execute(cmd, None);
                 }
-                self.execute(cmd, None);
             }
             Command::PFMERGE(pfmerge) => {
                 let mut cmd = redis::cmd("PFMERGE");
-                cmd.arg(pfmerge.dest_key);
+                cmd.arg(self.rename(pfmerge.dest_key));
                 for key in &pfmerge.source_keys {
-                    cmd.arg(*key);
+                    cmd.arg(self.rename(key));
                 }
                 self.execute(cmd, None);
             }
             Command::PSETEX(psetex) => {
                 let mut cmd = redis::cmd("PSETEX");
-                cmd.arg(psetex.key).arg(psetex.milliseconds).arg(psetex.value);
+                cmd.arg(self.rename(psetex.key))
+                    .arg(psetex.milliseconds)
+                    .arg(psetex.value);
                 self.execute(cmd, None);
             }
             Command::PUBLISH(publish) => {
@@ -389,17 +522,17 @@ pub trait CommandConverter {
             }
             Command::RENAME(rename) => {
                 let mut cmd = redis::cmd("RENAME");
-                cmd.arg(rename.key).arg(rename.new_key);
+                cmd.arg(self.rename(rename.key)).arg(self.rename(rename.new_key));
                 self.execute(cmd, None);
             }
             Command::RENAMENX(renamenx) => {
                 let mut cmd = redis::cmd("RENAMENX");
-                cmd.arg(renamenx.key).arg(renamenx.new_key);
+                cmd.arg(self.rename(renamenx.key)).arg(self.rename(renamenx.new_key));
                 self.execute(cmd, None);
             }
             Command::RESTORE(restore) => {
                 let mut cmd = redis::cmd("RESTORE");
-                cmd.arg(restore.key).arg(restore.ttl).arg(restore.value);
+                cmd.arg(self.rename(restore.key)).arg(restore.ttl).arg(restore.value);
                 if restore.replace.is_some() {
                     cmd.arg("REPLACE");
                 }
@@ -416,17 +549,18 @@ pub trait CommandConverter {
             }
             Command::RPOP(rpop) => {
                 let mut cmd = redis::cmd("RPOP");
-                cmd.arg(rpop.key);
+                cmd.arg(self.rename(rpop.key));
                 self.execute(cmd, None);
             }
             Command::RPOPLPUSH(rpoplpush) => {
                 let mut cmd = redis::cmd("RPOPLPUSH");
-                cmd.arg(rpoplpush.source).arg(rpoplpush.destination);
+                cmd.arg(self.rename(rpoplpush.source))
+                    .arg(self.rename(rpoplpush.destination));
                 self.execute(cmd, None);
             }
             Command::RPUSH(rpush) => {
                 let mut cmd = redis::cmd("RPUSH");
-                cmd.arg(rpush.key);
+                cmd.arg(self.rename(rpush.key));
                 for element in &rpush.elements {
                     cmd.arg(*element);
                 }
@@ -434,7 +568,7 @@ pub trait CommandConverter {
             }
             Command::RPUSHX(rpushx) => {
                 let mut cmd = redis::cmd("RPUSHX");
-                cmd.arg(rpushx.key);
+                cmd.arg(self.rename(rpushx.key));
                 for element in &rpushx.elements {
                     cmd.arg(*element);
                 }
@@ -442,7 +576,7 @@ pub trait CommandConverter {
             }
             Command::SADD(sadd) => {
                 let mut cmd = redis::cmd("SADD");
-                cmd.arg(sadd.key);
+                cmd.arg(self.rename(sadd.key));
                 for member in &sadd.members {
                     cmd.arg(*member);
                 }
@@ -460,15 +594,15 @@ pub trait CommandConverter {
             }
             Command::SDIFFSTORE(sdiffstore) => {
                 let mut cmd = redis::cmd("SDIFFSTORE");
-                cmd.arg(sdiffstore.destination);
+                cmd.arg(self.rename(sdiffstore.destination));
                 for key in &sdiffstore.keys {
-                    cmd.arg(*key);
+                    cmd.arg(self.rename(key));
                 }
                 self.execute(cmd, None);
             }
             Command::SET(set) => {
                 let mut cmd = redis::cmd("SET");
-                cmd.arg(set.key).arg(set.value);
+                cmd.arg(self.rename(set.key)).arg(set.value);
                 if let Some((expire_type, value)) = set.expire.as_ref() {
                     match expire_type {
                         ExpireType::EX => {
@@ -496,55 +630,56 @@ pub trait CommandConverter {
             }
             Command::SETBIT(setbit) => {
                 let mut cmd = redis::cmd("SETBIT");
-                cmd.arg(setbit.key).arg(setbit.offset).arg(setbit.value);
+                cmd.arg(self.rename(setbit.key)).arg(setbit.offset).arg(setbit.value);
                 self.execute(cmd, None);
             }
             Command::SETEX(setex) => {
                 let mut cmd = redis::cmd("SETEX");
-                cmd.arg(setex.key).arg(setex.seconds).arg(setex.value);
+                cmd.arg(self.rename(setex.key)).arg(setex.seconds).arg(setex.value);
                 self.execute(cmd, None);
             }
             Command::SETNX(setnx) => {
                 let mut cmd = redis::cmd("SETNX");
-                cmd.arg(setnx.key).arg(setnx.value);
+                cmd.arg(self.rename(setnx.key)).arg(setnx.value);
                 self.execute(cmd, None);
             }
             Command::SELECT(select) => {
-                let mut cmd = redis::cmd("SELECT");
-                cmd.arg(select.db);
-                self.swap_db(select.db);
-                self.execute(cmd, None);
+                self.select_db(select.db as i64);
             }
             Command::SETRANGE(setrange) => {
                 let mut cmd = redis::cmd("SETRANGE");
-                cmd.arg(setrange.key).arg(setrange.offset).arg(setrange.value);
+                cmd.arg(self.rename(setrange.key))
+                    .arg(setrange.offset)
+                    .arg(setrange.value);
                 self.execute(cmd, None);
             }
             Command::SINTERSTORE(sinterstore) => {
                 let mut cmd = redis::cmd("SINTERSTORE");
-                cmd.arg(sinterstore.destination);
+                cmd.arg(self.rename(sinterstore.destination));
                 for key in &sinterstore.keys {
-                    cmd.arg(*key);
+                    cmd.arg(self.rename(key));
                 }
                 self.execute(cmd, None);
             }
             Command::SMOVE(smove) => {
                 let mut cmd = redis::cmd("SMOVE");
-                cmd.arg(smove.source).arg(smove.destination).arg(smove.member);
+                cmd.arg(self.rename(smove.source))
+                    .arg(self.rename(smove.destination))
+                    .arg(smove.member);
                 self.execute(cmd, None);
             }
             Command::SORT(sort) => {
                 let mut cmd = redis::cmd("SORT");
-                cmd.arg(sort.key);
+                cmd.arg(self.rename(sort.key));
                 if let Some(pattern) = sort.by_pattern {
-                    cmd.arg("BY").arg(pattern);
+                    cmd.arg("BY").arg(self.rename_pattern(pattern));
                 }
                 if let Some(limit) = &sort.limit {
                     cmd.arg("LIMIT").arg(limit.offset).arg(limit.count);
                 }
                 if let Some(get_patterns) = &sort.get_patterns {
                     for pattern in get_patterns {
-                        cmd.arg("GET").arg(*pattern);
+                        cmd.arg("GET").arg(self.rename_pattern(pattern));
                     }
                 }
                 if let Some(order) = &sort.order {
@@ -561,13 +696,13 @@ pub trait CommandConverter {
                     cmd.arg("ALPHA");
                 }
                 if let Some(dest) = sort.destination {
-                    cmd.arg("STORE").arg(dest);
+                    cmd.arg("STORE").arg(self.rename(dest));
                 }
                 self.execute(cmd, None);
             }
             Command::SREM(srem) => {
                 let mut cmd = redis::cmd("SREM");
-                cmd.arg(srem.key);
+                cmd.arg(self.rename(srem.key));
                 for member in &srem.members {
                     cmd.arg(*member);
                 }
@@ -575,27 +710,35 @@ pub trait CommandConverter {
             }
             Command::SUNIONSTORE(sunion) => {
                 let mut cmd = redis::cmd("SUNIONSTORE");
-                cmd.arg(sunion.destination);
+                cmd.arg(self.rename(sunion.destination));
                 for key in &sunion.keys {
-                    cmd.arg(*key);
+                    cmd.arg(self.rename(key));
                 }
                 self.execute(cmd, None);
             }
             Command::SWAPDB(swapdb) => {
-                let mut cmd = redis::cmd("SWAPDB");
-                cmd.arg(swapdb.index1).arg(swapdb.index2);
-                self.execute(cmd, None);
+                if let Some((index1, index2)) = self.map_swapdb(swapdb.index1, swapdb.index2) {
+                    let mut cmd = redis::cmd("SWAPDB");
+                    cmd.arg(index1).arg(index2);
+                    self.execute(cmd, None);
+                }
             }
             Command::UNLINK(unlink) => {
                 let mut cmd = redis::cmd("UNLINK");
+                let mut accepted = false;
                 for key in &unlink.keys {
-                    cmd.arg(*key);
+                    if self.filter().accept(key) {
+                        cmd.arg(self.rename(key));
+                        accepted = true;
+                    }
+                }
+                if accepted {
+                    self.execute(cmd, None);
                 }
-                self.execute(cmd, None);
             }
             Command::ZADD(zadd) => {
                 let mut cmd = redis::cmd("ZADD");
-                cmd.arg(zadd.key);
+                cmd.arg(self.rename(zadd.key));
                 if let Some(exist_type) = &zadd.exist_type {
                     match exist_type {
                         ExistType::NX => {
@@ -619,14 +762,16 @@ pub trait CommandConverter {
             }
             Command::ZINCRBY(zincrby) => {
                 let mut cmd = redis::cmd("ZINCRBY");
-                cmd.arg(zincrby.key).arg(zincrby.increment).arg(zincrby.member);
+                cmd.arg(self.rename(zincrby.key))
+                    .arg(zincrby.increment)
+                    .arg(zincrby.member);
                 self.execute(cmd, None);
             }
             Command::ZINTERSTORE(zinterstore) => {
                 let mut cmd = redis::cmd("ZINTERSTORE");
-                cmd.arg(zinterstore.destination).arg(zinterstore.num_keys);
+                cmd.arg(self.rename(zinterstore.destination)).arg(zinterstore.num_keys);
                 for key in &zinterstore.keys {
-                    cmd.arg(*key);
+                    cmd.arg(self.rename(key));
                 }
                 if let Some(weights) = &zinterstore.weights {
                     cmd.arg("WEIGHTS");
@@ -652,7 +797,7 @@ pub trait CommandConverter {
             }
             Command::ZPOPMAX(zpopmax) => {
                 let mut cmd = redis::cmd("ZPOPMAX");
-                cmd.arg(zpopmax.key);
+                cmd.arg(self.rename(zpopmax.key));
                 if let Some(count) = zpopmax.count {
                     cmd.arg(count);
                 }
@@ -660,7 +805,7 @@ pub trait CommandConverter {
             }
             Command::ZPOPMIN(zpopmin) => {
                 let mut cmd = redis::cmd("ZPOPMIN");
-                cmd.arg(zpopmin.key);
+                cmd.arg(self.rename(zpopmin.key));
                 if let Some(count) = zpopmin.count {
                     cmd.arg(count);
                 }
@@ -668,7 +813,7 @@ pub trait CommandConverter {
             }
             Command::ZREM(zrem) => {
                 let mut cmd = redis::cmd("ZREM");
-                cmd.arg(zrem.key);
+                cmd.arg(self.rename(zrem.key));
                 for member in &zrem.members {
                     cmd.arg(*member);
                 }
@@ -676,24 +821,24 @@ pub trait CommandConverter {
             }
             Command::ZREMRANGEBYLEX(zrem) => {
                 let mut cmd = redis::cmd("ZREMRANGEBYLEX");
-                cmd.arg(zrem.key).arg(zrem.min).arg(zrem.max);
+                cmd.arg(self.rename(zrem.key)).arg(zrem.min).arg(zrem.max);
                 self.execute(cmd, None);
             }
             Command::ZREMRANGEBYRANK(zrem) => {
                 let mut cmd = redis::cmd("ZREMRANGEBYRANK");
-                cmd.arg(zrem.key).arg(zrem.start).arg(zrem.stop);
+                cmd.arg(self.rename(zrem.key)).arg(zrem.start).arg(zrem.stop);
                 self.execute(cmd, None);
             }
             Command::ZREMRANGEBYSCORE(zrem) => {
                 let mut cmd = redis::cmd("ZREMRANGEBYSCORE");
-                cmd.arg(zrem.key).arg(zrem.min).arg(zrem.max);
+                cmd.arg(self.rename(zrem.key)).arg(zrem.min).arg(zrem.max);
                 self.execute(cmd, None);
             }
             Command::ZUNIONSTORE(zunion) => {
                 let mut cmd = redis::cmd("ZUNIONSTORE");
-                cmd.arg(zunion.destination).arg(zunion.destination).arg(zunion.num_keys);
+                cmd.arg(self.rename(zunion.destination)).arg(zunion.num_keys);
                 for key in &zunion.keys {
-                    cmd.arg(*key);
+                    cmd.arg(self.rename(key));
                 }
                 if let Some(weights) = &zunion.weights {
                     cmd.arg("WEIGHTS");
@@ -719,14 +864,19 @@ pub trait CommandConverter {
             }
             Command::Other(raw_cmd) => {
                 let mut cmd = redis::cmd(&raw_cmd.name);
-                for arg in raw_cmd.args {
-                    cmd.arg(arg);
+                // 与keys()一致, 将第一个参数视为key
+                for (i, arg) in raw_cmd.args.iter().enumerate() {
+                    if i == 0 {
+                        cmd.arg(self.rename(arg));
+                    } else {
+                        cmd.arg(arg.as_slice());
+                    }
                 }
                 self.execute(cmd, None);
             }
             Command::XACK(xack) => {
                 let mut cmd = redis::cmd("XACK");
-                cmd.arg(xack.key).arg(xack.group);
+                cmd.arg(self.rename(xack.key)).arg(xack.group);
                 for id in &xack.ids {
                     cmd.arg(id.as_slice());
                 }
@@ -734,7 +884,7 @@ pub trait CommandConverter {
             }
             Command::XADD(xadd) => {
                 let mut cmd = redis::cmd("XADD");
-                cmd.arg(xadd.key).arg(xadd.id);
+                cmd.arg(self.rename(xadd.key)).arg(xadd.id);
                 for field in &xadd.fields {
                     cmd.arg(field.name).arg(field.value);
                 }
@@ -742,7 +892,7 @@ pub trait CommandConverter {
             }
             Command::XCLAIM(xclaim) => {
                 let mut cmd = redis::cmd("XCLAIM");
-                cmd.arg(xclaim.key)
+                cmd.arg(self.rename(xclaim.key))
                     .arg(xclaim.group)
                     .arg(xclaim.consumer)
                     .arg(xclaim.min_idle_time);
@@ -768,7 +918,7 @@ pub trait CommandConverter {
             }
             Command::XDEL(xdel) => {
                 let mut cmd = redis::cmd("XDEL");
-                cmd.arg(xdel.key);
+                cmd.arg(self.rename(xdel.key));
                 for id in &xdel.ids {
                     cmd.arg(id.as_slice());
                 }
@@ -777,17 +927,23 @@ pub trait CommandConverter {
             Command::XGROUP(xgroup) => {
                 let mut cmd = redis::cmd("XGROUP");
                 if let Some(create) = &xgroup.create {
-                    cmd.arg("CREATE").arg(create.key).arg(create.group_name).arg(create.id);
+                    cmd.arg("CREATE")
+                        .arg(self.rename(create.key))
+                        .arg(create.group_name)
+                        .arg(create.id);
                 }
                 if let Some(set_id) = &xgroup.set_id {
-                    cmd.arg("SETID").arg(set_id.key).arg(set_id.group_name).arg(set_id.id);
+                    cmd.arg("SETID")
+                        .arg(self.rename(set_id.key))
+                        .arg(set_id.group_name)
+                        .arg(set_id.id);
                 }
                 if let Some(destroy) = &xgroup.destroy {
-                    cmd.arg("DESTROY").arg(destroy.key).arg(destroy.group_name);
+                    cmd.arg("DESTROY").arg(self.rename(destroy.key)).arg(destroy.group_name);
                 }
                 if let Some(del_consumer) = &xgroup.del_consumer {
                     cmd.arg("DELCONSUMER")
-                        .arg(del_consumer.key)
+                        .arg(self.rename(del_consumer.key))
                         .arg(del_consumer.group_name)
                         .arg(del_consumer.consumer_name);
                 }
@@ -795,7 +951,7 @@ pub trait CommandConverter {
             }
             Command::XTRIM(xtrim) => {
                 let mut cmd = redis::cmd("XTRIM");
-                cmd.arg(xtrim.key).arg("MAXLEN");
+                cmd.arg(self.rename(xtrim.key)).arg("MAXLEN");
                 if xtrim.approximation {
                     cmd.arg("~");
                 }
@@ -805,24 +961,564 @@ pub trait CommandConverter {
         }
     }
 
+    // 记录源Redis切换后的db, 若该db需要复制, 则将目的Redis切换至其映射后的db. 返回该db是否需要复制
+    fn select_db(&mut self, db: i64) -> bool {
+        if self.filter().switch_db(db) && self.filter().accept_db(db) {
+            let target_db = self.filter().map_db(db);
+            self.swap_db(target_db as i32);
+        }
+        self.filter().accept_db(db)
+    }
+
+    // 将目的Redis切换回源Redis当前db所对应的db
+    fn restore_db(&mut self) {
+        let db = self.filter().current_db();
+        if self.filter().accept_db(db) {
+            let target_db = self.filter().map_db(db);
+            self.swap_db(target_db as i32);
+        }
+    }
+
+    // 源Redis当前所在的db不需要复制时, 除SELECT等与db无关的命令外全部跳过
+    fn in_accepted_db(&mut self, cmd: &Command) -> bool {
+        match cmd {
+            Command::SELECT(_)
+            | Command::SWAPDB(_)
+            | Command::FLUSHALL(_)
+            | Command::MULTI
+            | Command::EXEC
+            | Command::PUBLISH(_)
+            | Command::SCRIPTFLUSH
+            | Command::SCRIPTLOAD(_) => true,
+            _ => {
+                let db = self.filter().current_db();
+                self.filter().accept_db(db)
+            }
+        }
+    }
+
+    fn map_swapdb(&mut self, index1: &[u8], index2: &[u8]) -> Option<(i64, i64)> {
+        let (index1, index2) = (parse_db(index1), parse_db(index2));
+        let mapped = self.filter().map_swapdb(index1, index2);
+        if mapped.is_none() && (self.filter().accept_db(index1) || self.filter().accept_db(index2)) {
+            warn!("SWAPDB {} {}中只有一个db需要复制, 已跳过", index1, index2);
+        }
+        mapped
+    }
+
+    // DEL, UNLINK等可拆分的命令在各自的处理逻辑中逐个key进行过滤, 其他命令只有在所有key都满足过滤条件时才会被复制
+    fn accept_keys(&mut self, cmd: &Command) -> bool {
+        match cmd {
+            Command::DEL(_) | Command::UNLINK(_) | Command::MSET(_) | Command::MSETNX(_) | Command::PFCOUNT(_) => true,
+            Command::RENAME(rename) => self.accept_rename(rename.key, rename.new_key),
+            Command::RENAMENX(renamenx) => self.accept_rename(renamenx.key, renamenx.new_key),
+            _ => {
+                let keys = keys(cmd);
+                let accepted = keys.iter().filter(|key| self.filter().accept(key)).count();
+                if accepted > 0 && accepted < keys.len() {
+                    let keys: Vec<_> = keys.iter().map(|key| String::from_utf8_lossy(key)).collect();
+                    warn!("命令中只有部分key满足过滤条件, 已跳过: {:?}", keys);
+                }
+                accepted == keys.len()
+            }
+        }
+    }
+
+    fn accept_rename(&mut self, key: &[u8], new_key: &[u8]) -> bool {
+        match (self.filter().accept(key), self.filter().accept(new_key)) {
+            (true, true) => true,
+            (false, false) => false,
+            (true, false) => {
+                // key被重命名至过滤范围之外, 相当于删除了key
+                let mut cmd = redis::cmd("DEL");
+                cmd.arg(self.rename(key));
+                self.execute(cmd, None);
+                false
+            }
+            (false, true) => {
+                warn!(
+                    "RENAME的源key不满足过滤条件, 已跳过: {} -> {}",
+                    String::from_utf8_lossy(key),
+                    String::from_utf8_lossy(new_key)
+                );
+                false
+            }
+        }
+    }
+
     fn handle_expire(&mut self, key: &[u8], expire: &Option<(rdb::ExpireType, i64)>) {
         if let Some((expire_type, ttl)) = expire {
+            let key = self.rename(key);
             match expire_type {
                 rdb::ExpireType::Second => {
                     let mut cmd = redis::cmd("EXPIREAT");
-                    cmd.arg(key).arg(*ttl);
-                    self.execute(cmd, Some(key));
+                    cmd.arg(key.as_slice()).arg(*ttl);
+                    self.execute(cmd, Some(key.as_slice()));
                 }
                 rdb::ExpireType::Millisecond => {
                     let mut cmd = redis::cmd("PEXPIREAT");
-                    cmd.arg(key).arg(*ttl);
-                    self.execute(cmd, Some(key));
+                    cmd.arg(key.as_slice()).arg(*ttl);
+                    self.execute(cmd, Some(key.as_slice()));
                 }
             }
         }
     }
 
+    fn rename(&self, key: &[u8]) -> Vec<u8> {
+        self.renamer().rename(key)
+    }
+
+    // SORT的BY/GET pattern同样指向其他key, "#"表示元素自身, 不进行重命名
+    fn rename_pattern(&self, pattern: &[u8]) -> Vec<u8> {
+        if pattern == b"#" {
+            pattern.to_vec()
+        } else {
+            self.renamer().rename(pattern)
+        }
+    }
+
     fn execute(&mut self, cmd: Cmd, key: Option<&[u8]>);
 
+    fn filter(&mut self) -> &mut Filter;
+
+    fn renamer(&self) -> &Renamer;
+
     fn swap_db(&mut self, db: i32);
+
+    // RDB阶段list/set/zset/hash每条命令最多包含的元素数量, 0表示不拆分
+    fn chunk_size(&self) -> usize;
+
+    // 为Some时, RDB阶段以RESTORE的方式写入
+    fn restore(&self) -> Option<&Restore>;
+
+    // 为Some时, RDB阶段按照--conflict处理目的Redis中已存在的key
+    fn conflict(&mut self) -> Option<&mut Conflict>;
+
+    // 源Redis, 用于读取RDB对象中不包含的数据. 离线文件作为源时无法读取
+    fn source(&mut self) -> Option<&mut SourceReader>;
+}
+
+// 源Redis中stream的状态转换为XSETID、XGROUP SETID ... ENTRIESREAD、XGROUP CREATECONSUMER及XCLAIM命令.
+// RDB生成之后stream的变化会以AOF命令的形式复制, 因此只有在消息(或消费组的last id)与RDB中的一致时,
+// 才设置stream的last id(或消费组的entries-read); 消费者及PEL与之后的AOF命令叠加后的结果是一致的
+fn stream_commands(state: &StreamState, key: &[u8], stream: &rdb::Stream) -> Vec<Cmd> {
+    let mut cmds = Vec::new();
+    // 既没有消息也没有消费组时, 目的Redis中不会创建该stream
+    if state.matches(stream) && !(stream.entries.is_empty() && stream.groups.is_empty()) {
+        let mut cmd = redis::cmd("XSETID");
+        cmd.arg(key).arg(state.last_generated_id.as_str());
+        if let (Some(entries_added), Some(max_deleted_id)) = (state.entries_added, &state.max_deleted_id) {
+            cmd.arg("ENTRIESADDED")
+                .arg(entries_added)
+                .arg("MAXDELETEDID")
+                .arg(max_deleted_id.as_str());
+        }
+        cmds.push(cmd);
+    }
+    for group in stream.groups.iter() {
+        // 消费组在RDB生成之后已被删除
+        let live = match state.groups.iter().find(|live| live.name[..] == group.name[..]) {
+            Some(live) => live,
+            None => continue,
+        };
+        let last_id = group.last_id.to_string();
+        if let Some(entries_read) = live.entries_read {
+            if stream_id(&live.last_delivered_id) == stream_id(&last_id) {
+                let mut cmd = redis::cmd("XGROUP");
+                cmd.arg("SETID")
+                    .arg(key)
+                    .arg(&group.name[..])
+                    .arg(last_id.as_str())
+                    .arg("ENTRIESREAD")
+                    .arg(entries_read);
+                cmds.push(cmd);
+            }
+        }
+        // 有pending消息的消费者会由XCLAIM创建
+        for consumer in &live.idle_consumers {
+            let mut cmd = redis::cmd("XGROUP");
+            cmd.arg("CREATECONSUMER")
+                .arg(key)
+                .arg(&group.name[..])
+                .arg(consumer.as_slice());
+            cmds.push(cmd);
+        }
+        for (id, consumer, idle, count) in &live.pending {
+            let mut cmd = redis::cmd("XCLAIM");
+            cmd.arg(key)
+                .arg(&group.name[..])
+                .arg(consumer.as_slice())
+                .arg(0)
+                .arg(id.as_str())
+                .arg("IDLE")
+                .arg(*idle)
+                .arg("RETRYCOUNT")
+                .arg(*count)
+                .arg("FORCE")
+                .arg("JUSTID");
+            cmds.push(cmd);
+        }
+    }
+    cmds
+}
+
+// 获取命令中所有的key
+pub(crate) fn keys<'a>(cmd: &'a Command) -> Vec<&'a [u8]> {
+    match cmd {
+        Command::APPEND(append) => vec![append.key],
+        Command::BITFIELD(bitfield) => vec![bitfield.key],
+        Command::BITOP(bitop) => {
+            let mut keys = vec![bitop.dest_key];
+            keys.extend(bitop.keys.iter().map(|key| key.as_slice()));
+            keys
+        }
+        Command::BRPOPLPUSH(brpoplpush) => vec![brpoplpush.source, brpoplpush.destination],
+        Command::DECR(decr) => vec![decr.key],
+        Command::DECRBY(decrby) => vec![decrby.key],
+        Command::DEL(del) => del.keys.iter().map(|key| key.as_slice()).collect(),
+        Command::EVAL(eval) => eval.keys.clone(),
+        Command::EVALSHA(evalsha) => evalsha.keys.clone(),
+        Command::EXPIRE(expire) => vec![expire.key],
+        Command::EXPIREAT(expireat) => vec![expireat.key],
+        Command::GETSET(getset) => vec![getset.key],
+        Command::HDEL(hdel) => vec![hdel.key],
+        Command::HINCRBY(hincrby) => vec![hincrby.key],
+        Command::HMSET(hmset) => vec![hmset.key],
+        Command::HSET(hset) => vec![hset.key],
+        Command::HSETNX(hsetnx) => vec![hsetnx.key],
+        Command::INCR(incr) => vec![incr.key],
+        Command::INCRBY(incrby) => vec![incrby.key],
+        Command::LINSERT(linsert) => vec![linsert.key],
+        Command::LPOP(lpop) => vec![lpop.key],
+        Command::LPUSH(lpush) => vec![lpush.key],
+        Command::LPUSHX(lpushx) => vec![lpushx.key],
+        Command::LREM(lrem) => vec![lrem.key],
+        Command::LSET(lset) => vec![lset.key],
+        Command::LTRIM(ltrim) => vec![ltrim.key],
+        Command::MOVE(_move) => vec![_move.key],
+        Command::MSET(mset) => mset.key_values.iter().map(|kv| kv.key).collect(),
+        Command::MSETNX(msetnx) => msetnx.key_values.iter().map(|kv| kv.key).collect(),
+        Command::PERSIST(persist) => vec![persist.key],
+        Command::PEXPIRE(pexpire) => vec![pexpire.key],
+        Command::PEXPIREAT(pexpireat) => vec![pexpireat.key],
+        Command::PFADD(pfadd) => vec![pfadd.key],
+        Command::PFCOUNT(pfcount) => pfcount.keys.clone(),
+        Command::PFMERGE(pfmerge) => {
+            let mut keys = vec![pfmerge.dest_key];
+            keys.extend(pfmerge.source_keys.iter());
+            keys
+        }
+        Command::PSETEX(psetex) => vec![psetex.key],
+        Command::RENAME(rename) => vec![rename.key, rename.new_key],
+        Command::RENAMENX(renamenx) => vec![renamenx.key, renamenx.new_key],
+        Command::RESTORE(restore) => vec![restore.key],
+        Command::RPOP(rpop) => vec![rpop.key],
+        Command::RPOPLPUSH(rpoplpush) => vec![rpoplpush.source, rpoplpush.destination],
+        Command::RPUSH(rpush) => vec![rpush.key],
+        Command::RPUSHX(rpushx) => vec![rpushx.key],
+        Command::SADD(sadd) => vec![sadd.key],
+        Command::SDIFFSTORE(sdiffstore) => {
+            let mut keys = vec![sdiffstore.destination];
+            keys.extend(sdiffstore.keys.iter());
+            keys
+        }
+        Command::SET(set) => vec![set.key],
+        Command::SETBIT(setbit) => vec![setbit.key],
+        Command::SETEX(setex) => vec![setex.key],
+        Command::SETNX(setnx) => vec![setnx.key],
+        Command::SETRANGE(setrange) => vec![setrange.key],
+        Command::SINTERSTORE(sinterstore) => {
+            let mut keys = vec![sinterstore.destination];
+            keys.extend(sinterstore.keys.iter());
+            keys
+        }
+        Command::SMOVE(smove) => vec![smove.source, smove.destination],
+        Command::SORT(sort) => {
+            let mut keys = vec![sort.key];
+            if let Some(dest) = sort.destination {
+                keys.push(dest);
+            }
+            keys
+        }
+        Command::SREM(srem) => vec![srem.key],
+        Command::SUNIONSTORE(sunion) => {
+            let mut keys = vec![sunion.destination];
+            keys.extend(sunion.keys.iter());
+            keys
+        }
+        Command::UNLINK(unlink) => unlink.keys.clone(),
+        Command::ZADD(zadd) => vec![zadd.key],
+        Command::ZINCRBY(zincrby) => vec![zincrby.key],
+        Command::ZINTERSTORE(zinterstore) => {
+            let mut keys = vec![zinterstore.destination];
+            keys.extend(zinterstore.keys.iter());
+            keys
+        }
+        Command::ZPOPMAX(zpopmax) => vec![zpopmax.key],
+        Command::ZPOPMIN(zpopmin) => vec![zpopmin.key],
+        Command::ZREM(zrem) => vec![zrem.key],
+        Command::ZREMRANGEBYLEX(zrem) => vec![zrem.key],
+        Command::ZREMRANGEBYRANK(zrem) => vec![zrem.key],
+        Command::ZREMRANGEBYSCORE(zrem) => vec![zrem.key],
+        Command::ZUNIONSTORE(zunion) => {
+            let mut keys = vec![zunion.destination];
+            keys.extend(zunion.keys.iter());
+            keys
+        }
+        // 无法得知未解析命令的参数含义, 按照惯例将第一个参数视为key
+        Command::Other(raw_cmd) => raw_cmd.args.iter().take(1).map(|arg| &arg[..]).collect(),
+        Command::XACK(xack) => vec![xack.key],
+        Command::XADD(xadd) => vec![xadd.key],
+        Command::XCLAIM(xclaim) => vec![xclaim.key],
+        Command::XDEL(xdel) => vec![xdel.key],
+        Command::XGROUP(xgroup) => {
+            let mut keys = Vec::new();
+            if let Some(create) = &xgroup.create {
+                keys.push(create.key);
+            }
+            if let Some(set_id) = &xgroup.set_id {
+                keys.push(set_id.key);
+            }
+            if let Some(destroy) = &xgroup.destroy {
+                keys.push(destroy.key);
+            }
+            if let Some(del_consumer) = &xgroup.del_consumer {
+                keys.push(del_consumer.key);
+            }
+            keys
+        }
+        Command::XTRIM(xtrim) => vec![xtrim.key],
+        Command::EXEC
+        | Command::FLUSHALL(_)
+        | Command::FLUSHDB(_)
+        | Command::MULTI
+        | Command::PUBLISH(_)
+        | Command::SCRIPTFLUSH
+        | Command::SCRIPTLOAD(_)
+        | Command::SELECT(_)
+        | Command::SWAPDB(_) => Vec::new(),
+    }
+}
+
+// 获取命令的名称
+pub(crate) fn name<'a>(cmd: &'a Command) -> &'a str {
+    match cmd {
+        Command::APPEND(_) => "APPEND",
+        Command::BITFIELD(_) => "BITFIELD",
+        Command::BITOP(_) => "BITOP",
+        Command::BRPOPLPUSH(_) => "BRPOPLPUSH",
+        Command::DECR(_) => "DECR",
+        Command::DECRBY(_) => "DECRBY",
+        Command::DEL(_) => "DEL",
+        Command::EVAL(_) => "EVAL",
+        Command::EVALSHA(_) => "EVALSHA",
+        Command::EXPIRE(_) => "EXPIRE",
+        Command::EXPIREAT(_) => "EXPIREAT",
+        Command::GETSET(_) => "GETSET",
+        Command::HDEL(_) => "HDEL",
+        Command::HINCRBY(_) => "HINCRBY",
+        Command::HMSET(_) => "HMSET",
+        Command::HSET(_) => "HSET",
+        Command::HSETNX(_) => "HSETNX",
+        Command::INCR(_) => "INCR",
+        Command::INCRBY(_) => "INCRBY",
+        Command::LINSERT(_) => "LINSERT",
+        Command::LPOP(_) => "LPOP",
+        Command::LPUSH(_) => "LPUSH",
+        Command::LPUSHX(_) => "LPUSHX",
+        Command::LREM(_) => "LREM",
+        Command::LSET(_) => "LSET",
+        Command::LTRIM(_) => "LTRIM",
+        Command::MOVE(_) => "MOVE",
+        Command::MSET(_) => "MSET",
+        Command::MSETNX(_) => "MSETNX",
+        Command::PERSIST(_) => "PERSIST",
+        Command::PEXPIRE(_) => "PEXPIRE",
+        Command::PEXPIREAT(_) => "PEXPIREAT",
+        Command::PFADD(_) => "PFADD",
+        Command::PFCOUNT(_) => "PFCOUNT",
+        Command::PFMERGE(_) => "PFMERGE",
+        Command::PSETEX(_) => "PSETEX",
+        Command::RENAME(_) => "RENAME",
+        Command::RENAMENX(_) => "RENAMENX",
+        Command::RESTORE(_) => "RESTORE",
+        Command::RPOP(_) => "RPOP",
+        Command::RPOPLPUSH(_) => "RPOPLPUSH",
+        Command::RPUSH(_) => "RPUSH",
+        Command::RPUSHX(_) => "RPUSHX",
+        Command::SADD(_) => "SADD",
+        Command::SDIFFSTORE(_) => "SDIFFSTORE",
+        Command::SET(_) => "SET",
+        Command::SETBIT(_) => "SETBIT",
+        Command::SETEX(_) => "SETEX",
+        Command::SETNX(_) => "SETNX",
+        Command::SETRANGE(_) => "SETRANGE",
+        Command::SINTERSTORE(_) => "SINTERSTORE",
+        Command::SMOVE(_) => "SMOVE",
+        Command::SORT(_) => "SORT",
+        Command::SREM(_) => "SREM",
+        Command::SUNIONSTORE(_) => "SUNIONSTORE",
+        Command::UNLINK(_) => "UNLINK",
+        Command::ZADD(_) => "ZADD",
+        Command::ZINCRBY(_) => "ZINCRBY",
+        Command::ZINTERSTORE(_) => "ZINTERSTORE",
+        Command::ZPOPMAX(_) => "ZPOPMAX",
+        Command::ZPOPMIN(_) => "ZPOPMIN",
+        Command::ZREM(_) => "ZREM",
+        Command::ZREMRANGEBYLEX(_) => "ZREMRANGEBYLEX",
+        Command::ZREMRANGEBYRANK(_) => "ZREMRANGEBYRANK",
+        Command::ZREMRANGEBYSCORE(_) => "ZREMRANGEBYSCORE",
+        Command::ZUNIONSTORE(_) => "ZUNIONSTORE",
+        Command::XACK(_) => "XACK",
+        Command::XADD(_) => "XADD",
+        Command::XCLAIM(_) => "XCLAIM",
+        Command::XDEL(_) => "XDEL",
+        Command::XGROUP(_) => "XGROUP",
+        Command::XTRIM(_) => "XTRIM",
+        Command::EXEC => "EXEC",
+        Command::FLUSHALL(_) => "FLUSHALL",
+        Command::FLUSHDB(_) => "FLUSHDB",
+        Command::MULTI => "MULTI",
+        Command::PUBLISH(_) => "PUBLISH",
+        Command::SCRIPTFLUSH => "SCRIPT",
+        Command::SCRIPTLOAD(_) => "SCRIPT",
+        Command::SELECT(_) => "SELECT",
+        Command::SWAPDB(_) => "SWAPDB",
+        Command::Other(raw_cmd) => &raw_cmd.name,
+    }
+}
+
+// 涉及多个key且无法按key拆分的命令, 在Sharding/Cluster模式下这些key可能分布在不同的节点上
+pub(crate) fn is_multi_key(cmd: &Command) -> bool {
+    match cmd {
+        Command::BITOP(_)
+        | Command::BRPOPLPUSH(_)
+        | Command::EVAL(_)
+        | Command::EVALSHA(_)
+        | Command::PFMERGE(_)
+        | Command::RENAME(_)
+        | Command::RENAMENX(_)
+        | Command::RPOPLPUSH(_)
+        | Command::SDIFFSTORE(_)
+        | Command::SINTERSTORE(_)
+        | Command::SMOVE(_)
+        | Command::SORT(_)
+        | Command::SUNIONSTORE(_)
+        | Command::ZINTERSTORE(_)
+        | Command::ZUNIONSTORE(_) => true,
+        _ => false,
+    }
+}
+
+// 获取命令执行后内容会发生变化的key. 无法得知Lua脚本修改了哪些key, 视为所有key都会变化
+pub(crate) fn written_keys<'a>(cmd: &'a Command) -> Vec<&'a [u8]> {
+    match cmd {
+        Command::BITOP(bitop) => vec![bitop.dest_key],
+        Command::PFMERGE(pfmerge) => vec![pfmerge.dest_key],
+        Command::SDIFFSTORE(sdiffstore) => vec![sdiffstore.destination],
+        Command::SINTERSTORE(sinterstore) => vec![sinterstore.destination],
+        Command::SUNIONSTORE(sunion) => vec![sunion.destination],
+        Command::ZINTERSTORE(zinterstore) => vec![zinterstore.destination],
+        Command::ZUNIONSTORE(zunion) => vec![zunion.destination],
+        Command::SORT(sort) => sort.destination.into_iter().collect(),
+        _ => keys(cmd),
+    }
+}
+
+// 获取已转换的命令中用于选择节点的key, 与key无关的命令返回None. PUBLISH以channel作为key
+pub(crate) fn routing_key(cmd: &Cmd) -> Option<&[u8]> {
+    let args: Vec<&[u8]> = cmd
+        .args_iter()
+        .filter_map(|arg| match arg {
+            Arg::Simple(arg) => Some(arg),
+            Arg::Cursor => None,
+        })
+        .collect();
+    let index = match args.get(0)?.to_ascii_uppercase().as_slice() {
+        b"EXEC" | b"FLUSHALL" | b"FLUSHDB" | b"MULTI" | b"SCRIPT" | b"SELECT" | b"SWAPDB" => return None,
+        // EVAL script numkeys key [key ...]
+        b"EVAL" | b"EVALSHA" => match String::from_utf8_lossy(args.get(2)?).parse::<usize>() {
+            Ok(num_keys) if num_keys > 0 => 3,
+            _ => return None,
+        },
+        // BITOP operation destkey key [key ...], XGROUP CREATE key ...
+        b"BITOP" | b"XGROUP" => 2,
+        _ => 1,
+    };
+    args.get(index).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use redis_event::cmd::keys::{DEL, UNLINK};
+    use redis_event::cmd::strings::{KeyValue, MSET};
+
+    use super::*;
+
+    // 记录转换之后的命令
+    struct Recorder {
+        filter: Filter,
+        renamer: Renamer,
+        cmds: Vec<Vec<u8>>,
+    }
+
+    impl CommandConverter for Recorder {
+        fn execute(&mut self, cmd: Cmd, _: Option<&[u8]>) {
+            self.cmds.push(cmd.get_packed_command());
+        }
+
+        fn filter(&mut self) -> &mut Filter {
+            &mut self.filter
+        }
+
+        fn renamer(&self) -> &Renamer {
+            &self.renamer
+        }
+
+        fn swap_db(&mut self, _: i32) {}
+
+        fn chunk_size(&self) -> usize {
+            0
+        }
+
+        fn restore(&self) -> Option<&Restore> {
+            None
+        }
+
+        fn conflict(&mut self) -> Option<&mut Conflict> {
+            None
+        }
+
+        fn source(&mut self) -> Option<&mut SourceReader> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_split_by_filter() {
+        let mut recorder = Recorder {
+            filter: Filter::new(&["a:*".to_string()], &[], &[], &[], None, HashMap::new()),
+            renamer: Renamer::new(Some("p:".to_string()), None, None),
+            cmds: Vec::new(),
+        };
+        let (a1, b1, a2, b2) = (b"a:1".to_vec(), b"b:1".to_vec(), b"a:2".to_vec(), b"b:2".to_vec());
+        recorder.handle_aof(Command::DEL(DEL {
+            keys: vec![&a1, &b1, &a2],
+        }));
+        recorder.handle_aof(Command::MSET(MSET {
+            key_values: vec![KeyValue { key: &a1, value: b"1" }, KeyValue { key: &b1, value: b"2" }],
+        }));
+        // 没有满足过滤条件的key时不产生命令
+        recorder.handle_aof(Command::UNLINK(UNLINK { keys: vec![&b1, &b2] }));
+        recorder.handle_aof(Command::UNLINK(UNLINK { keys: vec![&b2, &a2] }));
+
+        let expected = vec![
+            redis::cmd("DEL").arg("p:a:1").arg("p:a:2").get_packed_command(),
+            redis::cmd("MSET").arg("p:a:1").arg("1").get_packed_command(),
+            redis::cmd("UNLINK").arg("p:a:2").get_packed_command(),
+        ];
+        assert_eq!(recorder.cmds, expected);
+    }
 }
@@ -0,0 +1,125 @@
+use std::thread;
+
+use log::{info, warn};
+
+use crate::verify::Targets;
+use crate::worker::{is_retryable, RetryConfig};
+use crate::{Abort, Opt};
+
+// --conflict: RDB阶段目的Redis中已存在相同key时的处理方式
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Policy {
+    // 先DEL再写入
+    Replace,
+    // 不写入该key
+    Skip,
+    // 终止复制
+    Error,
+    // 直接写入, list/set/zset/hash与已有的元素合并
+    Append,
+}
+
+impl Policy {
+    pub(crate) fn parse(name: &str) -> Option<Policy> {
+        match name.to_ascii_lowercase().as_str() {
+            "replace" => Some(Policy::Replace),
+            "skip" => Some(Policy::Skip),
+            "error" => Some(Policy::Error),
+            "append" => Some(Policy::Append),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct Conflict {
+    policy: Policy,
+    // skip及error需要在写入之前查询key是否已存在
+    targets: Option<Targets>,
+    // 查询失败时的重试
+    retry: RetryConfig,
+    abort: Abort,
+    skipped: u64,
+}
+
+impl Conflict {
+    pub(crate) fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    // 返回false时不写入该key. db与key均为目的Redis中的.
+    // error策略遇到已存在的key, 或查询重试之后仍失败时, 停止复制, 之后的key都不再写入
+    pub(crate) fn accept(&mut self, db: i64, key: &[u8]) -> bool {
+        if self.abort.is_aborted() {
+            return false;
+        }
+        let targets = match self.targets.as_mut() {
+            Some(targets) => targets,
+            None => return true,
+        };
+        let mut attempts = 0;
+        let exists = loop {
+            match targets.exists(db, key) {
+                Ok(exists) => break exists,
+                Err(err) if is_retryable(&err) && attempts < self.retry.max_retries => {
+                    attempts += 1;
+                    let backoff = self.retry.backoff(attempts);
+                    warn!(
+                        "查询目的Redis中的key失败: {}, {}ms后进行第{}次重试",
+                        err,
+                        backoff.as_millis(),
+                        attempts
+                    );
+                    thread::sleep(backoff);
+                }
+                Err(err) => {
+                    let reason = format!(
+                        "查询目的Redis中的key失败: {}, key: {}, db: {}",
+                        err,
+                        String::from_utf8_lossy(key),
+                        db
+                    );
+                    self.abort.abort(&reason);
+                    return false;
+                }
+            }
+        };
+        if !exists {
+            return true;
+        }
+        if self.policy == Policy::Skip {
+            self.skipped += 1;
+        } else {
+            let reason = format!("目的Redis中已存在key: {}, db: {}", String::from_utf8_lossy(key), db);
+            self.abort.abort(&reason);
+        }
+        false
+    }
+}
+
+impl Drop for Conflict {
+    fn drop(&mut self) {
+        if self.skipped > 0 {
+            info!("目的Redis中已存在而跳过的key: {}", self.skipped);
+        }
+    }
+}
+
+// append为默认行为, 无需额外处理
+pub(crate) fn new(opt: &Opt, abort: &Abort) -> Option<Conflict> {
+    let targets = match opt.conflict {
+        Policy::Append => return None,
+        Policy::Replace => None,
+        Policy::Skip | Policy::Error => Some(Targets::new(opt)),
+    };
+    Some(Conflict {
+        policy: opt.conflict,
+        targets,
+        retry: RetryConfig {
+            max_retries: opt.max_retries,
+            interval: opt.retry_interval,
+            dead_letter_dir: None,
+        },
+        abort: abort.clone(),
+        skipped: 0,
+    })
+}
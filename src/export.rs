@@ -0,0 +1,926 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use redis::Cmd;
+use redis_event::rdb::{ExpireType, Object};
+use redis_event::{Event, EventHandler};
+
+use crate::checkpoint::Flush;
+use crate::command::CommandConverter;
+use crate::conflict::Conflict;
+use crate::filter::Filter;
+use crate::rename::Renamer;
+use crate::restore;
+use crate::restore::Restore;
+use crate::source::{GroupState, SourceReader, StreamState};
+
+pub(crate) const RDB_VERSION: u16 = 9;
+// Redis 7.0的RDB版本, stream开始保存max-deleted-id、entries-added及消费组的entries-read
+const RDB_VERSION_STREAM_2: u16 = 10;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+// 与Redis的SCG_INVALID_ENTRIES_READ相同, 表示消费组的entries-read未知
+const INVALID_ENTRIES_READ: u64 = u64::MAX;
+// 与Redis的stream-node-max-entries默认值相同
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+lazy_static! {
+    static ref CRC64_TABLE: [u64; 256] = {
+        let mut table = [0u64; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u64;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0x95ac9329ac4bc9b5
+                } else {
+                    crc >> 1
+                };
+            }
+            *entry = crc;
+        }
+        table
+    };
+}
+
+// 与Redis相同的CRC64(Jones), 用于RDB文件末尾的校验和
+pub(crate) fn crc64(crc: u64, data: &[u8]) -> u64 {
+    data.iter().fold(crc, |crc, byte| {
+        CRC64_TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+// --output-dir: 不写入目的Redis, 而是将复制的数据保存为文件. RDB阶段的数据保存为RDB文件, AOF阶段的命令以RESP格式
+// 保存为AOF文件, AOF文件按照大小及时间切分. 与写入目的Redis时相同, 同样会进行过滤、db映射及重命名
+pub(crate) struct FileEventHandler {
+    dir: PathBuf,
+    tag: String,
+    rotate_size: u64,
+    rotate_interval: Option<Duration>,
+    flush_interval: Duration,
+    filter: Filter,
+    renamer: Renamer,
+    // 读取RDB对象中不包含的stream状态(PEL、消费者等)
+    source: SourceReader,
+    // RDB文件的版本, 与源Redis相同, 但不超过RDB_VERSION_STREAM_2
+    version: u16,
+    rdb: Option<RdbFile>,
+    aof: Option<AofFile>,
+    // 目的db, 每个新的AOF文件都以SELECT该db开头, 使其可以单独重放
+    db: Option<i64>,
+    transaction: bool,
+    flushed: Instant,
+}
+
+impl EventHandler for FileEventHandler {
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::RDB(Object::BOR) => self.begin_rdb(),
+            Event::RDB(Object::EOR) => {
+                if let Some(rdb) = self.rdb.take() {
+                    finish(rdb.finish());
+                }
+            }
+            Event::RDB(object) => self.handle_object(object),
+            Event::AOF(cmd) => {
+                if let Some(rdb) = self.rdb.take() {
+                    finish(rdb.finish());
+                }
+                self.handle_aof(cmd);
+                // 不在事务中切换, 避免MULTI与EXEC分别位于两个文件中
+                if !self.transaction && self.aof.as_ref().map_or(false, |aof| aof.full(self)) {
+                    finish(self.aof.take().unwrap().finish());
+                }
+            }
+        }
+        if self.flushed.elapsed() >= self.flush_interval {
+            self.flush();
+        }
+    }
+}
+
+impl Drop for FileEventHandler {
+    fn drop(&mut self) {
+        if let Some(rdb) = self.rdb.take() {
+            rdb.abort();
+        }
+        if let Some(aof) = self.aof.take() {
+            finish(aof.finish());
+        }
+    }
+}
+
+impl Flush for FileEventHandler {
+    fn flush(&mut self) {
+        if let Some(aof) = self.aof.as_mut() {
+            if let Err(err) = aof.writer.flush() {
+                panic!("写入文件{}失败: {}", aof.path.display(), err)
+            }
+        }
+        self.flushed = Instant::now();
+    }
+}
+
+impl CommandConverter for FileEventHandler {
+    fn execute(&mut self, cmd: Cmd, _: Option<&[u8]>) {
+        match cmd.args_iter().next() {
+            Some(redis::Arg::Simple(name)) if name.eq_ignore_ascii_case(b"MULTI") => self.transaction = true,
+            Some(redis::Arg::Simple(name)) if name.eq_ignore_ascii_case(b"EXEC") => self.transaction = false,
+            _ => {}
+        }
+        let aof = self.aof();
+        if let Err(err) = aof.write(&cmd.get_packed_command()) {
+            panic!("写入文件{}失败: {}", aof.path.display(), err)
+        }
+    }
+
+    fn filter(&mut self) -> &mut Filter {
+        &mut self.filter
+    }
+
+    fn renamer(&self) -> &Renamer {
+        &self.renamer
+    }
+
+    // RDB对象直接写入RDB文件, 不经过handle_rdb
+    fn chunk_size(&self) -> usize {
+        0
+    }
+
+    fn restore(&self) -> Option<&Restore> {
+        None
+    }
+
+    fn conflict(&mut self) -> Option<&mut Conflict> {
+        None
+    }
+
+    fn source(&mut self) -> Option<&mut SourceReader> {
+        Some(&mut self.source)
+    }
+
+    fn swap_db(&mut self, db: i32) {
+        self.db = Some(db as i64);
+        // 新的AOF文件会以SELECT开头
+        if self.aof.is_some() {
+            let mut cmd = redis::cmd("SELECT");
+            cmd.arg(db);
+            self.execute(cmd, None);
+        }
+    }
+}
+
+impl FileEventHandler {
+    fn begin_rdb(&mut self) {
+        // 重新进行了全量同步, 之后的命令写入新的AOF文件
+        if let Some(aof) = self.aof.take() {
+            finish(aof.finish());
+        }
+        if let Some(rdb) = self.rdb.take() {
+            rdb.abort();
+        }
+        self.rdb();
+    }
+
+    fn handle_object(&mut self, object: Object) {
+        let (key, db, expire) = match &object {
+            Object::String(kv) => (kv.key, kv.meta.db, &kv.meta.expire),
+            Object::List(list) => (list.key, list.meta.db, &list.meta.expire),
+            Object::Set(set) => (set.key, set.meta.db, &set.meta.expire),
+            Object::SortedSet(sorted_set) => (sorted_set.key, sorted_set.meta.db, &sorted_set.meta.expire),
+            Object::Hash(hash) => (hash.key, hash.meta.db, &hash.meta.expire),
+            Object::Stream(key, stream) => (key.as_slice(), stream.meta.db, &stream.meta.expire),
+            _ => return,
+        };
+        if !self.filter.accept_db(db) || !self.filter.accept(key) {
+            return;
+        }
+        // RDB对象中只有stream的消息及消费组的last id, 其余的状态从源Redis读取.
+        // 无法读取时(如离线文件作为源)不保存带有消费组的stream, 以免消费组丢失PEL及消费者
+        let state = match &object {
+            Object::Stream(_, stream) => {
+                let state = match self.source.stream(db, key) {
+                    Ok(state) => state,
+                    Err(err) => {
+                        warn!("读取stream的状态失败: {}, key: {}", err, String::from_utf8_lossy(key));
+                        None
+                    }
+                };
+                if state.is_none() && !stream.groups.is_empty() {
+                    error!(
+                        "无法读取stream的消费组状态(PEL、消费者), 未保存该key: {}",
+                        String::from_utf8_lossy(key)
+                    );
+                    return;
+                }
+                state
+            }
+            _ => None,
+        };
+        let key = self.renamer.rename(key);
+        let db = self.filter.map_db(db);
+        let expire = expire.as_ref().map(|(expire_type, ttl)| match expire_type {
+            ExpireType::Second => *ttl * 1000,
+            ExpireType::Millisecond => *ttl,
+        });
+        let rdb = self.rdb();
+        if let Err(err) = rdb.write_object(db, &key, expire, &object, state.as_ref()) {
+            panic!("写入文件{}失败: {}", rdb.tmp.display(), err)
+        }
+    }
+
+    fn rdb(&mut self) -> &mut RdbFile {
+        if self.rdb.is_none() {
+            let path = self.path("dump", "rdb");
+            match RdbFile::create(path.clone(), self.version) {
+                Ok(rdb) => self.rdb = Some(rdb),
+                Err(err) => panic!("创建文件{}失败: {}", path.display(), err),
+            }
+        }
+        self.rdb.as_mut().unwrap()
+    }
+
+    fn aof(&mut self) -> &mut AofFile {
+        if self.aof.is_none() {
+            let path = self.path("appendonly", "aof");
+            let result = AofFile::create(path.clone()).and_then(|mut aof| {
+                if let Some(db) = self.db {
+                    aof.write(&redis::cmd("SELECT").arg(db).get_packed_command())?;
+                }
+                Ok(aof)
+            });
+            match result {
+                Ok(aof) => self.aof = Some(aof),
+                Err(err) => panic!("创建文件{}失败: {}", path.display(), err),
+            }
+        }
+        self.aof.as_mut().unwrap()
+    }
+
+    fn path(&self, name: &str, extension: &str) -> PathBuf {
+        let time = chrono::Local::now().format("%Y%m%d%H%M%S%3f");
+        self.dir.join(format!("{}{}-{}.{}", name, self.tag, time, extension))
+    }
+}
+
+fn finish(result: io::Result<PathBuf>) {
+    match result {
+        Ok(path) => info!("文件已保存: {}", path.display()),
+        Err(err) => panic!("保存文件失败: {}", err),
+    }
+}
+
+// 写入过程中使用.tmp后缀, RDB接收完毕后才重命名
+struct RdbFile {
+    path: PathBuf,
+    tmp: PathBuf,
+    writer: BufWriter<File>,
+    crc: u64,
+    version: u16,
+    db: Option<i64>,
+}
+
+impl RdbFile {
+    fn create(path: PathBuf, version: u16) -> io::Result<RdbFile> {
+        let tmp = path.with_extension("rdb.tmp");
+        let mut rdb = RdbFile {
+            writer: BufWriter::new(File::create(&tmp)?),
+            path,
+            tmp,
+            crc: 0,
+            version,
+            db: None,
+        };
+        rdb.write(format!("REDIS{:04}", version).as_bytes())?;
+        Ok(rdb)
+    }
+
+    fn write_object(
+        &mut self, db: i64, key: &[u8], expire: Option<i64>, object: &Object, state: Option<&StreamState>,
+    ) -> io::Result<()> {
+        let (rdb_type, value) = match encode_value(object, self.version, state) {
+            Some(encoded) => encoded,
+            None => return Ok(()),
+        };
+        let mut buf = Vec::with_capacity(value.len() + key.len() + 24);
+        if self.db != Some(db) {
+            buf.push(RDB_OPCODE_SELECTDB);
+            write_len(&mut buf, db as u64);
+            self.db = Some(db);
+        }
+        if let Some(expire) = expire {
+            buf.push(RDB_OPCODE_EXPIRETIME_MS);
+            buf.extend_from_slice(&expire.to_le_bytes());
+        }
+        buf.push(rdb_type);
+        write_string(&mut buf, key);
+        self.write(&buf)?;
+        self.write(&value)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.crc = crc64(self.crc, data);
+        self.writer.write_all(data)
+    }
+
+    // 未完整接收的RDB保留.tmp后缀
+    fn abort(mut self) {
+        if let Err(err) = self.writer.flush() {
+            error!("写入文件{}失败: {}", self.tmp.display(), err);
+        }
+        warn!("RDB未完整接收, 已保存的部分: {}", self.tmp.display());
+    }
+
+    fn finish(mut self) -> io::Result<PathBuf> {
+        self.write(&[RDB_OPCODE_EOF])?;
+        let crc = self.crc;
+        self.writer.write_all(&crc.to_le_bytes())?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        fs::rename(&self.tmp, &self.path)?;
+        Ok(self.path)
+    }
+}
+
+// 对象在RDB中的类型及序列化之后的值(不含key), RDB文件与DUMP使用相同的格式.
+// RDB版本低于8(Redis 4.0)时, sorted set的score以字符串保存. state为源Redis中stream的当前状态
+pub(crate) fn encode_value(object: &Object, version: u16, state: Option<&StreamState>) -> Option<(u8, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let rdb_type = match object {
+        Object::String(kv) => {
+            write_string(&mut buf, &kv.value[..]);
+            RDB_TYPE_STRING
+        }
+        Object::List(list) => {
+            write_len(&mut buf, list.values.len() as u64);
+            for value in list.values.iter() {
+                write_string(&mut buf, &value[..]);
+            }
+            RDB_TYPE_LIST
+        }
+        Object::Set(set) => {
+            write_len(&mut buf, set.members.len() as u64);
+            for member in set.members.iter() {
+                write_string(&mut buf, &member[..]);
+            }
+            RDB_TYPE_SET
+        }
+        Object::SortedSet(sorted_set) => {
+            write_len(&mut buf, sorted_set.items.len() as u64);
+            for item in sorted_set.items.iter() {
+                write_string(&mut buf, &item.member[..]);
+                if version >= 8 {
+                    buf.extend_from_slice(&item.score.to_le_bytes());
+                } else {
+                    write_double(&mut buf, item.score);
+                }
+            }
+            if version >= 8 {
+                RDB_TYPE_ZSET_2
+            } else {
+                RDB_TYPE_ZSET
+            }
+        }
+        Object::Hash(hash) => {
+            write_len(&mut buf, hash.fields.len() as u64);
+            for field in hash.fields.iter() {
+                write_string(&mut buf, &field.name[..]);
+                write_string(&mut buf, &field.value[..]);
+            }
+            RDB_TYPE_HASH
+        }
+        Object::Stream(_, stream) => {
+            let mut entries = Vec::new();
+            for (id, entry) in stream.entries.iter() {
+                let fields: Vec<(Vec<u8>, Vec<u8>)> = entry
+                    .fields
+                    .iter()
+                    .map(|(field, value)| (field.to_vec(), value.to_vec()))
+                    .collect();
+                entries.push((stream_id(&id.to_string()), fields));
+            }
+            entries.sort_by_key(|(id, _)| *id);
+            let meta = StreamMeta::new(&entries, state.filter(|state| state.matches(stream)));
+            let now = chrono::Local::now().timestamp_millis();
+            let mut groups = Vec::new();
+            for group in stream.groups.iter() {
+                // 消费组在RDB生成之后已被删除时, 之后的AOF命令会将其删除
+                let live = state.and_then(|state| state.groups.iter().find(|live| live.name[..] == group.name[..]));
+                groups.push(StreamGroup::new(
+                    &group.name,
+                    stream_id(&group.last_id.to_string()),
+                    live,
+                    now,
+                ));
+            }
+            write_stream(&mut buf, &entries, &meta, &groups, version);
+            if version >= RDB_VERSION_STREAM_2 {
+                RDB_TYPE_STREAM_LISTPACKS_2
+            } else {
+                RDB_TYPE_STREAM_LISTPACKS
+            }
+        }
+        _ => return None,
+    };
+    Some((rdb_type, buf))
+}
+
+// stream的last id, 以及RDB_VERSION_STREAM_2开始保存的第一个消息的ID、max-deleted-id与entries-added
+struct StreamMeta {
+    last_id: (u64, u64),
+    first_id: (u64, u64),
+    max_deleted_id: (u64, u64),
+    entries_added: u64,
+}
+
+impl StreamMeta {
+    // RDB生成之后stream有变化时, 之后的AOF命令会以显式的ID追加消息, 因此只有与源Redis一致时才使用其last id,
+    // 否则以最后一个消息的ID作为last id
+    fn new(entries: &[((u64, u64), Vec<(Vec<u8>, Vec<u8>)>)], state: Option<&StreamState>) -> StreamMeta {
+        let first_id = entries.first().map_or((0, 0), |(id, _)| *id);
+        let last_id = entries.last().map_or((0, 0), |(id, _)| *id);
+        match state {
+            Some(state) => StreamMeta {
+                last_id: stream_id(&state.last_generated_id),
+                first_id,
+                max_deleted_id: state.max_deleted_id.as_deref().map_or((0, 0), stream_id),
+                entries_added: state.entries_added.unwrap_or(entries.len() as u64),
+            },
+            None => StreamMeta {
+                last_id,
+                first_id,
+                max_deleted_id: (0, 0),
+                entries_added: entries.len() as u64,
+            },
+        }
+    }
+}
+
+// 消费组的last id、entries-read、PEL及消费者
+struct StreamGroup {
+    name: Vec<u8>,
+    last_id: (u64, u64),
+    entries_read: u64,
+    // 消息ID, 消费者, 投递时间(毫秒时间戳), 投递次数
+    pending: Vec<((u64, u64), Vec<u8>, i64, u64)>,
+    // 消费者及其最近活跃的时间(毫秒时间戳)
+    consumers: Vec<(Vec<u8>, i64)>,
+}
+
+impl StreamGroup {
+    // live为源Redis中该消费组的当前状态, 为None时只有名称及last id
+    fn new(name: &[u8], last_id: (u64, u64), live: Option<&GroupState>, now: i64) -> StreamGroup {
+        let mut group = StreamGroup {
+            name: name.to_vec(),
+            last_id,
+            entries_read: INVALID_ENTRIES_READ,
+            pending: Vec::new(),
+            consumers: Vec::new(),
+        };
+        let live = match live {
+            Some(live) => live,
+            None => return group,
+        };
+        if let Some(entries_read) = live.entries_read {
+            if stream_id(&live.last_delivered_id) == last_id {
+                group.entries_read = entries_read;
+            }
+        }
+        for (id, consumer, idle, count) in &live.pending {
+            group
+                .pending
+                .push((stream_id(id), consumer.clone(), now - idle, *count as u64));
+            if !group.consumers.iter().any(|(name, _)| name == consumer) {
+                group.consumers.push((consumer.clone(), now));
+            }
+        }
+        for consumer in &live.idle_consumers {
+            group.consumers.push((consumer.clone(), now));
+        }
+        group
+    }
+}
+
+// 每STREAM_NODE_MAX_ENTRIES个消息保存为一个listpack, 以其中第一个消息的ID作为master ID
+fn write_stream(
+    buf: &mut Vec<u8>, entries: &[((u64, u64), Vec<(Vec<u8>, Vec<u8>)>)], meta: &StreamMeta, groups: &[StreamGroup],
+    version: u16,
+) {
+    let nodes: Vec<_> = entries.chunks(STREAM_NODE_MAX_ENTRIES).collect();
+    write_len(buf, nodes.len() as u64);
+    for node in nodes {
+        let master = node[0].0;
+        let mut master_key = master.0.to_be_bytes().to_vec();
+        master_key.extend_from_slice(&master.1.to_be_bytes());
+        write_string(buf, &master_key);
+        write_string(buf, &stream_listpack(master, node));
+    }
+    write_len(buf, entries.len() as u64);
+    write_len(buf, meta.last_id.0);
+    write_len(buf, meta.last_id.1);
+    if version >= RDB_VERSION_STREAM_2 {
+        write_len(buf, meta.first_id.0);
+        write_len(buf, meta.first_id.1);
+        write_len(buf, meta.max_deleted_id.0);
+        write_len(buf, meta.max_deleted_id.1);
+        write_len(buf, meta.entries_added);
+    }
+    write_len(buf, groups.len() as u64);
+    for group in groups {
+        write_string(buf, &group.name);
+        write_len(buf, group.last_id.0);
+        write_len(buf, group.last_id.1);
+        if version >= RDB_VERSION_STREAM_2 {
+            write_len(buf, group.entries_read);
+        }
+        // 消费组的PEL: 消息ID(16字节, 大端), 投递时间, 投递次数
+        write_len(buf, group.pending.len() as u64);
+        for (id, _, delivery_time, count) in &group.pending {
+            write_raw_id(buf, *id);
+            buf.extend_from_slice(&delivery_time.to_le_bytes());
+            write_len(buf, *count);
+        }
+        // 消费者: 名称, 活跃时间, 及其PEL中的消息ID
+        write_len(buf, group.consumers.len() as u64);
+        for (consumer, seen_time) in &group.consumers {
+            write_string(buf, consumer);
+            buf.extend_from_slice(&seen_time.to_le_bytes());
+            let ids: Vec<_> = group
+                .pending
+                .iter()
+                .filter(|(_, owner, _, _)| owner == consumer)
+                .map(|(id, _, _, _)| *id)
+                .collect();
+            write_len(buf, ids.len() as u64);
+            for id in ids {
+                write_raw_id(buf, id);
+            }
+        }
+    }
+}
+
+fn write_raw_id(buf: &mut Vec<u8>, id: (u64, u64)) {
+    buf.extend_from_slice(&id.0.to_be_bytes());
+    buf.extend_from_slice(&id.1.to_be_bytes());
+}
+
+fn write_string(buf: &mut Vec<u8>, data: &[u8]) {
+    write_len(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+fn write_len(buf: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        buf.push(len as u8);
+    } else if len < 1 << 14 {
+        buf.extend_from_slice(&[(len >> 8) as u8 | 0x40, len as u8]);
+    } else if len <= u32::MAX as u64 {
+        buf.push(0x80);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        buf.push(0x81);
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+// 与Redis的rdbSaveDoubleValue相同: 253表示NaN, 254/255表示正/负无穷, 否则为长度及字符串形式的值
+fn write_double(buf: &mut Vec<u8>, value: f64) {
+    if value.is_nan() {
+        buf.push(253);
+    } else if value.is_infinite() {
+        buf.push(if value > 0.0 { 254 } else { 255 });
+    } else {
+        let value = format!("{:e}", value);
+        buf.push(value.len() as u8);
+        buf.extend_from_slice(value.as_bytes());
+    }
+}
+
+// 形如"1628217470123-0"的消息ID
+pub(crate) fn stream_id(id: &str) -> (u64, u64) {
+    let mut parts = id.splitn(2, '-');
+    let ms = parts.next().and_then(|ms| ms.parse().ok()).unwrap_or(0);
+    let seq = parts.next().and_then(|seq| seq.parse().ok()).unwrap_or(0);
+    (ms, seq)
+}
+
+// 与Redis的t_stream.c相同的格式: 先是master entry(count, deleted, master fields, 0),
+// 随后每个消息为flags, ms-diff, seq-diff, num-fields, field-1, value-1, ..., lp-count
+fn stream_listpack(master: (u64, u64), entries: &[((u64, u64), Vec<(Vec<u8>, Vec<u8>)>)]) -> Vec<u8> {
+    let mut lp = Listpack::default();
+    lp.push_int(entries.len() as i64);
+    lp.push_int(0);
+    lp.push_int(0);
+    lp.push_int(0);
+    for (id, fields) in entries {
+        lp.push_int(0);
+        lp.push_int(id.0.wrapping_sub(master.0) as i64);
+        lp.push_int(id.1.wrapping_sub(master.1) as i64);
+        lp.push_int(fields.len() as i64);
+        for (field, value) in fields {
+            lp.push_string(field);
+            lp.push_string(value);
+        }
+        lp.push_int(fields.len() as i64 * 2 + 4);
+    }
+    lp.finish()
+}
+
+#[derive(Default)]
+struct Listpack {
+    entries: Vec<u8>,
+    count: usize,
+}
+
+impl Listpack {
+    fn push_int(&mut self, value: i64) {
+        let mut entry = Vec::with_capacity(9);
+        if (0..=127).contains(&value) {
+            entry.push(value as u8);
+        } else if (-4096..=4095).contains(&value) {
+            let value = value as u64 & 0x1FFF;
+            entry.push((value >> 8) as u8 | 0xC0);
+            entry.push(value as u8);
+        } else if (i16::MIN as i64..=i16::MAX as i64).contains(&value) {
+            entry.push(0xF1);
+            entry.extend_from_slice(&(value as i16).to_le_bytes());
+        } else if (-(1 << 23)..1 << 23).contains(&value) {
+            entry.push(0xF2);
+            entry.extend_from_slice(&(value as i32).to_le_bytes()[..3]);
+        } else if (i32::MIN as i64..=i32::MAX as i64).contains(&value) {
+            entry.push(0xF3);
+            entry.extend_from_slice(&(value as i32).to_le_bytes());
+        } else {
+            entry.push(0xF4);
+            entry.extend_from_slice(&value.to_le_bytes());
+        }
+        self.push(entry);
+    }
+
+    fn push_string(&mut self, data: &[u8]) {
+        let mut entry = Vec::with_capacity(data.len() + 5);
+        let len = data.len();
+        if len < 64 {
+            entry.push(len as u8 | 0x80);
+        } else if len < 4096 {
+            entry.push((len >> 8) as u8 | 0xE0);
+            entry.push(len as u8);
+        } else {
+            entry.push(0xF0);
+            entry.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+        entry.extend_from_slice(data);
+        self.push(entry);
+    }
+
+    // 每个元素之后是该元素的长度(backlen), 用于反向遍历
+    fn push(&mut self, entry: Vec<u8>) {
+        let mut len = entry.len() as u64;
+        self.entries.extend_from_slice(&entry);
+        // 每个字节保存7bit, 高位在前, 除第一个字节外最高bit均为1
+        let mut backlen = Vec::with_capacity(5);
+        loop {
+            backlen.push((len & 127) as u8);
+            len >>= 7;
+            if len == 0 {
+                break;
+            }
+        }
+        let last = backlen.len() - 1;
+        backlen[..last].iter_mut().for_each(|byte| *byte |= 128);
+        backlen.reverse();
+        self.entries.extend_from_slice(&backlen);
+        self.count += 1;
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let total = 6 + self.entries.len() + 1;
+        let mut lp = Vec::with_capacity(total);
+        lp.extend_from_slice(&(total as u32).to_le_bytes());
+        lp.extend_from_slice(&(self.count.min(u16::MAX as usize) as u16).to_le_bytes());
+        lp.extend_from_slice(&self.entries);
+        lp.push(0xFF);
+        lp
+    }
+}
+
+struct AofFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    created: Instant,
+}
+
+impl AofFile {
+    fn create(path: PathBuf) -> io::Result<AofFile> {
+        Ok(AofFile {
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            size: 0,
+            created: Instant::now(),
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.size += data.len() as u64;
+        self.writer.write_all(data)
+    }
+
+    // 达到了切换的大小或时间
+    fn full(&self, handler: &FileEventHandler) -> bool {
+        (handler.rotate_size > 0 && self.size >= handler.rotate_size)
+            || handler
+                .rotate_interval
+                .map_or(false, |interval| self.created.elapsed() >= interval)
+    }
+
+    fn finish(mut self) -> io::Result<PathBuf> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(self.path)
+    }
+}
+
+// source为源Redis的url, 离线文件作为源时为None
+pub(crate) fn new(
+    dir: &str, tag: &str, source: Option<&str>, rotate_size: u64, rotate_interval: u64, flush_interval: u64,
+    filter: Filter, renamer: Renamer,
+) -> FileEventHandler {
+    if let Err(err) = fs::create_dir_all(dir) {
+        panic!("创建目录{}失败: {}", dir, err)
+    }
+    let version = match source.map(restore::redis_version) {
+        Some(Ok(redis_version)) => restore::rdb_version(&redis_version).min(RDB_VERSION_STREAM_2),
+        Some(Err(err)) => {
+            warn!("获取源Redis的版本失败, RDB文件使用版本{}: {}", RDB_VERSION, err);
+            RDB_VERSION
+        }
+        None => RDB_VERSION,
+    };
+    FileEventHandler {
+        dir: PathBuf::from(dir),
+        tag: tag.to_string(),
+        rotate_size,
+        rotate_interval: if rotate_interval > 0 {
+            Some(Duration::from_secs(rotate_interval))
+        } else {
+            None
+        },
+        flush_interval: Duration::from_millis(flush_interval),
+        filter,
+        renamer,
+        source: SourceReader::new(source),
+        version,
+        rdb: None,
+        aof: None,
+        db: None,
+        transaction: false,
+        flushed: Instant::now(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn len(len: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_len(&mut buf, len);
+        buf
+    }
+
+    // 单个整数元素及其backlen
+    fn int_entry(value: i64) -> Vec<u8> {
+        let mut lp = Listpack::default();
+        lp.push_int(value);
+        lp.entries
+    }
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(crc64(crc64(0, b"12345"), b"6789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn test_write_len() {
+        assert_eq!(len(0), vec![0x00]);
+        assert_eq!(len(63), vec![0x3F]);
+        assert_eq!(len(64), vec![0x40, 0x40]);
+        assert_eq!(len(16383), vec![0x7F, 0xFF]);
+        assert_eq!(len(16384), vec![0x80, 0x00, 0x00, 0x40, 0x00]);
+        assert_eq!(len(u32::MAX as u64), vec![0x80, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(
+            len(u32::MAX as u64 + 1),
+            vec![0x81, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_listpack_int() {
+        // 7bit
+        assert_eq!(int_entry(0), vec![0x00, 1]);
+        assert_eq!(int_entry(127), vec![0x7F, 1]);
+        // 13bit
+        assert_eq!(int_entry(128), vec![0xC0, 0x80, 2]);
+        assert_eq!(int_entry(-1), vec![0xDF, 0xFF, 2]);
+        assert_eq!(int_entry(4095), vec![0xCF, 0xFF, 2]);
+        assert_eq!(int_entry(-4096), vec![0xD0, 0x00, 2]);
+        // 16bit
+        assert_eq!(int_entry(4096), vec![0xF1, 0x00, 0x10, 3]);
+        assert_eq!(int_entry(-4097), vec![0xF1, 0xFF, 0xEF, 3]);
+        assert_eq!(int_entry(32767), vec![0xF1, 0xFF, 0x7F, 3]);
+        // 24bit
+        assert_eq!(int_entry(32768), vec![0xF2, 0x00, 0x80, 0x00, 4]);
+        assert_eq!(int_entry(8388607), vec![0xF2, 0xFF, 0xFF, 0x7F, 4]);
+        assert_eq!(int_entry(-8388608), vec![0xF2, 0x00, 0x00, 0x80, 4]);
+        // 32bit
+        assert_eq!(int_entry(8388608), vec![0xF3, 0x00, 0x00, 0x80, 0x00, 5]);
+        assert_eq!(int_entry(i32::MAX as i64), vec![0xF3, 0xFF, 0xFF, 0xFF, 0x7F, 5]);
+        // 64bit
+        assert_eq!(
+            int_entry(i32::MAX as i64 + 1),
+            vec![0xF4, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 9]
+        );
+        assert_eq!(
+            int_entry(i64::MIN),
+            vec![0xF4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 9]
+        );
+    }
+
+    #[test]
+    fn test_listpack_backlen() {
+        // 2字节的header + 130字节, 共132字节
+        let mut lp = Listpack::default();
+        lp.push_string(&[b'a'; 130]);
+        assert_eq!(&lp.entries[..2], &[0xE0, 130]);
+        assert_eq!(lp.entries.len(), 134);
+        assert_eq!(&lp.entries[132..], &[0x01, 0x84]);
+
+        // 5字节的header + 16384字节, 共16389字节
+        let mut lp = Listpack::default();
+        lp.push_string(&[b'a'; 16384]);
+        assert_eq!(&lp.entries[..5], &[0xF0, 0x00, 0x40, 0x00, 0x00]);
+        assert_eq!(lp.entries.len(), 16392);
+        assert_eq!(&lp.entries[16389..], &[0x01, 0x80, 0x85]);
+
+        let lp = lp.finish();
+        assert_eq!(&lp[..6], &[0x0F, 0x40, 0x00, 0x00, 0x01, 0x00]);
+        assert_eq!(lp.last(), Some(&0xFF));
+    }
+
+    #[test]
+    fn test_write_stream_groups() {
+        let meta = StreamMeta {
+            last_id: (5, 1),
+            first_id: (0, 0),
+            max_deleted_id: (3, 0),
+            entries_added: 7,
+        };
+        let groups = vec![StreamGroup {
+            name: b"g".to_vec(),
+            last_id: (5, 1),
+            entries_read: 7,
+            pending: vec![((4, 0), b"c".to_vec(), 1000, 2)],
+            consumers: vec![(b"c".to_vec(), 2000)],
+        }];
+        let id = [0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        let mut expected = vec![0, 0, 5, 1, 1, 1, b'g', 5, 1, 1];
+        expected.extend_from_slice(&id);
+        expected.extend_from_slice(&1000i64.to_le_bytes());
+        expected.extend_from_slice(&[2, 1, 1, b'c']);
+        expected.extend_from_slice(&2000i64.to_le_bytes());
+        expected.push(1);
+        expected.extend_from_slice(&id);
+        let mut buf = Vec::new();
+        write_stream(&mut buf, &[], &meta, &groups, RDB_VERSION);
+        assert_eq!(buf, expected);
+
+        // 版本10开始保存first id、max-deleted-id、entries-added及entries-read
+        let mut expected = vec![0, 0, 5, 1, 0, 0, 3, 0, 7, 1, 1, b'g', 5, 1, 7, 1];
+        expected.extend_from_slice(&id);
+        expected.extend_from_slice(&1000i64.to_le_bytes());
+        expected.extend_from_slice(&[2, 1, 1, b'c']);
+        expected.extend_from_slice(&2000i64.to_le_bytes());
+        expected.push(1);
+        expected.extend_from_slice(&id);
+        let mut buf = Vec::new();
+        write_stream(&mut buf, &[], &meta, &groups, RDB_VERSION_STREAM_2);
+        assert_eq!(buf, expected);
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+
+use redis::ToRedisArgs;
+use regex::bytes::Regex;
+
+// 按照key名称过滤需要复制的数据: 若指定了include, 则key需至少匹配其中一个; 若匹配了任意一个exclude, 则不复制.
+// 同时负责db的过滤与映射, 并记录源Redis当前所在的db
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Filter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    dbs: Option<Vec<i64>>,
+    db_mapping: HashMap<i64, i64>,
+    db: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+enum Pattern {
+    Glob(Vec<u8>),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn is_match(&self, key: &[u8]) -> bool {
+        match self {
+            Pattern::Glob(pattern) => glob_match(pattern, key),
+            Pattern::Regex(regex) => regex.is_match(key),
+        }
+    }
+}
+
+impl Filter {
+    pub(crate) fn new(
+        include: &[String], exclude: &[String], include_regex: &[String], exclude_regex: &[String],
+        dbs: Option<Vec<i64>>, db_mapping: HashMap<i64, i64>,
+    ) -> Filter {
+        Filter {
+            include: to_patterns(include, include_regex),
+            exclude: to_patterns(exclude, exclude_regex),
+            dbs,
+            db_mapping,
+            db: None,
+        }
+    }
+
+    pub(crate) fn accept_db(&self, db: i64) -> bool {
+        match &self.dbs {
+            None => true,
+            Some(dbs) => dbs.contains(&db),
+        }
+    }
+
+    // 源db在目的Redis中所对应的db
+    pub(crate) fn map_db(&self, db: i64) -> i64 {
+        *self.db_mapping.get(&db).unwrap_or(&db)
+    }
+
+    // 目的db是否对应于某个需要复制的源db
+    pub(crate) fn accept_target_db(&self, db: i64) -> bool {
+        if self
+            .db_mapping
+            .iter()
+            .any(|(source, target)| *target == db && self.accept_db(*source))
+        {
+            return true;
+        }
+        !self.db_mapping.contains_key(&db) && self.accept_db(db)
+    }
+
+    // 源Redis当前所在的db, 若还未接收到SELECT则默认为0
+    pub(crate) fn current_db(&self) -> i64 {
+        self.db.unwrap_or(0)
+    }
+
+    // 记录源Redis切换后的db, 若db发生了变化则返回true
+    pub(crate) fn switch_db(&mut self, db: i64) -> bool {
+        if self.db == Some(db) {
+            false
+        } else {
+            self.db = Some(db);
+            true
+        }
+    }
+
+    // 源Redis执行FLUSHALL时, 若只复制了部分db, 则只能在目的Redis中对这些db分别执行FLUSHDB
+    pub(crate) fn flushall_dbs(&self) -> Option<Vec<i64>> {
+        self.dbs
+            .as_ref()
+            .map(|dbs| dbs.iter().map(|db| self.map_db(*db)).collect())
+    }
+
+    // 只有两个db都需要复制时, 才能在目的Redis中执行SWAPDB
+    pub(crate) fn map_swapdb(&self, index1: i64, index2: i64) -> Option<(i64, i64)> {
+        if self.accept_db(index1) && self.accept_db(index2) {
+            Some((self.map_db(index1), self.map_db(index2)))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn accept(&self, key: &[u8]) -> bool {
+        if !self.include.is_empty() && !self.include.iter().any(|pattern| pattern.is_match(key)) {
+            return false;
+        }
+        !self.exclude.iter().any(|pattern| pattern.is_match(key))
+    }
+}
+
+pub(crate) fn parse_db<T: ToRedisArgs>(index: T) -> i64 {
+    let index = index.to_redis_args().concat();
+    match String::from_utf8_lossy(&index).parse::<i64>() {
+        Ok(db) => db,
+        Err(_) => panic!("无效的db: {}", String::from_utf8_lossy(&index)),
+    }
+}
+
+fn to_patterns(globs: &[String], regexes: &[String]) -> Vec<Pattern> {
+    let mut patterns: Vec<Pattern> = globs
+        .iter()
+        .map(|glob| Pattern::Glob(glob.as_bytes().to_vec()))
+        .collect();
+    for regex in regexes {
+        match Regex::new(regex) {
+            Ok(regex) => patterns.push(Pattern::Regex(regex)),
+            Err(err) => panic!("无效的正则表达式 {}: {}", regex, err),
+        }
+    }
+    patterns
+}
+
+// 与Redis的KEYS/SCAN MATCH所使用的glob规则一致, 移植自redis/src/util.c中的stringmatchlen
+pub(crate) fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    while p < pattern.len() && s < string.len() {
+        match pattern[p] {
+            b'*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == b'*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                while s < string.len() {
+                    if glob_match(&pattern[p + 1..], &string[s..]) {
+                        return true;
+                    }
+                    s += 1;
+                }
+                return false;
+            }
+            b'?' => {
+                s += 1;
+            }
+            b'[' => {
+                p += 1;
+                let not = p < pattern.len() && pattern[p] == b'^';
+                if not {
+                    p += 1;
+                }
+                let mut matched = false;
+                loop {
+                    if p >= pattern.len() {
+                        // 没有闭合的']', 回退一位, 以便下方的p += 1之后正好结束
+                        p -= 1;
+                        break;
+                    }
+                    if pattern[p] == b'\\' && pattern.len() - p >= 2 {
+                        p += 1;
+                        if pattern[p] == string[s] {
+                            matched = true;
+                        }
+                    } else if pattern[p] == b']' {
+                        break;
+                    } else if pattern.len() - p >= 3 && pattern[p + 1] == b'-' {
+                        let (mut start, mut end) = (pattern[p], pattern[p + 2]);
+                        if start > end {
+                            std::mem::swap(&mut start, &mut end);
+                        }
+                        p += 2;
+                        if (start..=end).contains(&string[s]) {
+                            matched = true;
+                        }
+                    } else if pattern[p] == string[s] {
+                        matched = true;
+                    }
+                    p += 1;
+                }
+                if not {
+                    matched = !matched;
+                }
+                if !matched {
+                    return false;
+                }
+                s += 1;
+            }
+            b'\\' => {
+                if pattern.len() - p >= 2 {
+                    p += 1;
+                }
+                if pattern[p] != string[s] {
+                    return false;
+                }
+                s += 1;
+            }
+            c => {
+                if c != string[s] {
+                    return false;
+                }
+                s += 1;
+            }
+        }
+        p += 1;
+        if s == string.len() {
+            while p < pattern.len() && pattern[p] == b'*' {
+                p += 1;
+            }
+            break;
+        }
+    }
+    p == pattern.len() && s == string.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> Filter {
+        let to_strings = |patterns: &[&str]| patterns.iter().map(|pattern| pattern.to_string()).collect::<Vec<_>>();
+        Filter::new(
+            &to_strings(include),
+            &to_strings(exclude),
+            &[],
+            &[],
+            None,
+            HashMap::new(),
+        )
+    }
+
+    #[test]
+    fn test_glob_escape() {
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"\\?", b"?"));
+        assert!(!glob_match(b"\\?", b"x"));
+        assert!(glob_match(b"a[\\]]b", b"a]b"));
+        // 末尾的'\'匹配其自身
+        assert!(glob_match(b"a\\", b"a\\"));
+    }
+
+    #[test]
+    fn test_glob_class() {
+        assert!(glob_match(b"h[a-z]llo", b"hello"));
+        assert!(!glob_match(b"h[a-z]llo", b"hEllo"));
+        // 起止颠倒的范围与正序相同
+        assert!(glob_match(b"h[z-a]llo", b"hello"));
+        assert!(!glob_match(b"h[z-a]llo", b"h5llo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[^a-c]llo", b"hello"));
+        // 没有闭合的'['时, 之后的字符均作为候选
+        assert!(glob_match(b"h[ab", b"ha"));
+        assert!(glob_match(b"h[ab", b"hb"));
+        assert!(!glob_match(b"h[ab", b"hc"));
+        assert!(!glob_match(b"h[ab", b"hab"));
+    }
+
+    #[test]
+    fn test_glob_multibyte() {
+        // 与Redis相同, 按字节匹配, "é"为2个字节
+        assert!(!glob_match("?".as_bytes(), "é".as_bytes()));
+        assert!(glob_match("??".as_bytes(), "é".as_bytes()));
+        assert!(!glob_match("user:?".as_bytes(), "user:é".as_bytes()));
+        assert!(glob_match("user:*".as_bytes(), "user:é".as_bytes()));
+    }
+
+    #[test]
+    fn test_accept() {
+        let include_exclude = filter(&["user:*"], &["user:admin*"]);
+        assert!(include_exclude.accept(b"user:1"));
+        // exclude优先于include
+        assert!(!include_exclude.accept(b"user:admin"));
+        assert!(!include_exclude.accept(b"order:1"));
+
+        let exclude_only = filter(&[], &["tmp:*"]);
+        assert!(exclude_only.accept(b"user:1"));
+        assert!(!exclude_only.accept(b"tmp:1"));
+
+        // 满足任意一个include即可
+        let includes = filter(&["user:*", "order:*"], &[]);
+        assert!(includes.accept(b"order:1"));
+        assert!(!includes.accept(b"cart:1"));
+
+        assert!(Filter::default().accept(b"anything"));
+    }
+}
@@ -1,48 +1,40 @@
-use std::sync::mpsc::Sender;
-use std::sync::{mpsc, Arc};
+use std::collections::BTreeMap;
+use std::sync::Arc;
 
-use redis_event::rdb::Object;
 use redis_event::Event::{AOF, RDB};
 use redis_event::{Event, EventHandler};
 
+use crate::checkpoint::{Flush, Marker};
 use crate::command::CommandConverter;
+use crate::conflict::Conflict;
+use crate::filter::Filter;
+use crate::rename::Renamer;
+use crate::restore::Restore;
+use crate::source::SourceReader;
 use crate::worker;
-use crate::worker::{Message, Worker};
+use crate::worker::{Message, MessageSender, RetryConfig, Workers};
+use crate::Abort;
 use redis::Cmd;
 use scheduled_thread_pool::ScheduledThreadPool;
 use std::sync::atomic::AtomicBool;
 
 pub(crate) struct EventHandlerImpl {
-    worker: Worker,
-    sender: Sender<Message>,
+    sender: MessageSender,
+    // 之后的命令所属的db, 为None时使用目的Redis地址中的db
+    db: Option<i64>,
+    filter: Filter,
+    renamer: Renamer,
+    chunk_size: usize,
+    restore: Option<Restore>,
+    conflict: Option<Conflict>,
+    source: SourceReader,
+    abort: Abort,
 }
 
 impl EventHandler for EventHandlerImpl {
     fn handle(&mut self, event: Event) {
         match event {
-            RDB(rdb) => match rdb {
-                Object::Stream(key, stream) => {
-                    for (id, entry) in stream.entries {
-                        let mut cmd = redis::cmd("XADD");
-                        cmd.arg(key.as_slice());
-                        cmd.arg(id.to_string());
-                        for (field, value) in entry.fields {
-                            cmd.arg(field).arg(value);
-                        }
-                        self.execute(cmd, None);
-                    }
-                    for group in stream.groups {
-                        let mut cmd = redis::cmd("XGROUP");
-                        cmd.arg("CREATE")
-                            .arg(key.as_slice())
-                            .arg(group.name)
-                            .arg(group.last_id.to_string());
-
-                        self.execute(cmd, None);
-                    }
-                }
-                _ => self.handle_rdb(rdb),
-            },
+            RDB(rdb) => self.handle_rdb(rdb),
             AOF(cmd) => {
                 self.handle_aof(cmd);
             }
@@ -50,46 +42,87 @@ impl EventHandler for EventHandlerImpl {
     }
 }
 
-impl Drop for EventHandlerImpl {
-    fn drop(&mut self) {
-        if let Err(_) = self.sender.send(Message::Terminate) {}
-        if let Some(thread) = self.worker.thread.take() {
-            if let Err(_) = thread.join() {}
-        }
+impl Flush for EventHandlerImpl {
+    fn flush(&mut self) {
+        self.mark().wait();
+    }
+
+    fn mark(&mut self) -> Marker {
+        Marker::send(Some(&self.sender))
     }
 }
 
 impl CommandConverter for EventHandlerImpl {
     fn execute(&mut self, cmd: Cmd, _: Option<&[u8]>) {
-        if let Err(err) = self.sender.send(Message::Cmd(cmd)) {
-            panic!("{}", err)
+        if let Err(err) = self.sender.send(Message::Cmd(cmd, self.db)) {
+            self.abort.abort(&format!("worker已退出: {}", err));
         }
     }
 
+    fn filter(&mut self) -> &mut Filter {
+        &mut self.filter
+    }
+
+    fn renamer(&self) -> &Renamer {
+        &self.renamer
+    }
+
+    fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    fn restore(&self) -> Option<&Restore> {
+        self.restore.as_ref()
+    }
+
+    fn conflict(&mut self) -> Option<&mut Conflict> {
+        self.conflict.as_mut()
+    }
+
+    fn source(&mut self) -> Option<&mut SourceReader> {
+        Some(&mut self.source)
+    }
+
     fn swap_db(&mut self, db: i32) {
-        if let Err(err) = self.sender.send(Message::SwapDb(db as i64)) {
-            panic!("{}", err)
-        }
+        self.db = Some(db as i64);
     }
 }
 
-pub(crate) fn new(
-    target: String, batch_size: i32, flush_interval: u64, control_flag: Arc<AtomicBool>,
-) -> EventHandlerImpl {
-    let (sender, receiver) = mpsc::channel();
+// 各个源共用的worker
+pub(crate) fn new_workers(
+    target: String, batch_size: i32, flush_interval: u64, queue_size: usize, retry: RetryConfig,
+    control_flag: Arc<AtomicBool>,
+) -> Workers {
+    let worker_name = "copy_redis::worker";
+    let (sender, receiver) = worker::channel(worker_name, queue_size);
     let worker_thread = worker::new_worker(
         target,
         receiver,
-        "copy_redis::worker",
+        worker_name,
         batch_size,
         flush_interval,
+        retry,
         control_flag,
         Arc::new(ScheduledThreadPool::with_name("r2d2-worker-{}", 1)),
     );
+    let mut workers = Workers::new();
+    workers.add(worker_name, sender, worker_thread);
+    workers
+}
+
+pub(crate) fn new(
+    source: Option<&str>, senders: &BTreeMap<String, MessageSender>, filter: Filter, renamer: Renamer,
+    chunk_size: usize, restore: Option<Restore>, conflict: Option<Conflict>, abort: Abort,
+) -> EventHandlerImpl {
     EventHandlerImpl {
-        worker: Worker {
-            thread: Option::Some(worker_thread),
-        },
-        sender,
+        sender: senders.values().next().unwrap().clone(),
+        db: None,
+        filter,
+        renamer,
+        chunk_size,
+        restore,
+        conflict,
+        source: SourceReader::new(source),
+        abort,
     }
 }
@@ -1,9 +1,17 @@
+// 日志target使用&'static str常量, 忽略结果时使用if let Err(_), 与现有代码的写法保持一致
+#![allow(
+    clippy::redundant_static_lifetimes,
+    clippy::redundant_pattern_matching,
+    clippy::too_many_arguments
+)]
+
 extern crate ctrlc;
 extern crate getopts;
 extern crate r2d2_redis;
 
 use std::cell::RefCell;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
@@ -12,93 +20,517 @@ use std::io::{Error, ErrorKind, Read, Write};
 use std::path::PathBuf;
 use std::process::exit;
 use std::rc::Rc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use std::{env, thread};
 
 use getopts::Options;
-use log::{error, info};
+use log::{error, info, warn};
 use redis_event::config::Config;
 use redis_event::listener;
-use redis_event::RedisListener;
+use redis_event::{EventHandler, RedisListener};
+
+use crate::checkpoint::{Checkpoint, Flush, TargetFlusher};
+use crate::command::CommandConverter;
+use crate::conflict::Policy;
+use crate::filter::Filter;
+use crate::metrics::EventCounter;
+use crate::rename::Renamer;
+use crate::restore::Restore;
+use crate::sharding::{Hashing, ShardStrategy};
+use crate::source::ClusterSource;
+use crate::verify::Targets;
+use crate::worker::{MessageSender, RetryConfig, Workers};
 
+mod cdc;
+mod checkpoint;
 mod cluster;
 mod command;
+mod conflict;
+mod export;
+mod filter;
 mod handler;
+mod metrics;
+mod offline;
+mod relay;
+mod rename;
+mod repair;
+mod reshard;
+mod restore;
 mod sharding;
+mod source;
 mod tests;
+mod verify;
 mod worker;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // 子命令: copy-redis verify|repair [options]
+    let subcommand = match args.get(1).map(String::as_str) {
+        Some("verify") | Some("repair") => Some(args.remove(1)),
+        _ => None,
+    };
     let opt: Opt = parse_args(args);
-    setup_logger(&opt.log_file).expect("logger设置失败");
-    run(opt);
+    // JSON输出至标准输出时, 日志输出至标准错误
+    let stderr = opt.output_json.as_deref() == Some("-");
+    setup_logger(&opt.log_file, stderr).expect("logger设置失败");
+    match subcommand.as_deref() {
+        Some("verify") => {
+            if !verify::run(&opt) {
+                exit(1);
+            }
+        }
+        Some("repair") => {
+            if !repair::run(&opt) {
+                exit(1);
+            }
+        }
+        _ => run(opt),
+    }
 }
 
 fn run(opt: Opt) {
-    let config = new_redis_listener_config(&opt);
-    let source_addr = format!("{}:{}", &config.host, config.port);
     // 先关闭listener，因为listener在读取流中的数据时，是阻塞的，
     // 所以在接收到ctrl-c信号的时候，得再等一会，等redis master的数据来到(或者读取超时)，此时，程序才会继续运行，
     // 等命令被handler处理完之后，listener才能结束，而且handler的结束还必须在listener之后，要不然丢数据
     let is_running = Arc::new(AtomicBool::new(true));
     setup_ctrlc_handler(is_running.clone());
 
-    let mut builder = listener::Builder::new();
-    builder.with_config(config);
-    builder.with_control_flag(Arc::clone(&is_running));
+    if let Some(addr) = &opt.metrics {
+        metrics::serve(addr);
+    }
 
-    if opt.sharding || opt.cluster {
-        if opt.sharding && opt.cluster {
-            panic!("不能同时指定sharding与cluster")
-        }
-        if opt.sharding {
-            let event_handler =
-                sharding::new_sharded(opt.targets, opt.batch_size, opt.flush_interval, Arc::clone(&is_running));
-            builder.with_event_handler(Rc::new(RefCell::new(event_handler)));
+    // 每个源Redis(源Redis为Cluster时则是每个master)各自使用一个listener及handler, 在各自的线程中并行复制
+    let mut sources: Vec<(String, Option<ClusterSource>)> = Vec::new();
+    let offline = if let Some(path) = &opt.source_rdb {
+        Some(offline::serve_rdb(path))
+    } else if let Some(path) = &opt.source_aof {
+        Some(offline::serve_aof(path, opt.until_timestamp))
+    } else {
+        None
+    };
+    if let Some(offline) = &offline {
+        sources.push((offline.url.clone(), None));
+    }
+    for source in &opt.sources {
+        if opt.source_cluster {
+            for (url, cluster) in source::cluster_masters(source) {
+                sources.push((url, Some(cluster)));
+            }
         } else {
-            let event_handler = cluster::new_cluster(opt.targets, is_running.clone());
-            builder.with_event_handler(Rc::new(RefCell::new(event_handler)));
+            sources.push((source.clone(), None));
+        }
+    }
+    let multiple = sources.len() > 1;
+    // 写入目的Redis的worker由所有源共用, 在所有源的复制结束之后才停止
+    let workers = new_workers(&opt, &is_running);
+    let abort = Abort::new(&is_running);
+    let opt = Arc::new(opt);
+    let mut threads = Vec::new();
+    for (url, cluster) in sources {
+        let opt = Arc::clone(&opt);
+        let abort = abort.clone();
+        let finished = offline.as_ref().map(|offline| Arc::clone(&offline.finished));
+        let senders = workers.as_ref().map(|workers| workers.senders.clone());
+        let thread = thread::Builder::new()
+            .name("copy_redis::source".into())
+            .spawn(move || {
+                let _guard = StopOnPanic(Arc::clone(&abort.running));
+                replicate(&opt, &url, cluster, multiple, finished, senders, abort);
+            })
+            .unwrap();
+        threads.push(thread);
+    }
+    let mut panicked = false;
+    for thread in threads {
+        if let Err(_) = thread.join() {
+            panicked = true;
+        }
+    }
+    drop(workers);
+    if panicked {
+        panic!("源Redis的复制异常终止");
+    }
+    if abort.is_aborted() {
+        exit(1);
+    }
+}
+
+// 复制无法继续时(worker已退出, --conflict error时目的Redis中已存在相同的key等), 停止所有源的listener,
+// 且不保存PSYNC信息, 程序以非0状态退出
+#[derive(Clone)]
+pub(crate) struct Abort {
+    pub(crate) running: Arc<AtomicBool>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl Abort {
+    pub(crate) fn new(running: &Arc<AtomicBool>) -> Abort {
+        Abort {
+            running: Arc::clone(running),
+            aborted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // 只输出第一次的原因
+    pub(crate) fn abort(&self, reason: &str) {
+        if !self.aborted.swap(true, Ordering::SeqCst) {
+            error!("{}, 复制终止", reason);
+        }
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+}
+
+// 任意一个源的复制线程panic时, 通知其他源停止复制
+struct StopOnPanic(Arc<AtomicBool>);
+
+impl Drop for StopOnPanic {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.store(false, Ordering::SeqCst);
         }
+    }
+}
+
+// 输出至文件或JSON时不写入目的Redis, 没有worker
+fn new_workers(opt: &Opt, is_running: &Arc<AtomicBool>) -> Option<Workers> {
+    if opt.output_dir.is_some() || opt.output_json.is_some() {
+        return None;
+    }
+    if opt.sharding && opt.cluster {
+        panic!("不能同时指定sharding与cluster")
+    }
+    let retry = RetryConfig {
+        max_retries: opt.max_retries,
+        interval: opt.retry_interval,
+        dead_letter_dir: opt.dead_letter.clone(),
+    };
+    let workers = if opt.sharding {
+        sharding::new_workers(
+            opt.targets.clone(),
+            opt.batch_size,
+            opt.flush_interval,
+            opt.queue_size,
+            retry,
+            Arc::clone(is_running),
+        )
+    } else if opt.cluster {
+        cluster::new_workers(
+            opt.targets.clone(),
+            opt.batch_size,
+            opt.flush_interval,
+            opt.queue_size,
+            retry,
+            Arc::clone(is_running),
+        )
     } else {
-        let event_handler = handler::new(
+        handler::new_workers(
             opt.targets.get(0).unwrap().to_string(),
             opt.batch_size,
             opt.flush_interval,
-            Arc::clone(&is_running),
-        );
-        builder.with_event_handler(Rc::new(RefCell::new(event_handler)));
-    }
+            opt.queue_size,
+            retry,
+            Arc::clone(is_running),
+        )
+    };
+    Some(workers)
+}
+
+fn replicate(
+    opt: &Opt, url: &str, cluster: Option<ClusterSource>, multiple: bool, finished: Option<Arc<AtomicBool>>,
+    senders: Option<BTreeMap<String, MessageSender>>, abort: Abort,
+) {
+    let is_running = Arc::clone(&abort.running);
+    // 输出至文件或JSON时为空
+    let senders = senders.unwrap_or_default();
+    let mut config = new_redis_listener_config(opt, url);
+    let mut source_addr = format!("{}:{}", &config.host, config.port);
+    // 有多个源时, 各自输出的文件以源地址区分
+    let tag = if multiple {
+        format!("@{}", source_addr)
+    } else {
+        String::new()
+    };
+    let filter = Filter::new(
+        &opt.include,
+        &opt.exclude,
+        &opt.include_regex,
+        &opt.exclude_regex,
+        opt.dbs.clone(),
+        opt.db_mapping.clone(),
+    );
+    let renamer = Renamer::new(
+        opt.key_prefix.clone(),
+        opt.rename_regex.clone(),
+        opt.rename_replacement.clone(),
+    );
+    let cleanup = if opt.reshard_delete {
+        Some((filter.clone(), renamer.clone()))
+    } else {
+        None
+    };
+
+    // 以离线文件作为源时, 源为本地模拟的master, 不需要获取复制offset及保存PSYNC信息
+    let offline = finished.is_some();
+    let mut polling = if offline {
+        None
+    } else {
+        poll_master_offset(opt, url, &source_addr)
+    };
+    metrics::set_repl_offset(&source_addr, config.repl_offset);
+    // 最近一次listener停止(连接中断或程序退出)时的复制offset
+    let offset = Arc::new(AtomicI64::new(config.repl_offset));
+
+    // 在AOF阶段定时保存PSYNC信息、更新metrics或输出JSON时, listener经由relay连接源Redis, 以便获取其已确认的offset
+    let interval = if opt.checkpoint_interval > 0 {
+        Some(Duration::from_secs(opt.checkpoint_interval))
+    } else {
+        None
+    };
+    let checkpointing = opt.aof && !offline && (interval.is_some() || opt.metrics.is_some());
+    let tracking = checkpointing || (opt.aof && !offline && opt.output_json.is_some());
+    let relay = if tracking && !config.is_tls_enabled {
+        let relay = relay::Relay::start(&source_addr);
+        config.host = relay.host.clone();
+        config.port = relay.port;
+        Some(relay)
+    } else {
+        if tracking {
+            warn!(
+                "源Redis({})使用TLS连接, 复制offset只在连接中断或程序退出时更新",
+                source_addr
+            );
+        }
+        None
+    };
+
+    let mut builder = listener::Builder::new();
+    builder.with_config(config);
+    builder.with_control_flag(Arc::clone(&is_running));
+
+    let restore = if opt.restore {
+        Some(Restore::new(&opt.targets))
+    } else {
+        None
+    };
+    // 离线文件作为源时, 不能再向本地模拟的master发起连接
+    let source = if offline { None } else { Some(url) };
+    let (event_handler, target, converter) = if let Some(dir) = &opt.output_dir {
+        share(export::new(
+            dir,
+            &tag,
+            source,
+            opt.rotate_size,
+            opt.rotate_interval,
+            opt.flush_interval,
+            filter,
+            renamer,
+        ))
+    } else if let Some(output) = &opt.output_json {
+        share(cdc::new(
+            output,
+            &source_addr,
+            opt.json_base64,
+            opt.flush_interval,
+            filter,
+            renamer,
+            opt.chunk_size,
+            relay
+                .as_ref()
+                .map_or_else(|| Arc::clone(&offset), |relay| relay.progress().offset),
+        ))
+    } else if opt.sharding {
+        share(sharding::new_sharded(
+            source,
+            &senders,
+            &opt.targets,
+            filter,
+            renamer,
+            opt.chunk_size,
+            restore,
+            conflict::new(opt, &abort),
+            opt.shard_strategy,
+            opt.shard_hash,
+            opt.shard_key_tag.clone(),
+            opt.reshard,
+            abort.clone(),
+        ))
+    } else if opt.cluster {
+        share(cluster::new_cluster(
+            source,
+            &senders,
+            filter,
+            renamer,
+            opt.chunk_size,
+            restore,
+            conflict::new(opt, &abort),
+            abort.clone(),
+        ))
+    } else {
+        share(handler::new(
+            source,
+            &senders,
+            filter,
+            renamer,
+            opt.chunk_size,
+            restore,
+            conflict::new(opt, &abort),
+            abort.clone(),
+        ))
+    };
+    let event_handler: Rc<RefCell<dyn EventHandler>> = if opt.flush_target {
+        Rc::new(RefCell::new(TargetFlusher {
+            handler: event_handler,
+            target: Rc::clone(&target),
+            targets: Targets::new(opt),
+            dbs: opt
+                .dbs
+                .as_ref()
+                .map(|dbs| dbs.iter().map(|db| *opt.db_mapping.get(db).unwrap_or(db)).collect()),
+        }))
+    } else {
+        event_handler
+    };
+    let event_handler: Rc<RefCell<dyn EventHandler>> = if opt.metrics.is_some() {
+        Rc::new(RefCell::new(EventCounter { handler: event_handler }))
+    } else {
+        event_handler
+    };
+    let checkpoint = relay.as_ref().filter(|_| checkpointing).map(|relay| {
+        Rc::new(RefCell::new(Checkpoint::new(
+            event_handler.clone(),
+            Rc::clone(&target),
+            relay.progress(),
+            &source_addr,
+            interval,
+            opt.metrics.is_some(),
+        )))
+    });
+    match &checkpoint {
+        None => builder.with_event_handler(event_handler),
+        Some(checkpoint) => builder.with_event_handler(checkpoint.clone()),
+    };
     let mut listener = builder.build();
 
     while is_running.load(Ordering::Relaxed) {
-        if let Err(error) = listener.start() {
+        let result = listener.start();
+        offset.store(listener.config.repl_offset, Ordering::Relaxed);
+        if let Err(error) = result {
+            // 离线文件中的数据全部发送之后, 模拟的master会关闭连接
+            if finished
+                .as_ref()
+                .map_or(false, |finished| finished.load(Ordering::SeqCst))
+            {
+                break;
+            }
             let error = error.to_string();
-            if error.starts_with("NOPERM") || error.starts_with("NOAUTH") {
-                panic!(error);
+            if offline || error.starts_with("NOPERM") || error.starts_with("NOAUTH") {
+                panic!("{}", error);
             } else {
-                error!("连接到源Redis错误: {}", error);
+                error!("连接到源Redis({})错误: {}", source_addr, error);
                 thread::sleep(Duration::from_millis(2000));
             }
+            // master发生故障转移后, 由新的master继续之前的复制进度(PSYNC时使用的是原master的repl id与offset)
+            if let Some(node) = cluster.as_ref().and_then(|cluster| cluster.failover(&source_addr)) {
+                info!(
+                    "源Cluster发生了故障转移, 从新的master {}继续复制, 原master: {}",
+                    node.addr, source_addr
+                );
+                if let Some(relay) = &relay {
+                    relay.retarget(&node.addr);
+                } else {
+                    let i = node.addr.rfind(':').unwrap();
+                    listener.config.host = node.addr[..i].to_string();
+                    listener.config.port = node.addr[i + 1..].parse().unwrap();
+                }
+                if let Some(checkpoint) = &checkpoint {
+                    checkpoint.borrow_mut().source = node.addr.clone();
+                }
+                // 之后需要从源Redis读取的key也从新的master读取
+                if let Some(reader) = converter.borrow_mut().source() {
+                    reader.retarget(&node.url);
+                }
+                source_addr = node.addr;
+                if let Some(polling) = &polling {
+                    polling.store(false, Ordering::SeqCst);
+                }
+                polling = poll_master_offset(opt, &node.url, &source_addr);
+            }
         } else {
             break;
         }
     }
 
-    // 程序正常退出时，保存repl id和offset
-    if let Err(err) = save_repl_meta(&source_addr, &listener.config.repl_id, listener.config.repl_offset) {
-        error!("保存PSYNC信息失败:{}", err);
+    // 程序正常退出时，等待已接收的命令写入完成，再保存repl id和offset
+    target.borrow_mut().flush();
+    metrics::set_repl_offset(&source_addr, listener.config.repl_offset);
+    if abort.is_aborted() {
+        // 已接收的命令未全部写入, 保存的offset会使之后的PSYNC跳过这些命令
+        error!("源Redis({})的复制异常终止, 未保存PSYNC信息", source_addr);
+        if let Some(polling) = &polling {
+            polling.store(false, Ordering::SeqCst);
+        }
+        return;
+    }
+    if !offline {
+        if let Err(err) = save_repl_meta(&source_addr, &listener.config.repl_id, listener.config.repl_offset) {
+            error!("保存PSYNC信息失败:{}", err);
+        }
     }
+    if let Some(polling) = &polling {
+        polling.store(false, Ordering::SeqCst);
+    }
+    if let Some((filter, renamer)) = cleanup {
+        // worker由所有源共用, 不能在这里停止; 已接收的命令在上面的flush中都已写入目的Redis
+        let layout = sharding::Layout::new(
+            &opt.targets,
+            opt.shard_strategy,
+            opt.shard_hash,
+            opt.shard_key_tag.clone(),
+        );
+        reshard::delete_moved(url, &layout, &filter, &renamer);
+    }
+}
+
+// 开启--metrics时, 定时获取源Redis的master_repl_offset. 返回值用于停止轮询
+fn poll_master_offset(opt: &Opt, url: &str, addr: &str) -> Option<Arc<AtomicBool>> {
+    opt.metrics.as_ref().map(|_| {
+        let polling = Arc::new(AtomicBool::new(true));
+        metrics::poll_master_offset(
+            url.to_string(),
+            addr.to_string(),
+            Duration::from_secs(5),
+            Arc::clone(&polling),
+        );
+        polling
+    })
 }
 
-fn new_redis_listener_config(opt: &Opt) -> Config {
-    let url = match url::Url::parse(&opt.source) {
+fn share<H>(
+    handler: H,
+) -> (
+    Rc<RefCell<dyn EventHandler>>,
+    Rc<RefCell<dyn Flush>>,
+    Rc<RefCell<dyn CommandConverter>>,
+)
+where
+    H: EventHandler + Flush + CommandConverter + 'static,
+{
+    let handler = Rc::new(RefCell::new(handler));
+    (handler.clone(), handler.clone(), handler)
+}
+
+fn new_redis_listener_config(opt: &Opt, source: &str) -> Config {
+    let url = match url::Url::parse(source) {
         Ok(result) => match result.scheme() {
             "redis" | "rediss" => Ok(result),
             _ => {
-                let err = format!("不支持的Redis URL: {}", &opt.source);
+                let err = format!("不支持的Redis URL: {}", source);
                 Err(Error::new(ErrorKind::InvalidInput, err))
             }
         },
@@ -195,15 +627,51 @@ fn save_repl_meta(source_addr: &str, id: &str, offset: i64) -> io::Result<()> {
 
 #[derive(Debug)]
 struct Opt {
-    source: String,
+    sources: Vec<String>,
+    source_cluster: bool,
+    source_rdb: Option<String>,
+    source_aof: Option<String>,
+    until_timestamp: Option<i64>,
     targets: Vec<String>,
+    output_dir: Option<String>,
+    output_json: Option<String>,
+    json_base64: bool,
+    rotate_size: u64,
+    rotate_interval: u64,
     discard_rdb: bool,
     aof: bool,
     log_file: Option<String>,
     sharding: bool,
+    shard_strategy: ShardStrategy,
+    shard_hash: Hashing,
+    shard_key_tag: Option<String>,
+    reshard: bool,
+    reshard_delete: bool,
+    verify_dump: bool,
+    repair_file: Option<String>,
+    repair_delete: bool,
     cluster: bool,
     batch_size: i32,
     flush_interval: u64,
+    queue_size: usize,
+    chunk_size: usize,
+    restore: bool,
+    conflict: Policy,
+    flush_target: bool,
+    max_retries: u32,
+    retry_interval: u64,
+    dead_letter: Option<String>,
+    checkpoint_interval: u64,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    include_regex: Vec<String>,
+    exclude_regex: Vec<String>,
+    dbs: Option<Vec<i64>>,
+    db_mapping: HashMap<i64, i64>,
+    key_prefix: Option<String>,
+    rename_regex: Option<String>,
+    rename_replacement: Option<String>,
+    metrics: Option<String>,
     identity: Option<String>,
     identity_passwd: Option<String>,
 }
@@ -213,13 +681,65 @@ const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 fn parse_args(args: Vec<String>) -> Opt {
     let mut opts = Options::new();
-    opts.optopt(
+    opts.optmulti(
         "s",
         "source",
-        "此Redis内的数据将复制到目的Redis中",
+        "此Redis内的数据将复制到目的Redis中, 可指定多次以将多个源Redis的数据合并至目的Redis",
         "源Redis的URI, 格式: \"redis[s]://[user:password@]host:port[/#insecure]\"",
     );
+    opts.optflag(
+        "",
+        "source-cluster",
+        "源Redis为Cluster, 此时source为Cluster中任意一个节点, 将从所有master节点复制数据",
+    );
+    opts.optopt(
+        "",
+        "source-rdb",
+        "以本地的RDB文件作为源, 不能与--source及--aof同时指定",
+        "dump.rdb",
+    );
+    opts.optopt(
+        "",
+        "source-aof",
+        "以本地的AOF文件作为源, 也可以是Redis 7的multi-part AOF目录. 不能与--source同时指定",
+        "appendonly.aof",
+    );
+    opts.optopt(
+        "",
+        "until-timestamp",
+        "与--source-aof同时使用, 只复制AOF中此时间点(unix时间戳, 秒)之前的命令, 需要AOF中有#TS注释(aof-timestamp-enabled)",
+        "1628217470",
+    );
     opts.optmulti("t", "target", "", "目的Redis的URI, URI格式同上");
+    opts.optopt(
+        "",
+        "output-dir",
+        "不写入目的Redis, 而是将RDB保存为RDB文件、AOF保存为AOF文件至此目录, 不能与--target同时指定",
+        "backup",
+    );
+    opts.optopt(
+        "",
+        "rotate-size",
+        "与--output-dir同时使用, AOF文件达到此大小(MB)时切换至新的文件, 若为0则不按大小切换",
+        "1024",
+    );
+    opts.optopt(
+        "",
+        "rotate-interval",
+        "与--output-dir同时使用, AOF文件每隔此时间(秒)切换至新的文件, 若为0则不按时间切换",
+        "0",
+    );
+    opts.optopt(
+        "",
+        "output-json",
+        "不写入目的Redis, 而是将每个RDB对象及AOF命令以JSON格式逐行输出. -为标准输出, unix:<path>为Unix socket, 否则为文件",
+        "-",
+    );
+    opts.optflag(
+        "",
+        "json-base64",
+        "与--output-json同时使用, key及参数以base64编码. 默认为utf8",
+    );
     opts.optflag(
         "d",
         "discard-rdb",
@@ -227,6 +747,34 @@ fn parse_args(args: Vec<String>) -> Opt {
     );
     opts.optflag("a", "aof", "是否需要处理AOF. 默认为false, 当RDB复制完后程序将终止");
     opts.optflag("", "sharding", "是否sharding模式");
+    opts.optopt(
+        "",
+        "shard-strategy",
+        "sharding模式下的分片方式, jedis或twemproxy(ketama). 默认为jedis",
+        "jedis",
+    );
+    opts.optopt(
+        "",
+        "shard-hash",
+        "sharding模式下计算hash值所使用的算法. jedis支持MURMUR_HASH(默认)或MD5, twemproxy支持fnv1a_64(默认)或md5",
+        "MURMUR_HASH",
+    );
+    opts.optflagopt(
+        "",
+        "shard-key-tag",
+        "sharding模式下只使用key中的tag部分计算hash. jedis为正则表达式, 取第一个捕获组, 默认为\\{(.+?)\\}; twemproxy为hash_tag的两个字符, 默认为{}",
+        "PATTERN",
+    );
+    opts.optflag(
+        "",
+        "reshard",
+        "sharding模式下只复制所属分片发生变化的key, 即按照目的Redis的分片规则仍属于源Redis(地址相同)的key不进行复制",
+    );
+    opts.optflag(
+        "",
+        "reshard-delete",
+        "与--reshard同时使用, 复制结束后从源Redis中删除已迁移至其他分片且校验一致的key",
+    );
     opts.optflag("", "cluster", "是否cluster模式");
     opts.optopt("l", "log", "默认输出至stdout", "日志输出文件");
     opts.optopt(
@@ -236,6 +784,103 @@ fn parse_args(args: Vec<String>) -> Opt {
         "2500",
     );
     opts.optopt("i", "flush-interval", "发送命令的最短间隔时间(毫秒)", "100");
+    opts.optopt(
+        "",
+        "queue-size",
+        "每个worker最多可积压的命令数量, 达到此数量时暂停读取源Redis",
+        "10000",
+    );
+    opts.optopt(
+        "",
+        "chunk-size",
+        "RDB阶段list/set/zset/hash的每条命令最多包含的元素数量, 超过时拆分为多条命令, 若为0则不拆分",
+        "1000",
+    );
+    opts.optflag(
+        "",
+        "restore",
+        "RDB阶段将每个key序列化为DUMP格式, 以RESTORE命令写入目的Redis. 目的Redis不支持该类型时仍以命令的方式写入",
+    );
+    opts.optopt(
+        "",
+        "conflict",
+        "RDB阶段目的Redis中已存在相同key时的处理方式: replace(先删除再写入), skip(跳过), error(终止复制), append(与已有的数据合并)",
+        "append",
+    );
+    opts.optflag(
+        "",
+        "flush-target-on-full-sync",
+        "每次全量同步开始时, 先清空目的Redis(所有分片及Cluster的所有master). 需同时指定--confirm-flush",
+    );
+    opts.optflag(
+        "",
+        "confirm-flush",
+        "确认--flush-target-on-full-sync会清空目的Redis中的数据",
+    );
+    opts.optopt("", "max-retries", "命令写入失败时的最大重试次数", "3");
+    opts.optopt(
+        "",
+        "retry-interval",
+        "首次重试前的等待时间(毫秒), 之后每次重试的等待时间翻倍",
+        "500",
+    );
+    opts.optopt(
+        "",
+        "dead-letter",
+        "重试后仍写入失败的命令将以RESP格式保存至此目录下, 若为空字符串则不保存",
+        ".copy-redis/dead-letter",
+    );
+    opts.optopt(
+        "",
+        "checkpoint-interval",
+        "AOF阶段保存PSYNC信息的间隔时间(秒), 若为0则只在程序退出时保存. 源Redis使用TLS连接时只能为0(默认)",
+        "60",
+    );
+    opts.optmulti(
+        "",
+        "include",
+        "只复制key名称匹配此glob pattern的数据, 可指定多次",
+        "user:*",
+    );
+    opts.optmulti(
+        "",
+        "exclude",
+        "不复制key名称匹配此glob pattern的数据, 可指定多次",
+        "*:tmp",
+    );
+    opts.optmulti(
+        "",
+        "include-regex",
+        "同include, 但使用正则表达式进行匹配",
+        "^user:\\d+$",
+    );
+    opts.optmulti("", "exclude-regex", "同exclude, 但使用正则表达式进行匹配", "^tmp:");
+    opts.optopt("", "db", "只复制指定的db, 多个db之间以逗号分隔. 默认复制所有db", "0,3");
+    opts.optmulti(
+        "",
+        "map-db",
+        "将源Redis中的db映射至目的Redis中的另一个db, 格式: 源db:目的db, 可指定多次",
+        "3:0",
+    );
+    opts.optopt("", "key-prefix", "写入目的Redis时为每个key添加的前缀", "tenantA:");
+    opts.optopt(
+        "",
+        "rename-regex",
+        "写入目的Redis时, 对key中第一处匹配此正则表达式的部分进行替换",
+        "^user:(\\d+)$",
+    );
+    opts.optopt(
+        "",
+        "rename-replacement",
+        "rename-regex匹配部分的替换内容, 可使用$1等引用捕获组",
+        "member:$1",
+    );
+    opts.optopt(
+        "",
+        "metrics",
+        "在此地址上提供Prometheus的/metrics接口, 默认不开启",
+        "0.0.0.0:9121",
+    );
     opts.optopt(
         "",
         "identity",
@@ -243,6 +888,22 @@ fn parse_args(args: Vec<String>) -> Opt {
         "",
     );
     opts.optopt("", "identity-passwd", "identity参数所指定的key文件解密时所需的密码", "");
+    opts.optflag(
+        "",
+        "verify-dump",
+        "verify时比较key的DUMP结果, 而不是按照类型读取值并比较摘要. 源与目的Redis的版本不同时DUMP的结果可能不同",
+    );
+    opts.optopt(
+        "",
+        "repair-file",
+        "verify时将修复不一致所需的命令写入此文件; repair时从此文件读取需要修复的key, 不指定时先进行一次校验",
+        "repair.txt",
+    );
+    opts.optflag(
+        "",
+        "repair-delete",
+        "repair时同时删除目的Redis中多余的key, 只删除复制范围(db、过滤及重命名规则)之内的key. 默认只重新复制",
+    );
     opts.optflag("h", "help", "输出帮助信息");
     opts.optflag("v", "version", "");
 
@@ -264,18 +925,115 @@ fn parse_args(args: Vec<String>) -> Opt {
         exit(0);
     }
 
-    let (source, targets) = if matches.opt_present("s") && matches.opt_present("t") {
-        (matches.opt_str("s").unwrap(), matches.opt_strs("t"))
+    let source_rdb = matches.opt_str("source-rdb");
+    let source_aof = matches.opt_str("source-aof");
+    let offline = source_rdb.is_some() || source_aof.is_some();
+    let output_dir = matches.opt_str("output-dir");
+    let output_json = matches.opt_str("output-json");
+    let target = matches.opt_present("t") || output_dir.is_some() || output_json.is_some();
+    let (sources, targets) = if (matches.opt_present("s") || offline) && target {
+        (matches.opt_strs("s"), matches.opt_strs("t"))
     } else {
         print_usage(&opts);
         exit(1);
     };
 
+    let source_cluster = matches.opt_present("source-cluster");
     let discard_rdb = matches.opt_present("discard-rdb");
     let sharding = matches.opt_present("sharding");
+    let shard_strategy = match matches.opt_str("shard-strategy") {
+        None => ShardStrategy::Jedis,
+        Some(name) => match ShardStrategy::parse(&name) {
+            Some(strategy) => strategy,
+            None => {
+                eprint!("Error: 不支持的分片方式: {}\r\n", name);
+                exit(1);
+            }
+        },
+    };
+    let shard_hash = match matches.opt_str("shard-hash") {
+        None => shard_strategy.default_hashing(),
+        Some(name) => match Hashing::parse(&name) {
+            Some(hashing) if shard_strategy.supports(hashing) => hashing,
+            _ => {
+                eprint!("Error: 不支持的hash算法: {}\r\n", name);
+                exit(1);
+            }
+        },
+    };
+    let shard_key_tag = matches.opt_default("shard-key-tag", shard_strategy.default_key_tag());
+    let reshard = matches.opt_present("reshard");
+    let reshard_delete = matches.opt_present("reshard-delete");
+    if reshard && !sharding {
+        eprint!("Error: --reshard需与--sharding同时指定\r\n");
+        exit(1);
+    }
+    if reshard_delete && !reshard {
+        eprint!("Error: --reshard-delete需与--reshard同时指定\r\n");
+        exit(1);
+    }
     let cluster = matches.opt_present("cluster");
+    let verify_dump = matches.opt_present("verify-dump");
+    let repair_file = matches.opt_str("repair-file");
+    let repair_delete = matches.opt_present("repair-delete");
     let aof = matches.opt_present("aof");
+    if source_rdb.is_some() && (!sources.is_empty() || aof || source_aof.is_some()) {
+        eprint!("Error: --source-rdb不能与--source、--source-aof及--aof同时指定\r\n");
+        exit(1);
+    }
+    if source_aof.is_some() && !sources.is_empty() {
+        eprint!("Error: --source-aof不能与--source同时指定\r\n");
+        exit(1);
+    }
+    let until_timestamp = match matches.opt_str("until-timestamp") {
+        None => None,
+        Some(_) if source_aof.is_none() => {
+            eprint!("Error: --until-timestamp需与--source-aof同时指定\r\n");
+            exit(1);
+        }
+        Some(timestamp) => match timestamp.parse::<i64>() {
+            Ok(timestamp) => Some(timestamp),
+            Err(_) => {
+                eprint!("Error: 无效的时间戳: {}\r\n", timestamp);
+                exit(1);
+            }
+        },
+    };
+    // AOF文件中的命令作为增量数据发送
+    let aof = aof || source_aof.is_some();
     let log_file = matches.opt_str("l");
+    let include = matches.opt_strs("include");
+    let exclude = matches.opt_strs("exclude");
+    let include_regex = matches.opt_strs("include-regex");
+    let exclude_regex = matches.opt_strs("exclude-regex");
+    let dbs = matches.opt_str("db").map(|dbs| {
+        dbs.split(',')
+            .map(|db| match db.trim().parse::<i64>() {
+                Ok(db) => db,
+                Err(_) => {
+                    eprint!("Error: 无效的db: {}\r\n", db);
+                    exit(1);
+                }
+            })
+            .collect()
+    });
+    let mut db_mapping = HashMap::new();
+    for mapping in matches.opt_strs("map-db") {
+        let pair: Vec<_> = mapping.split(':').map(|db| db.trim().parse::<i64>()).collect();
+        match pair.as_slice() {
+            [Ok(from), Ok(to)] => {
+                db_mapping.insert(*from, *to);
+            }
+            _ => {
+                eprint!("Error: 无效的db映射: {}\r\n", mapping);
+                exit(1);
+            }
+        }
+    }
+    let key_prefix = matches.opt_str("key-prefix");
+    let rename_regex = matches.opt_str("rename-regex");
+    let rename_replacement = matches.opt_str("rename-replacement");
+    let metrics = matches.opt_str("metrics");
     let identity = matches.opt_str("identity");
     let identity_passwd = matches.opt_str("identity-passwd");
 
@@ -307,27 +1065,193 @@ fn parse_args(args: Vec<String>) -> Opt {
         100
     };
 
+    let queue_size = if matches.opt_present("queue-size") {
+        let _str = matches.opt_str("queue-size").unwrap();
+        match _str.parse::<usize>() {
+            Ok(size) if size > 0 => size,
+            _ => 10000,
+        }
+    } else {
+        10000
+    };
+
+    let chunk_size = match matches.opt_str("chunk-size") {
+        None => 1000,
+        Some(size) => match size.parse::<usize>() {
+            Ok(size) => size,
+            Err(_) => {
+                eprint!("Error: 无效的chunk-size: {}\r\n", size);
+                exit(1);
+            }
+        },
+    };
+
+    let max_retries = if matches.opt_present("max-retries") {
+        let _str = matches.opt_str("max-retries").unwrap();
+        match _str.parse::<u32>() {
+            Ok(retries) => retries,
+            Err(_) => 3,
+        }
+    } else {
+        3
+    };
+
+    let retry_interval = if matches.opt_present("retry-interval") {
+        let _str = matches.opt_str("retry-interval").unwrap();
+        match _str.parse::<u64>() {
+            Ok(interval) => interval,
+            Err(_) => 500,
+        }
+    } else {
+        500
+    };
+
+    let dead_letter = match matches.opt_str("dead-letter") {
+        None => Some(format!("{}/dead-letter", METADATA)),
+        Some(dir) if dir.is_empty() => None,
+        Some(dir) => Some(dir),
+    };
+
+    if output_dir.is_some() && (!targets.is_empty() || sharding || cluster) {
+        eprint!("Error: --output-dir不能与--target、--sharding及--cluster同时指定\r\n");
+        exit(1);
+    }
+    if output_json.is_some() && (!targets.is_empty() || output_dir.is_some() || sharding || cluster) {
+        eprint!("Error: --output-json不能与--target、--output-dir、--sharding及--cluster同时指定\r\n");
+        exit(1);
+    }
+    let json_base64 = matches.opt_present("json-base64");
+    let restore = matches.opt_present("restore");
+    if restore && targets.is_empty() {
+        eprint!("Error: --restore需要指定--target\r\n");
+        exit(1);
+    }
+    let conflict = match matches.opt_str("conflict") {
+        None => Policy::Append,
+        Some(name) => match Policy::parse(&name) {
+            Some(policy) => policy,
+            None => {
+                eprint!("Error: 不支持的conflict: {}\r\n", name);
+                exit(1);
+            }
+        },
+    };
+    if conflict != Policy::Append && targets.is_empty() {
+        eprint!("Error: --conflict需要指定--target\r\n");
+        exit(1);
+    }
+    let flush_target = matches.opt_present("flush-target-on-full-sync");
+    if flush_target && !matches.opt_present("confirm-flush") {
+        eprint!("Error: --flush-target-on-full-sync会清空目的Redis中的数据, 请同时指定--confirm-flush以确认\r\n");
+        exit(1);
+    }
+    if flush_target && (targets.is_empty() || discard_rdb || reshard) {
+        eprint!("Error: --flush-target-on-full-sync需要指定--target, 且不能与--discard-rdb及--reshard同时指定\r\n");
+        exit(1);
+    }
+    // 一个源的全量同步会清空其他源已写入的数据
+    if flush_target && (sources.len() > 1 || source_cluster) {
+        eprint!("Error: 有多个源时不能指定--flush-target-on-full-sync\r\n");
+        exit(1);
+    }
+    let rotate_size = match matches.opt_str("rotate-size") {
+        None => 1024 * 1024 * 1024,
+        Some(size) => match size.parse::<u64>() {
+            Ok(size) => size * 1024 * 1024,
+            Err(_) => {
+                eprint!("Error: 无效的rotate-size: {}\r\n", size);
+                exit(1);
+            }
+        },
+    };
+    let rotate_interval = match matches.opt_str("rotate-interval") {
+        None => 0,
+        Some(interval) => match interval.parse::<u64>() {
+            Ok(interval) => interval,
+            Err(_) => {
+                eprint!("Error: 无效的rotate-interval: {}\r\n", interval);
+                exit(1);
+            }
+        },
+    };
+
+    // TLS连接中的数据无法经由relay解析, AOF阶段无法得知listener已确认的offset, 只能在程序退出时保存PSYNC信息
+    let tls = sources
+        .iter()
+        .any(|source| url::Url::parse(source).map_or(false, |url| url.scheme() == "rediss"));
+    let checkpoint_interval = if matches.opt_present("checkpoint-interval") {
+        let _str = matches.opt_str("checkpoint-interval").unwrap();
+        match _str.parse::<u64>() {
+            Ok(interval) => interval,
+            Err(_) => 60,
+        }
+    } else if tls {
+        0
+    } else {
+        60
+    };
+    if tls && aof && checkpoint_interval > 0 {
+        eprint!("Error: 源Redis使用TLS连接时不支持在AOF阶段定时保存PSYNC信息, 请指定--checkpoint-interval 0\r\n");
+        exit(1);
+    }
+
     return Opt {
-        source,
+        sources,
+        source_cluster,
+        source_rdb,
+        source_aof,
+        until_timestamp,
         targets,
+        output_dir,
+        output_json,
+        json_base64,
+        rotate_size,
+        rotate_interval,
         discard_rdb,
         aof,
         log_file,
         sharding,
+        shard_strategy,
+        shard_hash,
+        shard_key_tag,
+        reshard,
+        reshard_delete,
+        verify_dump,
+        repair_file,
+        repair_delete,
         cluster,
         batch_size,
         flush_interval,
+        queue_size,
+        chunk_size,
+        restore,
+        conflict,
+        flush_target,
+        max_retries,
+        retry_interval,
+        dead_letter,
+        checkpoint_interval,
+        include,
+        exclude,
+        include_regex,
+        exclude_regex,
+        dbs,
+        db_mapping,
+        key_prefix,
+        rename_regex,
+        rename_replacement,
+        metrics,
         identity,
         identity_passwd,
     };
 }
 
 fn print_usage(opts: &Options) {
-    let brief = format!("Usage: copy-redis [options]");
+    let brief = format!("Usage: copy-redis [verify|repair] [options]");
     print!("{}", opts.usage(&brief));
 }
 
-fn setup_logger(log_file: &Option<String>) -> Result<(), fern::InitError> {
+fn setup_logger(log_file: &Option<String>, stderr: bool) -> Result<(), fern::InitError> {
     let mut base_config = fern::Dispatch::new();
 
     base_config = base_config.level(log::LevelFilter::Info);
@@ -345,6 +1269,8 @@ fn setup_logger(log_file: &Option<String>) -> Result<(), fern::InitError> {
     if log_file.is_some() {
         let file_config = log_format.chain(fern::log_file(PathBuf::from(log_file.as_ref().unwrap()))?);
         base_config.chain(file_config).apply()?;
+    } else if stderr {
+        base_config.chain(log_format.chain(io::stderr())).apply()?;
     } else {
         let stdout_config = log_format.chain(io::stdout());
         base_config.chain(stdout_config).apply()?;
@@ -0,0 +1,220 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder, HistogramVec, IntCounterVec,
+    IntGaugeVec, TextEncoder,
+};
+use redis_event::rdb::Object;
+use redis_event::{Event, EventHandler};
+
+use crate::command;
+
+lazy_static! {
+    pub(crate) static ref RDB_OBJECTS: IntCounterVec =
+        register_int_counter_vec!("copy_redis_rdb_objects_total", "已处理的RDB对象数量", &["type"]).unwrap();
+    pub(crate) static ref AOF_COMMANDS: IntCounterVec =
+        register_int_counter_vec!("copy_redis_aof_commands_total", "已处理的AOF命令数量", &["command"]).unwrap();
+    pub(crate) static ref COMMANDS_WRITTEN: IntCounterVec = register_int_counter_vec!(
+        "copy_redis_commands_written_total",
+        "写入目的Redis成功的命令数量",
+        &["worker"]
+    )
+    .unwrap();
+    pub(crate) static ref COMMANDS_FAILED: IntCounterVec = register_int_counter_vec!(
+        "copy_redis_commands_failed_total",
+        "重试后仍写入失败的命令数量",
+        &["worker"]
+    )
+    .unwrap();
+    pub(crate) static ref FLUSH_LATENCY: HistogramVec = register_histogram_vec!(
+        "copy_redis_flush_duration_seconds",
+        "每一批命令写入目的Redis的耗时",
+        &["worker"]
+    )
+    .unwrap();
+    pub(crate) static ref QUEUE_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        "copy_redis_queue_depth",
+        "已发送至worker但还未被处理的消息数量",
+        &["worker"]
+    )
+    .unwrap();
+    pub(crate) static ref BACKPRESSURE: IntCounterVec = register_int_counter_vec!(
+        "copy_redis_backpressure_total",
+        "因worker的队列已满而阻塞的次数",
+        &["worker"]
+    )
+    .unwrap();
+    pub(crate) static ref DROPPED_COMMANDS: IntCounterVec = register_int_counter_vec!(
+        "copy_redis_dropped_commands_total",
+        "无法在目的Redis中执行或模拟而被丢弃的命令数量",
+        &["command"]
+    )
+    .unwrap();
+    pub(crate) static ref REPL_OFFSET: IntGaugeVec = register_int_gauge_vec!(
+        "copy_redis_repl_offset",
+        "已写入目的Redis的复制offset, 在每次保存PSYNC信息时更新",
+        &["source"]
+    )
+    .unwrap();
+    pub(crate) static ref MASTER_REPL_OFFSET: IntGaugeVec = register_int_gauge_vec!(
+        "copy_redis_master_repl_offset",
+        "源Redis的master_repl_offset",
+        &["source"]
+    )
+    .unwrap();
+    pub(crate) static ref REPL_LAG: IntGaugeVec = register_int_gauge_vec!(
+        "copy_redis_repl_lag_bytes",
+        "源Redis与已写入目的Redis的复制offset之差",
+        &["source"]
+    )
+    .unwrap();
+}
+
+pub(crate) fn set_repl_offset(source: &str, offset: i64) {
+    REPL_OFFSET.with_label_values(&[source]).set(offset);
+    update_lag(source);
+}
+
+fn update_lag(source: &str) {
+    let offset = REPL_OFFSET.with_label_values(&[source]).get();
+    let master_offset = MASTER_REPL_OFFSET.with_label_values(&[source]).get();
+    if offset > 0 && master_offset > 0 {
+        REPL_LAG.with_label_values(&[source]).set(master_offset - offset);
+    }
+}
+
+// 统计经过的RDB对象与AOF命令的数量, 再交由实际的handler处理
+pub(crate) struct EventCounter {
+    pub(crate) handler: Rc<RefCell<dyn EventHandler>>,
+}
+
+impl EventHandler for EventCounter {
+    fn handle(&mut self, event: Event) {
+        match &event {
+            Event::RDB(rdb) => {
+                let _type = match rdb {
+                    Object::String(_) => "string",
+                    Object::List(_) => "list",
+                    Object::Set(_) => "set",
+                    Object::SortedSet(_) => "sorted_set",
+                    Object::Hash(_) => "hash",
+                    Object::Stream(_, _) => "stream",
+                    _ => "",
+                };
+                if !_type.is_empty() {
+                    RDB_OBJECTS.with_label_values(&[_type]).inc();
+                }
+            }
+            Event::AOF(cmd) => {
+                let name = command::name(cmd).to_uppercase();
+                AOF_COMMANDS.with_label_values(&[name.as_str()]).inc();
+            }
+        }
+        self.handler.borrow_mut().handle(event);
+    }
+}
+
+// 在指定地址上提供/metrics接口
+pub(crate) fn serve(addr: &str) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(err) => panic!("metrics监听地址{}绑定失败: {}", addr, err),
+    };
+    info!("metrics地址: http://{}/metrics", addr);
+    thread::Builder::new()
+        .name("copy_redis::metrics".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(err) = respond(stream) {
+                            warn!(target: "copy_redis::metrics", "响应metrics请求失败: {}", err);
+                        }
+                    }
+                    Err(err) => error!(target: "copy_redis::metrics", "{}", err),
+                }
+            }
+        })
+        .unwrap();
+}
+
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // 读取并丢弃剩余的请求头
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    if path.split('?').next() != Some("/metrics") {
+        return stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    }
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut body) {
+        return Err(io::Error::new(io::ErrorKind::Other, err.to_string()));
+    }
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        encoder.format_type(),
+        body.len()
+    )?;
+    stream.write_all(&body)
+}
+
+// 定时通过INFO replication获取源Redis的master_repl_offset, addr为源Redis的地址, 用作指标的label
+pub(crate) fn poll_master_offset(source: String, addr: String, interval: Duration, running: Arc<AtomicBool>) {
+    thread::Builder::new()
+        .name("copy_redis::master_offset".into())
+        .spawn(move || {
+            let t_name = "copy_redis::master_offset";
+            let mut conn = None;
+            while running.load(Ordering::Relaxed) {
+                if conn.is_none() {
+                    conn = match redis::Client::open(source.as_str()).and_then(|client| client.get_connection()) {
+                        Ok(conn) => Some(conn),
+                        Err(err) => {
+                            warn!(target: t_name, "连接源Redis失败: {}", err);
+                            None
+                        }
+                    };
+                }
+                if let Some(c) = conn.as_mut() {
+                    match redis::cmd("INFO").arg("replication").query::<String>(c) {
+                        Ok(info) => {
+                            let offset = info
+                                .lines()
+                                .find(|line| line.starts_with("master_repl_offset:"))
+                                .and_then(|line| line["master_repl_offset:".len()..].trim().parse::<i64>().ok());
+                            if let Some(offset) = offset {
+                                MASTER_REPL_OFFSET.with_label_values(&[addr.as_str()]).set(offset);
+                                update_lag(&addr);
+                            }
+                        }
+                        Err(err) => {
+                            warn!(target: t_name, "获取源Redis的复制offset失败: {}", err);
+                            conn = None;
+                        }
+                    }
+                }
+                thread::sleep(interval);
+            }
+        })
+        .unwrap();
+}
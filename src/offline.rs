@@ -0,0 +1,398 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+use crate::export::crc64;
+
+const T_NAME: &'static str = "copy_redis::offline";
+const REPL_ID: &'static str = "0000000000000000000000000000000000000000";
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+// 本地模拟的master. 文件中的数据全部发送之后finished被置为true, 随后master关闭连接
+pub(crate) struct Offline {
+    pub(crate) url: String,
+    pub(crate) finished: Arc<AtomicBool>,
+}
+
+// 以本地的RDB文件作为源: 在本地启动一个模拟的master, 按照复制协议将文件内容作为全量同步的RDB发送给listener,
+// 从而复用redis-event的RDB解析, 以及与在线复制完全相同的handler
+pub(crate) fn serve_rdb(path: &str) -> Offline {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => panic!("打开RDB文件{}失败: {}", path, err),
+    };
+    let len = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+    let mut progress = Progress::new(format!("RDB文件{}", path), len);
+    serve(move |stream| send_rdb(stream, file, len, &mut progress))
+}
+
+// 以本地的AOF文件作为源: 模拟的master先发送AOF文件的RDB部分(没有时发送一个空的RDB), 再将其中的命令作为增量数据发送.
+// path也可以是Redis 7的multi-part AOF目录(或其中的manifest文件). 指定了until时, 遇到时间戳大于until的#TS注释即停止
+pub(crate) fn serve_aof(path: &str, until: Option<i64>) -> Offline {
+    let files = match aof_files(Path::new(path)) {
+        Ok(files) if !files.is_empty() => files,
+        Ok(_) => panic!("{}中没有AOF文件", path),
+        Err(err) => panic!("读取AOF文件{}失败: {}", path, err),
+    };
+    serve(move |stream| send_aof(stream, &files, until))
+}
+
+fn serve<F>(send: F) -> Offline
+where
+    F: FnOnce(&mut TcpStream) -> io::Result<()> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").expect("模拟master监听失败");
+    let url = format!("redis://{}", listener.local_addr().unwrap());
+    let finished = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&finished);
+    thread::Builder::new()
+        .name(T_NAME.into())
+        .spawn(move || {
+            let result = listener.accept().and_then(|(mut stream, _)| {
+                let mut reader = BufReader::new(stream.try_clone()?);
+                handshake(&mut reader, &mut stream)?;
+                // 丢弃listener发送的REPLCONF ACK等数据, 直至连接关闭
+                thread::spawn(move || io::copy(&mut reader, &mut io::sink()));
+                send(&mut stream)?;
+                stream.flush()?;
+                flag.store(true, Ordering::SeqCst);
+                // listener处理完已接收的数据后, 读取到连接关闭即结束
+                let _ = stream.shutdown(Shutdown::Write);
+                Ok(())
+            });
+            if let Err(err) = result {
+                error!(target: T_NAME, "发送文件失败: {}", err);
+            }
+        })
+        .unwrap();
+    Offline { url, finished }
+}
+
+fn send_rdb(stream: &mut TcpStream, rdb: impl Read, len: u64, progress: &mut Progress) -> io::Result<()> {
+    write!(stream, "+FULLRESYNC {} 0\r\n${}\r\n", REPL_ID, len)?;
+    let mut rdb = rdb.take(len);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = rdb.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        stream.write_all(&buf[..n])?;
+        progress.advance(n as u64);
+    }
+    progress.report();
+    Ok(())
+}
+
+fn send_aof(stream: &mut TcpStream, files: &[PathBuf], until: Option<i64>) -> io::Result<()> {
+    let mut total = 0;
+    for file in files {
+        total += fs::metadata(file)?.len();
+    }
+    let mut progress = Progress::new(format!("AOF文件{}", files[0].display()), total);
+    // base文件开头的RDB部分(.rdb的base文件或RDB preamble)作为全量同步的RDB发送
+    let rdb = rdb_len(&files[0])?;
+    if rdb > 0 {
+        send_rdb(stream, File::open(&files[0])?, rdb, &mut progress)?;
+    } else {
+        let empty = empty_rdb();
+        write!(stream, "+FULLRESYNC {} 0\r\n${}\r\n", REPL_ID, empty.len())?;
+        stream.write_all(&empty)?;
+    }
+    for (i, file) in files.iter().enumerate() {
+        let skip = if i == 0 { rdb } else { 0 };
+        if !send_commands(stream, file, skip, until, &mut progress)? {
+            break;
+        }
+    }
+    progress.report();
+    Ok(())
+}
+
+// 将AOF文件中的命令原样发送, 跳过#开头的注释. 到达until指定的时间点时返回false
+fn send_commands(
+    stream: &mut TcpStream, path: &Path, skip: u64, until: Option<i64>, progress: &mut Progress,
+) -> io::Result<bool> {
+    let mut file = File::open(path)?;
+    if skip >= file.metadata()?.len() {
+        return Ok(true);
+    }
+    file.seek(SeekFrom::Start(skip))?;
+    let mut reader = BufReader::new(file);
+    let mut offset = skip;
+    loop {
+        let mut cmd = Vec::new();
+        if reader.read_until(b'\n', &mut cmd)? == 0 {
+            return Ok(true);
+        }
+        if cmd.starts_with(b"#") {
+            progress.advance(cmd.len() as u64);
+            offset += cmd.len() as u64;
+            if let (Some(until), Some(ts)) = (until, timestamp(&cmd)) {
+                if ts > until {
+                    info!(target: T_NAME, "已到达指定的时间点: {}, 停止读取AOF", until);
+                    return Ok(false);
+                }
+            }
+            continue;
+        }
+        if !cmd.starts_with(b"*") {
+            let err = format!("AOF文件{}格式错误, offset: {}", path.display(), offset);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+        }
+        // AOF文件末尾的命令可能只写入了一部分(如Redis宕机时), 与Redis的aof-load-truncated相同, 忽略该命令
+        if !read_args(&mut reader, &mut cmd)? {
+            warn!(target: T_NAME, "AOF文件{}末尾的命令不完整, 已忽略", path.display());
+            return Ok(true);
+        }
+        stream.write_all(&cmd)?;
+        progress.advance(cmd.len() as u64);
+        offset += cmd.len() as u64;
+    }
+}
+
+// 读取命令的各个参数并追加至cmd, cmd中已有"*<参数个数>\r\n". 命令不完整时返回false
+fn read_args(reader: &mut impl BufRead, cmd: &mut Vec<u8>) -> io::Result<bool> {
+    if !cmd.ends_with(b"\n") {
+        return Ok(false);
+    }
+    for _ in 0..parse_len(trim(cmd))? {
+        let start = cmd.len();
+        reader.read_until(b'\n', cmd)?;
+        if !cmd.ends_with(b"\n") {
+            return Ok(false);
+        }
+        let len = parse_len(trim(&cmd[start..]))? + 2;
+        let start = cmd.len();
+        reader.by_ref().take(len as u64).read_to_end(cmd)?;
+        if cmd.len() - start < len {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// 形如"#TS:1628217470"的注释
+fn timestamp(line: &[u8]) -> Option<i64> {
+    let line = trim(line);
+    if !line.starts_with(b"#TS:") {
+        return None;
+    }
+    String::from_utf8_lossy(&line[4..]).parse().ok()
+}
+
+// multi-part AOF的manifest文件中, 每行描述一个文件, 如: file appendonly.aof.1.base.rdb seq 1 type b.
+// 依次返回base文件及所有incr文件, history文件已不再使用, 忽略
+fn aof_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let manifest = if path.is_dir() {
+        let mut manifest = None;
+        for entry in fs::read_dir(path)? {
+            let entry = entry?.path();
+            if entry.extension().map_or(false, |ext| ext == "manifest") {
+                manifest = Some(entry);
+                break;
+            }
+        }
+        match manifest {
+            Some(manifest) => manifest,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "未找到manifest文件")),
+        }
+    } else if path.extension().map_or(false, |ext| ext == "manifest") {
+        path.to_path_buf()
+    } else {
+        return Ok(vec![path.to_path_buf()]);
+    };
+    let dir = manifest.parent().unwrap_or(Path::new("."));
+    let mut base = None;
+    let mut incr: Vec<(u64, PathBuf)> = Vec::new();
+    for line in BufReader::new(File::open(&manifest)?).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let field = |name: &str| {
+            fields
+                .chunks(2)
+                .find(|pair| pair[0] == name)
+                .and_then(|pair| pair.get(1))
+        };
+        let (file, seq, kind) = match (field("file"), field("seq"), field("type")) {
+            (Some(file), Some(seq), Some(kind)) => (dir.join(file.trim_matches('"')), seq.parse().unwrap_or(0), *kind),
+            _ => continue,
+        };
+        match kind {
+            "b" => base = Some(file),
+            "i" => incr.push((seq, file)),
+            _ => {}
+        }
+    }
+    incr.sort_by_key(|(seq, _)| *seq);
+    Ok(base.into_iter().chain(incr.into_iter().map(|(_, file)| file)).collect())
+}
+
+// 文件开头RDB部分的长度: .rdb文件为整个文件, 带有RDB preamble的AOF文件为RDB的结束位置, 否则为0.
+// RDB以0xFF及8字节的CRC64校验和结束, 校验和与此前所有数据的CRC64相同的位置即为RDB的结束位置.
+// 关闭了rdbchecksum时校验和为0, 此时以其后紧接着AOF命令(或文件结束)为准
+fn rdb_len(path: &Path) -> io::Result<u64> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if !reader.fill_buf()?.starts_with(b"REDIS") {
+        return Ok(0);
+    }
+    if path.extension().map_or(false, |ext| ext == "rdb") {
+        return fs::metadata(path).map(|metadata| metadata.len());
+    }
+    let mut crc = 0u64;
+    // 以0xFF结束且尚未读取完校验和的位置: (RDB的长度, 到该位置为止的CRC64, 已读取的校验和)
+    let mut candidates: Vec<(u64, u64, Vec<u8>)> = Vec::new();
+    let mut zero_checksum = None;
+    let mut pos = 0u64;
+    for byte in reader.bytes() {
+        let byte = byte?;
+        if zero_checksum == Some(pos) && byte == b'*' {
+            return Ok(pos);
+        }
+        for (len, expected, checksum) in candidates.iter_mut() {
+            checksum.push(byte);
+            if checksum.len() == 8 {
+                let checksum = u64::from_le_bytes([
+                    checksum[0],
+                    checksum[1],
+                    checksum[2],
+                    checksum[3],
+                    checksum[4],
+                    checksum[5],
+                    checksum[6],
+                    checksum[7],
+                ]);
+                if checksum == *expected {
+                    return Ok(*len);
+                }
+                if checksum == 0 {
+                    zero_checksum = Some(*len);
+                }
+            }
+        }
+        candidates.retain(|(_, _, checksum)| checksum.len() < 8);
+        crc = crc64(crc, &[byte]);
+        pos += 1;
+        if byte == 0xFF {
+            candidates.push((pos + 8, crc, Vec::with_capacity(8)));
+        }
+    }
+    if zero_checksum == Some(pos) {
+        return Ok(pos);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "无法确定RDB preamble的结束位置",
+    ))
+}
+
+// 不包含任何key的RDB
+fn empty_rdb() -> Vec<u8> {
+    let mut rdb = b"REDIS0009\xFF".to_vec();
+    let crc = crc64(0, &rdb);
+    rdb.extend_from_slice(&crc.to_le_bytes());
+    rdb
+}
+
+struct Progress {
+    name: String,
+    total: u64,
+    read: u64,
+    reported: Instant,
+}
+
+impl Progress {
+    fn new(name: String, total: u64) -> Progress {
+        Progress {
+            name,
+            total,
+            read: 0,
+            reported: Instant::now(),
+        }
+    }
+
+    fn advance(&mut self, n: u64) {
+        self.read += n;
+        if self.reported.elapsed() >= PROGRESS_INTERVAL {
+            self.report();
+            self.reported = Instant::now();
+        }
+    }
+
+    fn report(&self) {
+        let percent = if self.total > 0 {
+            self.read as f64 * 100.0 / self.total as f64
+        } else {
+            100.0
+        };
+        info!(
+            target: T_NAME,
+            "{}已读取: {}/{} bytes({:.1}%)", self.name, self.read, self.total, percent
+        );
+    }
+}
+
+// 响应listener在PSYNC之前发送的AUTH、REPLCONF等命令, 直至接收到PSYNC
+fn handshake(reader: &mut BufReader<TcpStream>, stream: &mut TcpStream) -> io::Result<()> {
+    loop {
+        let args = read_command(reader)?;
+        let name = args.get(0).map(|name| name.to_ascii_uppercase()).unwrap_or_default();
+        match name.as_slice() {
+            b"PSYNC" | b"SYNC" => return Ok(()),
+            b"PING" => stream.write_all(b"+PONG\r\n")?,
+            _ => stream.write_all(b"+OK\r\n")?,
+        }
+    }
+}
+
+// 读取一条RESP格式(或inline格式)的命令
+pub(crate) fn read_command(reader: &mut BufReader<TcpStream>) -> io::Result<Vec<Vec<u8>>> {
+    let line = read_line(reader)?;
+    if !line.starts_with(b"*") {
+        return Ok(line
+            .split(|b| *b == b' ')
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect());
+    }
+    let mut args = Vec::new();
+    for _ in 0..parse_len(&line)? {
+        let line = read_line(reader)?;
+        let mut arg = vec![0; parse_len(&line)? + 2];
+        reader.read_exact(&mut arg)?;
+        arg.truncate(arg.len() - 2);
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+pub(crate) fn read_line(reader: &mut BufReader<TcpStream>) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "连接已关闭"));
+    }
+    Ok(trim(&line).to_vec())
+}
+
+fn trim(mut line: &[u8]) -> &[u8] {
+    while line.ends_with(b"\n") || line.ends_with(b"\r") {
+        line = &line[..line.len() - 1];
+    }
+    line
+}
+
+// 形如"*3"或"$5"的长度
+fn parse_len(line: &[u8]) -> io::Result<usize> {
+    String::from_utf8_lossy(line.get(1..).unwrap_or_default())
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "无效的RESP长度"))
+}
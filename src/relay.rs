@@ -0,0 +1,243 @@
+use std::io;
+use std::io::{BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{info, warn};
+
+use crate::offline::{read_command, read_line};
+
+const T_NAME: &'static str = "copy_redis::relay";
+
+// 在listener与源Redis之间转发复制连接上的数据. redis-event只在listener停止之后才会更新config中的repl id与offset,
+// 因此由relay从PSYNC的响应中获取repl id, 从listener定时发送的REPLCONF ACK中获取其已处理的offset,
+// 从而不需要中断复制连接也能得知复制的进度. TLS连接中的数据无法解析, 因此不使用relay
+pub(crate) struct Relay {
+    // listener所连接的本地地址
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    upstream: Arc<Mutex<String>>,
+    progress: Progress,
+}
+
+// listener在当前连接上最近一次确认的repl id与offset, 建立新的连接时offset重置为-1
+#[derive(Clone)]
+pub(crate) struct Progress {
+    repl_id: Arc<Mutex<String>>,
+    pub(crate) offset: Arc<AtomicI64>,
+}
+
+impl Progress {
+    pub(crate) fn new() -> Progress {
+        Progress {
+            repl_id: Arc::new(Mutex::new("?".to_string())),
+            offset: Arc::new(AtomicI64::new(-1)),
+        }
+    }
+
+    // 还未接收到REPLCONF ACK时返回None
+    pub(crate) fn acked(&self) -> Option<(String, i64)> {
+        let offset = self.offset.load(Ordering::SeqCst);
+        if offset < 0 {
+            None
+        } else {
+            Some((self.repl_id.lock().unwrap().clone(), offset))
+        }
+    }
+}
+
+impl Relay {
+    // upstream为源Redis的host:port
+    pub(crate) fn start(upstream: &str) -> Relay {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("relay监听失败");
+        let addr = listener.local_addr().unwrap();
+        let relay = Relay {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            upstream: Arc::new(Mutex::new(upstream.to_string())),
+            progress: Progress::new(),
+        };
+        let upstream = Arc::clone(&relay.upstream);
+        let progress = relay.progress.clone();
+        thread::Builder::new()
+            .name(T_NAME.into())
+            .spawn(move || {
+                // listener每次(重新)连接时都会建立新的连接, 同一时刻只有一个连接
+                for client in listener.incoming() {
+                    progress.offset.store(-1, Ordering::SeqCst);
+                    let upstream = upstream.lock().unwrap().clone();
+                    if let Err(err) = client.and_then(|client| forward(client, &upstream, &progress)) {
+                        warn!(target: T_NAME, "转发源Redis({})的复制连接中断: {}", upstream, err);
+                    }
+                }
+            })
+            .unwrap();
+        relay
+    }
+
+    // 源Redis发生故障转移后, 之后的连接转发至新的master
+    pub(crate) fn retarget(&self, upstream: &str) {
+        info!(target: T_NAME, "复制连接将转发至: {}", upstream);
+        *self.upstream.lock().unwrap() = upstream.to_string();
+    }
+
+    pub(crate) fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+}
+
+// 任意一个方向的连接关闭时, 同时关闭另一个方向, 使listener能及时重新连接
+fn forward(client: TcpStream, upstream: &str, progress: &Progress) -> io::Result<()> {
+    let master = TcpStream::connect(upstream)?;
+    let mut reader = BufReader::new(master.try_clone()?);
+    let mut writer = client.try_clone()?;
+    let shared = progress.clone();
+    let downstream = thread::Builder::new().name(T_NAME.into()).spawn(move || {
+        let result = forward_replies(&mut reader, &mut writer, &shared);
+        let _ = writer.shutdown(Shutdown::Both);
+        result
+    })?;
+    let mut reader = BufReader::new(client);
+    let mut writer = master;
+    let result = forward_commands(&mut reader, &mut writer, progress);
+    let _ = writer.shutdown(Shutdown::Both);
+    let downstream = downstream.join().unwrap_or(Ok(()));
+    result.or(downstream)
+}
+
+// listener → 源Redis: 记录PSYNC所使用的repl id, 以及REPLCONF ACK中的offset
+fn forward_commands(reader: &mut BufReader<TcpStream>, writer: &mut TcpStream, progress: &Progress) -> io::Result<()> {
+    loop {
+        let args = match read_command(reader) {
+            Ok(args) => args,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let name = args.get(0).map(|name| name.to_ascii_uppercase()).unwrap_or_default();
+        match (name.as_slice(), args.get(1), args.get(2)) {
+            (b"PSYNC", Some(id), _) => *progress.repl_id.lock().unwrap() = String::from_utf8_lossy(id).into_owned(),
+            (b"REPLCONF", Some(sub), Some(offset)) if sub.eq_ignore_ascii_case(b"ACK") => {
+                if let Ok(offset) = String::from_utf8_lossy(offset).parse::<i64>() {
+                    progress.offset.store(offset, Ordering::SeqCst);
+                }
+            }
+            _ => {}
+        }
+        writer.write_all(&encode(&args))?;
+    }
+}
+
+// 源Redis → listener: 从+FULLRESYNC <id> <offset>或+CONTINUE [<id>]中获取repl id, 之后的数据原样转发
+fn forward_replies(reader: &mut BufReader<TcpStream>, writer: &mut TcpStream, progress: &Progress) -> io::Result<()> {
+    loop {
+        let line = read_line(reader)?;
+        writer.write_all(&line)?;
+        writer.write_all(b"\r\n")?;
+        let fields: Vec<String> = String::from_utf8_lossy(&line)
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+        match fields.get(0).map(String::as_str) {
+            Some("+FULLRESYNC") | Some("+CONTINUE") => {
+                // 没有repl id的+CONTINUE表示沿用PSYNC时的repl id
+                if let Some(id) = fields.get(1) {
+                    *progress.repl_id.lock().unwrap() = id.clone();
+                }
+                break;
+            }
+            _ => {}
+        }
+    }
+    io::copy(reader, writer)?;
+    Ok(())
+}
+
+fn encode(args: &[Vec<u8>]) -> Vec<u8> {
+    let mut packed = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        packed.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        packed.extend_from_slice(arg);
+        packed.extend_from_slice(b"\r\n");
+    }
+    packed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::relay::{Progress, Relay};
+
+    // 模拟的master: 以reply响应PSYNC, 之后发送data
+    fn master(reply: &'static str, data: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.starts_with("PSYNC") {
+                    break;
+                }
+            }
+            stream.write_all(reply.as_bytes()).unwrap();
+            stream.write_all(data).unwrap();
+            // 读取REPLCONF ACK, 直至连接关闭
+            let _ = reader.read_to_end(&mut Vec::new());
+        });
+        addr
+    }
+
+    fn wait_acked(progress: &Progress) -> Option<(String, i64)> {
+        let start = Instant::now();
+        while progress.acked().is_none() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        progress.acked()
+    }
+
+    fn replicate(relay: &Relay, psync: &str, len: usize) -> Vec<u8> {
+        let mut stream = TcpStream::connect((relay.host.as_str(), relay.port)).unwrap();
+        stream.write_all(psync.as_bytes()).unwrap();
+        let mut received = vec![0; len];
+        stream.read_exact(&mut received).unwrap();
+        stream
+            .write_all(b"*3\r\n$8\r\nREPLCONF\r\n$3\r\nACK\r\n$3\r\n150\r\n")
+            .unwrap();
+        received
+    }
+
+    #[test]
+    fn test_continue() {
+        let reply = "+CONTINUE 8de1787ba490483314a4d30f1c628bc5025eb761\r\n";
+        let relay = Relay::start(&master(reply, b"*1\r\n$4\r\nPING\r\n"));
+        let progress = relay.progress();
+        assert_eq!(progress.acked(), None);
+
+        let received = replicate(&relay, "PSYNC 0000 100\r\n", reply.len() + 14);
+        assert_eq!(received, [reply.as_bytes(), b"*1\r\n$4\r\nPING\r\n"].concat());
+        assert_eq!(
+            wait_acked(&progress),
+            Some(("8de1787ba490483314a4d30f1c628bc5025eb761".to_string(), 150))
+        );
+    }
+
+    #[test]
+    fn test_retarget() {
+        let relay = Relay::start(&master("+FULLRESYNC old 0\r\n", b""));
+        relay.retarget(&master("+CONTINUE\r\n", b""));
+        let progress = relay.progress();
+
+        // 没有repl id的+CONTINUE沿用PSYNC时的repl id
+        let received = replicate(&relay, "PSYNC 0000 100\r\n", 11);
+        assert_eq!(received, b"+CONTINUE\r\n");
+        assert_eq!(wait_acked(&progress), Some(("0000".to_string(), 150)));
+    }
+}
@@ -0,0 +1,51 @@
+use regex::bytes::Regex;
+
+// 对写入目的Redis的key进行重命名: 先进行正则替换(只替换第一处匹配), 再添加前缀.
+// 过滤条件始终作用于源Redis中的key名称
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Renamer {
+    regex: Option<(Regex, Vec<u8>)>,
+    prefix: Option<Vec<u8>>,
+}
+
+impl Renamer {
+    pub(crate) fn new(prefix: Option<String>, regex: Option<String>, replacement: Option<String>) -> Renamer {
+        let regex = match (regex, replacement) {
+            (None, None) => None,
+            (Some(regex), Some(replacement)) => match Regex::new(&regex) {
+                Ok(regex) => Some((regex, replacement.into_bytes())),
+                Err(err) => panic!("无效的正则表达式 {}: {}", regex, err),
+            },
+            _ => panic!("rename-regex与rename-replacement需同时指定"),
+        };
+        Renamer {
+            regex,
+            prefix: prefix.filter(|prefix| !prefix.is_empty()).map(String::into_bytes),
+        }
+    }
+
+    pub(crate) fn rename(&self, key: &[u8]) -> Vec<u8> {
+        let mut new_key = match &self.regex {
+            None => key.to_vec(),
+            Some((regex, replacement)) => regex.replace(key, replacement.as_slice()).into_owned(),
+        };
+        if let Some(prefix) = &self.prefix {
+            new_key.splice(0..0, prefix.iter().cloned());
+        }
+        new_key
+    }
+
+    // 目的Redis中的key是否可能由重命名产生: 需以前缀开头, 且去掉前缀之后的原始key满足accept.
+    // 正则替换无法还原出原始的key, 此时只检查前缀
+    pub(crate) fn may_produce<F>(&self, key: &[u8], accept: F) -> bool
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let key = match &self.prefix {
+            None => key,
+            Some(prefix) if key.starts_with(prefix) => &key[prefix.len()..],
+            Some(_) => return false,
+        };
+        self.regex.is_some() || accept(key)
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use log::{error, info, warn};
+
+use crate::checkpoint::Flush;
+use crate::cluster;
+use crate::command::CommandConverter;
+use crate::filter::Filter;
+use crate::handler;
+use crate::rename::Renamer;
+use crate::sharding;
+use crate::source::SourceReader;
+use crate::verify;
+use crate::verify::Targets;
+use crate::worker::RetryConfig;
+use crate::{Abort, Opt};
+
+// 修复一个不一致的key所需的操作
+pub(crate) enum Action {
+    // 从源Redis重新复制该key, db与key均为源Redis中的
+    Copy { source: String, db: i64, key: Vec<u8> },
+    // 从目的Redis的该节点中删除该key, db与key均为目的Redis中的
+    Delete { target: String, db: i64, key: Vec<u8> },
+}
+
+// copy-redis repair: 只重新复制不一致的key. 需要修复的key来自--repair-file, 未指定时先进行一次校验.
+// 复制的key以DUMP/RESTORE ... REPLACE的方式, 经由与复制时相同的handler(普通/Sharding/Cluster模式)写入目的Redis.
+// 多余的key只有指定了--repair-delete时才删除
+pub(crate) fn run(opt: &Opt) -> bool {
+    let actions = match &opt.repair_file {
+        Some(path) => match read_repair_file(path) {
+            Ok(actions) => actions,
+            Err(err) => {
+                error!("读取修复命令失败: {}", err);
+                return false;
+            }
+        },
+        None => verify::diff(opt)
+            .0
+            .iter()
+            .map(|divergence| divergence.action())
+            .collect(),
+    };
+    info!("需要修复的key: {}", actions.len());
+    let filter = Filter::new(
+        &opt.include,
+        &opt.exclude,
+        &opt.include_regex,
+        &opt.exclude_regex,
+        opt.dbs.clone(),
+        opt.db_mapping.clone(),
+    );
+    let renamer = Renamer::new(
+        opt.key_prefix.clone(),
+        opt.rename_regex.clone(),
+        opt.rename_replacement.clone(),
+    );
+    let retry = RetryConfig {
+        max_retries: opt.max_retries,
+        interval: opt.retry_interval,
+        dead_letter_dir: opt.dead_letter.clone(),
+    };
+    // 删除多余的key时, 用于确认key位于复制范围之内
+    let scope = (filter.clone(), renamer.clone());
+    let running = Arc::new(AtomicBool::new(true));
+    // worker异常退出时, 之后的key不再写入, 修复结果为失败
+    let abort = Abort::new(&running);
+    let source = opt.sources.get(0).map(String::as_str);
+    if opt.sharding && opt.cluster {
+        panic!("不能同时指定sharding与cluster")
+    }
+    if opt.sharding {
+        let workers = sharding::new_workers(
+            opt.targets.clone(),
+            opt.batch_size,
+            opt.flush_interval,
+            opt.queue_size,
+            retry,
+            running,
+        );
+        let handler = sharding::new_sharded(
+            source,
+            &workers.senders,
+            &opt.targets,
+            filter,
+            renamer,
+            opt.chunk_size,
+            None,
+            None,
+            opt.shard_strategy,
+            opt.shard_hash,
+            opt.shard_key_tag.clone(),
+            false,
+            abort.clone(),
+        );
+        apply(opt, handler, &actions, &scope) && !abort.is_aborted()
+    } else if opt.cluster {
+        let workers = cluster::new_workers(
+            opt.targets.clone(),
+            opt.batch_size,
+            opt.flush_interval,
+            opt.queue_size,
+            retry,
+            running,
+        );
+        let handler = cluster::new_cluster(
+            source,
+            &workers.senders,
+            filter,
+            renamer,
+            opt.chunk_size,
+            None,
+            None,
+            abort.clone(),
+        );
+        apply(opt, handler, &actions, &scope) && !abort.is_aborted()
+    } else {
+        let workers = handler::new_workers(
+            opt.targets.get(0).unwrap().to_string(),
+            opt.batch_size,
+            opt.flush_interval,
+            opt.queue_size,
+            retry,
+            running,
+        );
+        let handler = handler::new(
+            source,
+            &workers.senders,
+            filter,
+            renamer,
+            opt.chunk_size,
+            None,
+            None,
+            abort.clone(),
+        );
+        apply(opt, handler, &actions, &scope) && !abort.is_aborted()
+    }
+}
+
+fn apply<H: CommandConverter + Flush>(
+    opt: &Opt, mut handler: H, actions: &[Action], (filter, renamer): &(Filter, Renamer),
+) -> bool {
+    let mut sources: HashMap<String, SourceReader> = verify::sources(opt)
+        .into_iter()
+        .map(|url| (verify::addr(&url), SourceReader::new(Some(&url))))
+        .collect();
+    let mut targets = Targets::new(opt);
+    let (mut copied, mut deleted, mut skipped, mut failed) = (0, 0, 0, 0);
+    for action in actions {
+        match action {
+            Action::Copy { source, db, key } => {
+                let reader = match sources.get_mut(source) {
+                    Some(reader) => reader,
+                    None => {
+                        error!("源Redis({})未通过--source指定, 无法修复key: {}", source, escape(key));
+                        failed += 1;
+                        continue;
+                    }
+                };
+                if !handler.select_db(*db) || !handler.filter().accept(key) {
+                    continue;
+                }
+                let new_key = handler.rename(key);
+                match reader.restore(*db, &[(key.as_slice(), new_key)]) {
+                    Ok(cmds) => {
+                        for cmd in cmds {
+                            handler.execute(cmd, None);
+                        }
+                        copied += 1;
+                    }
+                    Err(err) => {
+                        error!("从源Redis({})读取key失败: {}, key: {}", source, err, escape(key));
+                        failed += 1;
+                    }
+                }
+            }
+            Action::Delete { target, db, key } => {
+                if !opt.repair_delete {
+                    skipped += 1;
+                    continue;
+                }
+                // 修复文件可能被手动编辑过, 因此再次确认key位于复制范围之内
+                if !filter.accept_target_db(*db) || !renamer.may_produce(key, |origin| filter.accept(origin)) {
+                    warn!(
+                        "key不在复制范围之内, 未删除, 目的: {}, db: {}, key: {}",
+                        target,
+                        db,
+                        escape(key)
+                    );
+                    skipped += 1;
+                    continue;
+                }
+                let result = targets
+                    .connection(target, *db)
+                    .and_then(|conn| redis::cmd("DEL").arg(key.as_slice()).query::<()>(conn));
+                match result {
+                    Ok(_) => deleted += 1,
+                    Err(err) => {
+                        error!("从目的Redis({})删除key失败: {}, key: {}", target, err, escape(key));
+                        failed += 1;
+                    }
+                }
+            }
+        }
+    }
+    // 等待复制的key全部写入目的Redis
+    handler.flush();
+    if skipped > 0 && !opt.repair_delete {
+        warn!("目的Redis中多余的key: {}, 指定--repair-delete时才会删除", skipped);
+    }
+    info!(
+        "修复完成, 已复制: {}, 已删除: {}, 未删除: {}, 失败: {}",
+        copied, deleted, skipped, failed
+    );
+    failed == 0
+}
+
+// 每行一条修复命令: "copy <源地址> <db> <key>"表示从源Redis重新复制该key,
+// "delete <目的地址> <db> <key>"表示从目的Redis中删除该key. key经过escape处理
+pub(crate) fn write_repair_file(path: &str, actions: &[Action]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for action in actions {
+        let (name, addr, db, key) = match action {
+            Action::Copy { source, db, key } => ("copy", source, db, key),
+            Action::Delete { target, db, key } => ("delete", target, db, key),
+        };
+        writeln!(file, "{} {} {} {}", name, addr, db, escape(key))?;
+    }
+    file.flush()
+}
+
+fn read_repair_file(path: &str) -> io::Result<Vec<Action>> {
+    let mut actions = Vec::new();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_action(&line) {
+            Some(action) => actions.push(action),
+            None => {
+                let err = format!("第{}行格式错误: {}", i + 1, line);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+        }
+    }
+    Ok(actions)
+}
+
+fn parse_action(line: &str) -> Option<Action> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 4 {
+        return None;
+    }
+    let addr = fields[1].to_string();
+    let db = fields[2].parse().ok()?;
+    let key = unescape(fields[3])?;
+    match fields[0] {
+        "copy" => Some(Action::Copy { source: addr, db, key }),
+        "delete" => Some(Action::Delete { target: addr, db, key }),
+        _ => None,
+    }
+}
+
+// 不可见字符、空格及'\'以\xHH表示
+fn escape(key: &[u8]) -> String {
+    let mut escaped = String::with_capacity(key.len());
+    for byte in key {
+        match byte {
+            b'!'..=b'~' if *byte != b'\\' => escaped.push(*byte as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+fn unescape(escaped: &str) -> Option<Vec<u8>> {
+    let bytes = escaped.as_bytes();
+    let mut key = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            if bytes.get(i + 1) != Some(&b'x') {
+                return None;
+            }
+            // from_str_radix允许"+f"这样带符号的输入
+            let hex = bytes.get(i + 2..i + 4)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            key.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 4;
+        } else {
+            key.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_round_trip() {
+        let all: Vec<u8> = (0..=255).collect();
+        let keys: [&[u8]; 10] = [
+            b"",
+            b"user:1",
+            b"a\tb",
+            b"\t\n",
+            b"\\",
+            b"\xff",
+            b"\\x41",
+            b"a b",
+            &[0xff, 0x00],
+            &all,
+        ];
+        for key in keys.iter() {
+            let escaped = escape(key);
+            // 转义之后不含空白及不可见字符, 可以按行及空白分隔
+            assert!(escaped.bytes().all(|byte| byte.is_ascii_graphic()));
+            assert_eq!(unescape(&escaped), Some(key.to_vec()));
+        }
+        assert_eq!(escape(b"a\tb\\"), "a\\x09b\\x5c");
+    }
+
+    #[test]
+    fn test_unescape_malformed() {
+        for escaped in &["\\", "a\\", "\\t", "\\x", "\\x4", "\\xzz", "\\x+f", "\\x-1", "\\xé"] {
+            assert_eq!(unescape(escaped), None, "{}", escaped);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use log::{error, info, warn};
+use redis::{Connection, RedisResult};
+
+use crate::filter::Filter;
+use crate::rename::Renamer;
+use crate::sharding;
+use crate::sharding::Layout;
+use crate::verify;
+
+// --reshard-delete: 源Redis的复制结束后, 删除源Redis中已迁移至其他分片的key.
+// 只有目的分片中的值与源Redis一致(与verify相同, 按类型比较值的摘要及过期时间)时才删除, 否则保留并输出警告
+pub(crate) fn delete_moved(source: &str, layout: &Layout, filter: &Filter, renamer: &Renamer) {
+    let (host, port) = sharding::host_port(source);
+    let addr = format!("{}:{}", host, port);
+    // 源Redis不在布局中时无法判断哪些key已迁移, 不能删除
+    let local = match layout.locate(source) {
+        Some(local) => local,
+        None => {
+            error!("源Redis({})不是目的Redis中的任何一个分片, 不删除已迁移的key", addr);
+            return;
+        }
+    };
+    info!("开始删除源Redis({})中已迁移至其他分片的key", addr);
+    match scan_and_delete(source, local, layout, filter, renamer) {
+        Ok((deleted, mismatched)) => {
+            info!(
+                "源Redis({})中已迁移的key删除完成, 已删除: {}, 校验不一致而保留: {}",
+                addr, deleted, mismatched
+            );
+        }
+        Err(err) => error!("删除源Redis({})中已迁移的key失败: {}", addr, err),
+    }
+}
+
+fn scan_and_delete(
+    source: &str, local: usize, layout: &Layout, filter: &Filter, renamer: &Renamer,
+) -> RedisResult<(u64, u64)> {
+    let mut conn = redis::Client::open(source)?.get_connection()?;
+    let (mut deleted, mut mismatched) = (0, 0);
+    for db in keyspace(&mut conn)? {
+        if !filter.accept_db(db) {
+            continue;
+        }
+        redis::cmd("SELECT").arg(db).query::<()>(&mut conn)?;
+        // 各个目的分片的连接, 已切换至源db所映射的db
+        let mut targets: HashMap<usize, Connection> = HashMap::new();
+        let mut cursor = 0u64;
+        loop {
+            let (next, keys): (u64, Vec<Vec<u8>>) =
+                redis::cmd("SCAN").arg(cursor).arg("COUNT").arg(1000).query(&mut conn)?;
+            for key in keys {
+                if !filter.accept(&key) {
+                    continue;
+                }
+                let new_key = renamer.rename(&key);
+                let owner = layout.owner(&new_key);
+                if owner == local {
+                    continue;
+                }
+                if !targets.contains_key(&owner) {
+                    let mut target = redis::Client::open(layout.url(owner))?.get_connection()?;
+                    redis::cmd("SELECT").arg(filter.map_db(db)).query::<()>(&mut target)?;
+                    targets.insert(owner, target);
+                }
+                let target = targets.get_mut(&owner).unwrap();
+                // 源与目的Redis的编码方式可能不同, 因此不能直接比较DUMP的结果
+                let value = verify::snapshot(&mut conn, &key, false)?;
+                let copied = verify::snapshot(target, &new_key, false)?;
+                match (value, copied) {
+                    (Some(value), Some(copied)) if value.matches(&copied) => {
+                        redis::cmd("DEL").arg(&key).query::<()>(&mut conn)?;
+                        deleted += 1;
+                    }
+                    // 源key已被删除或过期
+                    (None, _) => {}
+                    _ => {
+                        warn!(
+                            "key {}在目的分片{}中的值与源Redis不一致, 未删除",
+                            String::from_utf8_lossy(&key),
+                            layout.addr(owner)
+                        );
+                        mismatched += 1;
+                    }
+                }
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+    }
+    Ok((deleted, mismatched))
+}
+
+// INFO keyspace中存在key的db, 形如: db0:keys=1,expires=0,avg_ttl=0
+pub(crate) fn keyspace(conn: &mut Connection) -> RedisResult<Vec<i64>> {
+    let info: String = redis::cmd("INFO").arg("keyspace").query(conn)?;
+    Ok(info
+        .lines()
+        .filter(|line| line.starts_with("db"))
+        .filter_map(|line| line[2..].split(':').next()?.parse().ok())
+        .collect())
+}
@@ -0,0 +1,190 @@
+use log::{info, warn};
+use redis::Cmd;
+use redis_event::rdb::{ExpireType, Object};
+
+use crate::export;
+use crate::export::crc64;
+
+// --restore: RDB阶段将每个对象重新序列化为DUMP格式, 以RESTORE key ttl payload REPLACE [ABSTTL]写入目的Redis,
+// 无需逐个元素重建, 大key的写入更快. 目的Redis的RDB版本不支持该对象的类型时(如低于5.0的Redis不支持stream),
+// 仍以命令的方式写入
+pub(crate) struct Restore {
+    // 目的Redis可以加载的RDB版本, 多个目的Redis时取最低的
+    version: u16,
+}
+
+impl Restore {
+    pub(crate) fn new(targets: &[String]) -> Restore {
+        let mut version = export::RDB_VERSION;
+        for target in targets {
+            let redis_version = match redis_version(target) {
+                Ok(redis_version) => redis_version,
+                Err(err) => panic!("获取目的Redis({})的版本失败: {}", target, err),
+            };
+            version = version.min(rdb_version(&redis_version));
+        }
+        info!("以RESTORE的方式写入RDB数据, DUMP版本: {}", version);
+        if version < 9 {
+            warn!("目的Redis低于5.0, stream仍以命令的方式写入");
+        }
+        Restore { version }
+    }
+
+    // 返回None时, 该对象以命令的方式写入
+    pub(crate) fn command(&self, key: &[u8], object: &Object) -> Option<Cmd> {
+        let expire = match object {
+            Object::String(kv) => &kv.meta.expire,
+            Object::List(list) => &list.meta.expire,
+            Object::Set(set) => &set.meta.expire,
+            Object::SortedSet(sorted_set) => &sorted_set.meta.expire,
+            Object::Hash(hash) => &hash.meta.expire,
+            Object::Stream(_, stream) if self.version >= 9 => &stream.meta.expire,
+            _ => return None,
+        };
+        let payload = self.payload(object)?;
+        let expire = expire.as_ref().map(|(expire_type, ttl)| match expire_type {
+            ExpireType::Second => *ttl * 1000,
+            ExpireType::Millisecond => *ttl,
+        });
+        let mut cmd = redis::cmd("RESTORE");
+        cmd.arg(key);
+        // ABSTTL从5.0开始支持, 更早的版本使用剩余的过期时间, 已过期的key不再写入
+        match expire {
+            None => {
+                cmd.arg(0).arg(payload).arg("REPLACE");
+            }
+            Some(expire) if self.version >= 9 => {
+                cmd.arg(expire).arg(payload).arg("REPLACE").arg("ABSTTL");
+            }
+            Some(expire) => {
+                let ttl = expire - chrono::Local::now().timestamp_millis();
+                if ttl <= 0 {
+                    cmd = redis::cmd("DEL");
+                    cmd.arg(key);
+                } else {
+                    cmd.arg(ttl).arg(payload).arg("REPLACE");
+                }
+            }
+        }
+        Some(cmd)
+    }
+
+    // 与DUMP相同的格式: 对象的类型及值, 2字节的RDB版本, 8字节的CRC64
+    fn payload(&self, object: &Object) -> Option<Vec<u8>> {
+        let (rdb_type, value) = export::encode_value(object, self.version, None)?;
+        let mut payload = Vec::with_capacity(value.len() + 11);
+        payload.push(rdb_type);
+        payload.extend_from_slice(&value);
+        payload.extend_from_slice(&self.version.to_le_bytes());
+        let crc = crc64(0, &payload);
+        payload.extend_from_slice(&crc.to_le_bytes());
+        Some(payload)
+    }
+}
+
+pub(crate) fn redis_version(url: &str) -> redis::RedisResult<String> {
+    let mut conn = redis::Client::open(url)?.get_connection()?;
+    let info: String = redis::cmd("INFO").arg("server").query(&mut conn)?;
+    Ok(info
+        .lines()
+        .find_map(|line| line.strip_prefix("redis_version:"))
+        .unwrap_or_default()
+        .trim()
+        .to_string())
+}
+
+// Redis版本对应的RDB版本, 只区分与DUMP格式相关的版本
+pub(crate) fn rdb_version(redis_version: &str) -> u16 {
+    let mut parts = redis_version.split('.').map(|part| part.parse::<u32>().unwrap_or(0));
+    let major = parts.next().unwrap_or(0);
+    let minor = parts.next().unwrap_or(0);
+    match (major, minor) {
+        (major, _) if major >= 7 => 10,
+        (major, _) if major >= 5 => 9,
+        (4, _) => 8,
+        (3, minor) if minor >= 2 => 7,
+        _ => 6,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis_event::rdb::{Field, Hash, KeyValue, List, Meta};
+
+    use super::*;
+
+    // Redis文档中DUMP的示例: SET mykey 10
+    const DUMP_INT: &[u8] = b"\x00\xc0\n\t\x00\xbem\x06\x89Z(\x00\n";
+    // 与Redis 5.0对SET key hello执行DUMP的结果相同
+    const DUMP_STRING: &[u8] = &[
+        0x00, 0x05, 0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x09, 0x00, 0xB3, 0x80, 0x8E, 0xBA, 0x31, 0xB2, 0x43, 0xBB,
+    ];
+    const DUMP_STRING_V6: &[u8] = &[
+        0x00, 0x05, 0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x06, 0x00, 0xF5, 0x9F, 0xB7, 0xF6, 0x90, 0x61, 0x1C, 0x99,
+    ];
+    // Redis对list及hash的DUMP使用quicklist/ziplist编码, 以下为RESTORE同样可以加载的普通编码(类型1及4)
+    const DUMP_LIST: &[u8] = &[
+        0x01, 0x03, 0x01, 0x61, 0x02, 0x62, 0x62, 0x03, 0x63, 0x63, 0x63, 0x09, 0x00, 0xED, 0x92, 0xDE, 0x48, 0x83,
+        0xFE, 0x4D, 0x69,
+    ];
+    const DUMP_HASH: &[u8] = &[
+        0x04, 0x02, 0x02, 0x66, 0x31, 0x02, 0x76, 0x31, 0x02, 0x66, 0x32, 0x02, 0x76, 0x32, 0x09, 0x00, 0xCE, 0x21,
+        0x87, 0x89, 0x99, 0x3C, 0x06, 0x6F,
+    ];
+
+    fn meta() -> Meta {
+        Meta {
+            db: 0,
+            expire: None,
+            evict: None,
+        }
+    }
+
+    #[test]
+    fn test_checksum() {
+        let (payload, crc) = DUMP_INT.split_at(DUMP_INT.len() - 8);
+        assert_eq!(&payload[payload.len() - 2..], &9u16.to_le_bytes());
+        assert_eq!(crc, &crc64(0, payload).to_le_bytes());
+    }
+
+    #[test]
+    fn test_payload() {
+        let meta = meta();
+        let restore = Restore { version: 9 };
+        let string = Object::String(KeyValue {
+            key: b"key",
+            value: b"hello",
+            meta: &meta,
+        });
+        assert_eq!(restore.payload(&string).unwrap(), DUMP_STRING);
+
+        let values = vec![b"a".to_vec(), b"bb".to_vec(), b"ccc".to_vec()];
+        let list = Object::List(List {
+            key: b"key",
+            values: &values,
+            meta: &meta,
+        });
+        assert_eq!(restore.payload(&list).unwrap(), DUMP_LIST);
+
+        let fields = vec![
+            Field {
+                name: b"f1".to_vec(),
+                value: b"v1".to_vec(),
+            },
+            Field {
+                name: b"f2".to_vec(),
+                value: b"v2".to_vec(),
+            },
+        ];
+        let hash = Object::Hash(Hash {
+            key: b"key",
+            fields: &fields,
+            meta: &meta,
+        });
+        assert_eq!(restore.payload(&hash).unwrap(), DUMP_HASH);
+
+        // 目的Redis低于3.2时, DUMP版本为6
+        let restore = Restore { version: 6 };
+        assert_eq!(restore.payload(&string).unwrap(), DUMP_STRING_V6);
+    }
+}
@@ -1,71 +1,350 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap;
-use std::sync::mpsc::Sender;
-use std::sync::{mpsc, Arc};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
 
+use log::warn;
 use murmurhash64::murmur_hash64a;
-use redis::{Arg, Cmd, ConnectionAddr, IntoConnectionInfo};
+use redis::{Cmd, ConnectionAddr, IntoConnectionInfo};
 use redis_event::cmd::Command;
 use redis_event::Event::{AOF, RDB};
 use redis_event::{Event, EventHandler};
+use regex::bytes::Regex;
 
+use crate::checkpoint::{Flush, Marker};
+use crate::command;
 use crate::command::CommandConverter;
-use crate::worker::new_worker;
-use crate::worker::{Message, Worker};
+use crate::conflict::Conflict;
+use crate::filter::Filter;
+use crate::rename::Renamer;
+use crate::restore::Restore;
+use crate::source;
+use crate::source::SourceReader;
+use crate::worker::{channel, new_worker};
+use crate::worker::{Message, MessageSender, RetryConfig, Workers};
+use crate::Abort;
 use scheduled_thread_pool::ScheduledThreadPool;
 use std::sync::atomic::AtomicBool;
 
 const SEED: u64 = 0x1234ABCD;
 
+// 与jedis的Hashing.MURMUR_HASH、Hashing.MD5及twemproxy的md5、fnv1a_64一致
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Hashing {
+    Murmur,
+    Md5,
+    Fnv1a64,
+}
+
+impl Hashing {
+    pub(crate) fn parse(name: &str) -> Option<Hashing> {
+        match name.to_ascii_uppercase().as_str() {
+            "MURMUR_HASH" | "MURMUR" => Some(Hashing::Murmur),
+            "MD5" => Some(Hashing::Md5),
+            "FNV1A_64" => Some(Hashing::Fnv1a64),
+            _ => None,
+        }
+    }
+
+    // jedis以TreeMap<Long, ...>保存虚拟节点, 因此hash值需按照有符号数排序.
+    // MD5与FNV1A_64的结果均为32位无符号数, jedis与twemproxy的MD5计算方式相同
+    fn hash(&self, key: &[u8]) -> i64 {
+        match self {
+            Hashing::Murmur => murmur_hash64a(key, SEED) as i64,
+            Hashing::Md5 => md5_hash(key, 0) as i64,
+            Hashing::Fnv1a64 => {
+                // twemproxy只保留了FNV_64_INIT与FNV_64_PRIME的低32位, 且以有符号char读取每个字节
+                let mut hash = 0xcbf29ce484222325u64 as u32;
+                for byte in key {
+                    hash ^= *byte as i8 as u32;
+                    hash = hash.wrapping_mul(0x100000001b3u64 as u32);
+                }
+                hash as i64
+            }
+        }
+    }
+}
+
+// md5摘要中第alignment组4个字节按小端序组成的整数
+fn md5_hash(key: &[u8], alignment: usize) -> u32 {
+    let digest = md5::compute(key).0;
+    let i = alignment * 4;
+    ((digest[i + 3] as u32) << 24) | ((digest[i + 2] as u32) << 16) | ((digest[i + 1] as u32) << 8) | digest[i] as u32
+}
+
+// 根据key选择目的分片, 返回分片在目的Redis列表中的下标
+pub(crate) trait Strategy {
+    fn shard(&self, key: &[u8]) -> usize;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ShardStrategy {
+    Jedis,
+    Twemproxy,
+}
+
+impl ShardStrategy {
+    pub(crate) fn parse(name: &str) -> Option<ShardStrategy> {
+        match name.to_ascii_lowercase().as_str() {
+            "jedis" => Some(ShardStrategy::Jedis),
+            "twemproxy" => Some(ShardStrategy::Twemproxy),
+            _ => None,
+        }
+    }
+
+    // 未指定hash算法时, 与jedis及twemproxy的默认配置一致
+    pub(crate) fn default_hashing(&self) -> Hashing {
+        match self {
+            ShardStrategy::Jedis => Hashing::Murmur,
+            ShardStrategy::Twemproxy => Hashing::Fnv1a64,
+        }
+    }
+
+    pub(crate) fn supports(&self, hashing: Hashing) -> bool {
+        match self {
+            ShardStrategy::Jedis => hashing != Hashing::Fnv1a64,
+            ShardStrategy::Twemproxy => hashing != Hashing::Murmur,
+        }
+    }
+
+    // jedis的key tag为正则表达式(Sharded.DEFAULT_KEY_TAG_PATTERN), twemproxy的hash_tag为两个字符
+    pub(crate) fn default_key_tag(&self) -> &'static str {
+        match self {
+            ShardStrategy::Jedis => "\\{(.+?)\\}",
+            ShardStrategy::Twemproxy => "{}",
+        }
+    }
+
+    fn build(&self, shards: &[Shard], hashing: Hashing, key_tag: Option<String>) -> Box<dyn Strategy> {
+        match self {
+            ShardStrategy::Jedis => Box::new(JedisSharding::new(shards, hashing, key_tag)),
+            ShardStrategy::Twemproxy => Box::new(Ketama::new(shards, hashing, key_tag)),
+        }
+    }
+}
+
+// 目的Redis URI中指定的分片信息
+struct Shard {
+    name: Option<String>,
+    host: String,
+    port: u16,
+    weight: u32,
+}
+
+// 与jedis的Sharded一致
+struct JedisSharding {
+    nodes: BTreeMap<i64, usize>,
+    hashing: Hashing,
+    key_tag: Option<Regex>,
+}
+
+impl JedisSharding {
+    fn new(shards: &[Shard], hashing: Hashing, key_tag: Option<String>) -> JedisSharding {
+        let key_tag = key_tag.map(|pattern| match Regex::new(&pattern) {
+            Ok(regex) => regex,
+            Err(err) => panic!("无效的key tag pattern {}: {}", pattern, err),
+        });
+        // 每个Shard生成160 * weight个虚拟节点, 未指定名称时以其下标命名
+        let mut nodes = BTreeMap::new();
+        for (i, shard) in shards.iter().enumerate() {
+            for n in 0..160 * shard.weight {
+                let node_name = match &shard.name {
+                    None => format!("SHARD-{}-NODE-{}", i, n),
+                    Some(name) => format!("{}*{}", name, n),
+                };
+                nodes.insert(hashing.hash(node_name.as_bytes()), i);
+            }
+        }
+        JedisSharding {
+            nodes,
+            hashing,
+            key_tag,
+        }
+    }
+
+    // 与jedis的getKeyTag一致: key匹配key tag pattern时, 只使用第一个捕获组计算hash
+    fn key_tag<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        if let Some(pattern) = &self.key_tag {
+            if let Some(tag) = pattern.captures(key).and_then(|captures| captures.get(1)) {
+                return tag.as_bytes();
+            }
+        }
+        key
+    }
+}
+
+impl Strategy for JedisSharding {
+    // hash值大于所有虚拟节点时落在第一个节点上
+    fn shard(&self, key: &[u8]) -> usize {
+        let hash = self.hashing.hash(self.key_tag(key));
+        match self.nodes.range(hash..).next() {
+            Some((_, i)) => *i,
+            None => *self.nodes.values().next().unwrap(),
+        }
+    }
+}
+
+const KETAMA_POINTS_PER_SERVER: u32 = 160;
+const KETAMA_MAX_HOSTLEN: usize = 86;
+
+// 与twemproxy的ketama分布(nc_ketama.c)一致
+struct Ketama {
+    continuum: Vec<(u32, usize)>,
+    hashing: Hashing,
+    hash_tag: Option<(u8, u8)>,
+}
+
+impl Ketama {
+    fn new(shards: &[Shard], hashing: Hashing, hash_tag: Option<String>) -> Ketama {
+        let hash_tag = hash_tag.map(|tag| match tag.as_bytes() {
+            [start, end] => (*start, *end),
+            _ => panic!("无效的hash_tag: {}, 需为两个字符, 如: {{}}", tag),
+        });
+        let total_weight: u32 = shards.iter().map(|shard| shard.weight).sum();
+        let mut continuum = Vec::new();
+        for (i, shard) in shards.iter().enumerate() {
+            // 未指定名称时twemproxy以host:port命名, 端口为11211时只使用host
+            let name = match &shard.name {
+                Some(name) => name.clone(),
+                None if shard.port == 11211 => shard.host.clone(),
+                None => format!("{}:{}", shard.host, shard.port),
+            };
+            // 按照twemproxy的单精度浮点运算计算虚拟节点数量
+            let pct = shard.weight as f32 / total_weight as f32;
+            let points = pct * KETAMA_POINTS_PER_SERVER as f32 / 4.0 * shards.len() as f32;
+            let points = (((points as f64 + 0.0000000001) as f32).floor() * 4.0) as u32;
+            for pointer in 0..points / 4 {
+                let mut host = format!("{}-{}", name, pointer).into_bytes();
+                host.truncate(KETAMA_MAX_HOSTLEN - 1);
+                for alignment in 0..4 {
+                    continuum.push((md5_hash(&host, alignment), i));
+                }
+            }
+        }
+        continuum.sort_by_key(|(value, _)| *value);
+        Ketama {
+            continuum,
+            hashing,
+            hash_tag,
+        }
+    }
+
+    // 与twemproxy一致: key中存在hash_tag包围的非空部分时, 只使用该部分计算hash
+    fn hash_tag<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        if let Some((start, end)) = self.hash_tag {
+            if let Some(i) = key.iter().position(|byte| *byte == start) {
+                if let Some(len) = key[i + 1..].iter().position(|byte| *byte == end) {
+                    if len > 0 {
+                        return &key[i + 1..i + 1 + len];
+                    }
+                }
+            }
+        }
+        key
+    }
+}
+
+impl Strategy for Ketama {
+    // 取第一个不小于hash值的点, hash值大于所有点时落在第一个点上
+    fn shard(&self, key: &[u8]) -> usize {
+        let hash = self.hashing.hash(self.hash_tag(key)) as u32;
+        let (mut left, mut right) = (0, self.continuum.len());
+        while left < right {
+            let middle = left + (right - left) / 2;
+            if self.continuum[middle].0 < hash {
+                left = middle + 1;
+            } else {
+                right = middle;
+            }
+        }
+        if right == self.continuum.len() {
+            right = 0;
+        }
+        self.continuum[right].1
+    }
+}
+
 pub struct ShardedEventHandler {
-    workers: Vec<Worker>,
-    nodes: BTreeMap<u64, String>,
-    senders: RefCell<BTreeMap<String, Sender<Message>>>,
+    layout: Layout,
+    // reshard模式下, 与源Redis地址相同的分片. 仍属于该分片的key无需复制
+    local: Option<String>,
+    senders: RefCell<BTreeMap<String, MessageSender>>,
+    // 之后的命令所属的db, 为None时使用目的Redis地址中的db
+    db: Option<i64>,
+    filter: Filter,
+    renamer: Renamer,
+    chunk_size: usize,
+    restore: Option<Restore>,
+    conflict: Option<Conflict>,
+    source: SourceReader,
+    // MULTI之后尚未EXEC的命令, 及其所属的分片
+    transaction: Option<Vec<(String, Cmd)>>,
+    abort: Abort,
 }
 
 impl EventHandler for ShardedEventHandler {
     fn handle(&mut self, event: Event) {
         match event {
             RDB(rdb) => self.handle_rdb(rdb),
+            AOF(cmd) if !self.in_accepted_db(&cmd) => {}
+            AOF(cmd) if command::is_multi_key(&cmd) => self.handle_multi_key(cmd),
             AOF(cmd) => match cmd {
                 Command::SELECT(select) => {
-                    let db = select.db.to_string();
-                    self.swap_db(select.db);
-                    self.broadcast("SELECT", Some(&vec![db.as_bytes()]));
+                    self.select_db(select.db as i64);
                 }
                 Command::DEL(del) => {
                     for key in &del.keys {
+                        if !self.filter.accept(key) {
+                            continue;
+                        }
+                        let new_key = self.rename(key);
                         let mut cmd = redis::cmd("DEL");
-                        cmd.arg(key.as_slice());
-                        self.execute(cmd, Some(key.as_slice()));
+                        cmd.arg(new_key.as_slice());
+                        self.execute(cmd, Some(new_key.as_slice()));
                     }
                 }
                 Command::MSET(mset) => {
                     for kv in &mset.key_values {
+                        if !self.filter.accept(kv.key) {
+                            continue;
+                        }
+                        let new_key = self.rename(kv.key);
                         let mut cmd = redis::cmd("SET");
-                        cmd.arg(kv.key).arg(kv.value);
-                        self.execute(cmd, Some(kv.key));
+                        cmd.arg(new_key.as_slice()).arg(kv.value);
+                        self.execute(cmd, Some(new_key.as_slice()));
                     }
                 }
                 Command::MSETNX(msetnx) => {
                     for kv in &msetnx.key_values {
+                        if !self.filter.accept(kv.key) {
+                            continue;
+                        }
+                        let new_key = self.rename(kv.key);
                         let mut cmd = redis::cmd("SETNX");
-                        cmd.arg(kv.key).arg(kv.value);
-                        self.execute(cmd, Some(kv.key));
+                        cmd.arg(new_key.as_slice()).arg(kv.value);
+                        self.execute(cmd, Some(new_key.as_slice()));
                     }
                 }
                 Command::PFCOUNT(pfcount) => {
                     for key in &pfcount.keys {
+                        if !self.filter.accept(key) {
+                            continue;
+                        }
+                        let new_key = self.rename(key);
                         let mut cmd = redis::cmd("PFCOUNT");
-                        cmd.arg(*key);
-                        self.execute(cmd, Some(*key));
+                        cmd.arg(new_key.as_slice());
+                        self.execute(cmd, Some(new_key.as_slice()));
                     }
                 }
                 Command::UNLINK(unlink) => {
                     for key in &unlink.keys {
+                        if !self.filter.accept(key) {
+                            continue;
+                        }
+                        let new_key = self.rename(key);
                         let mut cmd = redis::cmd("UNLINK");
-                        cmd.arg(*key);
-                        self.execute(cmd, Some(*key));
+                        cmd.arg(new_key.as_slice());
+                        self.execute(cmd, Some(new_key.as_slice()));
                     }
                 }
                 Command::SCRIPTFLUSH => {
@@ -75,7 +354,10 @@ impl EventHandler for ShardedEventHandler {
                     self.broadcast("SCRIPT", Some(&vec!["LOAD".as_bytes(), scriptload.script]));
                 }
                 Command::SWAPDB(swapdb) => {
-                    self.broadcast("SWAPDB", Some(&vec![swapdb.index1, swapdb.index2]));
+                    if let Some((index1, index2)) = self.map_swapdb(swapdb.index1, swapdb.index2) {
+                        let (index1, index2) = (index1.to_string(), index2.to_string());
+                        self.broadcast("SWAPDB", Some(&vec![index1.as_bytes(), index2.as_bytes()]));
+                    }
                 }
                 Command::FLUSHDB(flushdb) => {
                     match flushdb._async {
@@ -83,49 +365,61 @@ impl EventHandler for ShardedEventHandler {
                         Some(_) => self.broadcast("FLUSHDB", Some(&vec!["ASYNC".as_bytes()])),
                     };
                 }
-                Command::FLUSHALL(flushall) => {
-                    match flushall._async {
-                        None => self.broadcast("FLUSHALL", None),
-                        Some(_) => self.broadcast("FLUSHALL", Some(&vec!["ASYNC".as_bytes()])),
-                    };
-                }
+                Command::FLUSHALL(flushall) => match self.filter.flushall_dbs() {
+                    None => {
+                        match flushall._async {
+                            None => self.broadcast("FLUSHALL", None),
+                            Some(_) => self.broadcast("FLUSHALL", Some(&vec!["ASYNC".as_bytes()])),
+                        };
+                    }
+                    Some(dbs) => {
+                        for db in dbs {
+                            self.swap_db(db as i32);
+                            match flushall._async {
+                                None => self.broadcast("FLUSHDB", None),
+                                Some(_) => self.broadcast("FLUSHDB", Some(&vec!["ASYNC".as_bytes()])),
+                            };
+                        }
+                        self.restore_db();
+                    }
+                },
                 Command::XGROUP(xgroup) => {
-                    if let Some(create) = &xgroup.create {
+                    if let Some(create) = xgroup.create.as_ref().filter(|x| self.filter.accept(x.key)) {
+                        let new_key = self.rename(create.key);
                         let mut cmd = redis::cmd("XGROUP");
-                        cmd.arg("CREATE").arg(create.key).arg(create.group_name).arg(create.id);
-                        self.execute(cmd, Some(create.key));
+                        cmd.arg("CREATE")
+                            .arg(new_key.as_slice())
+                            .arg(create.group_name)
+                            .arg(create.id);
+                        self.execute(cmd, Some(new_key.as_slice()));
                     }
-                    if let Some(set_id) = &xgroup.set_id {
+                    if let Some(set_id) = xgroup.set_id.as_ref().filter(|x| self.filter.accept(x.key)) {
+                        let new_key = self.rename(set_id.key);
                         let mut cmd = redis::cmd("XGROUP");
-                        cmd.arg("SETID").arg(set_id.key).arg(set_id.group_name).arg(set_id.id);
-                        self.execute(cmd, Some(set_id.key));
+                        cmd.arg("SETID")
+                            .arg(new_key.as_slice())
+                            .arg(set_id.group_name)
+                            .arg(set_id.id);
+                        self.execute(cmd, Some(new_key.as_slice()));
                     }
-                    if let Some(destroy) = &xgroup.destroy {
+                    if let Some(destroy) = xgroup.destroy.as_ref().filter(|x| self.filter.accept(x.key)) {
+                        let new_key = self.rename(destroy.key);
                         let mut cmd = redis::cmd("XGROUP");
-                        cmd.arg("DESTROY").arg(destroy.key).arg(destroy.group_name);
-                        self.execute(cmd, Some(destroy.key));
+                        cmd.arg("DESTROY").arg(new_key.as_slice()).arg(destroy.group_name);
+                        self.execute(cmd, Some(new_key.as_slice()));
                     }
-                    if let Some(del_consumer) = &xgroup.del_consumer {
+                    if let Some(del_consumer) = xgroup.del_consumer.as_ref().filter(|x| self.filter.accept(x.key)) {
+                        let new_key = self.rename(del_consumer.key);
                         let mut cmd = redis::cmd("XGROUP");
                         cmd.arg("DELCONSUMER")
-                            .arg(del_consumer.key)
+                            .arg(new_key.as_slice())
                             .arg(del_consumer.group_name)
                             .arg(del_consumer.consumer_name);
-                        self.execute(cmd, Some(del_consumer.key));
+                        self.execute(cmd, Some(new_key.as_slice()));
                     }
                 }
-                Command::BITOP(_)
-                | Command::EVAL(_)
-                | Command::EVALSHA(_)
-                | Command::MULTI
-                | Command::EXEC
-                | Command::PFMERGE(_)
-                | Command::SDIFFSTORE(_)
-                | Command::SINTERSTORE(_)
-                | Command::SUNIONSTORE(_)
-                | Command::ZUNIONSTORE(_)
-                | Command::ZINTERSTORE(_)
-                | Command::PUBLISH(_) => {}
+                Command::MULTI => self.transaction = Some(Vec::new()),
+                Command::EXEC => self.commit(),
                 _ => self.handle_aof(cmd),
             },
         };
@@ -133,12 +427,61 @@ impl EventHandler for ShardedEventHandler {
 }
 
 impl ShardedEventHandler {
-    fn get_shard(&self, key: &[u8]) -> Option<String> {
-        let hash = murmur_hash64a(key, SEED);
-        if let Some((_, node)) = self.nodes.range(hash..).next() {
-            Some(node.clone())
+    fn get_shard(&self, key: &[u8]) -> String {
+        self.layout.names[self.layout.owner(key)].clone()
+    }
+
+    fn send(&self, node: &str, message: Message) {
+        let senders = self.senders.borrow();
+        if let Err(err) = senders.get(node).unwrap().send(message) {
+            self.abort.abort(&format!("分片{}的worker已退出: {}", node, err));
+        }
+    }
+
+    // 所有key都属于同一个分片时直接执行, 否则从源Redis读取命令所修改的key, 再写入各自的分片
+    fn handle_multi_key(&mut self, cmd: Command) {
+        if !self.accept_keys(&cmd) {
+            return;
+        }
+        let shards: Vec<String> = command::keys(&cmd)
+            .iter()
+            .map(|key| self.get_shard(&self.rename(key)))
+            .collect();
+        if shards.windows(2).all(|pair| pair[0] == pair[1]) {
+            self.handle_aof(cmd);
+            return;
+        }
+        let keys: Vec<(&[u8], Vec<u8>)> = command::written_keys(&cmd)
+            .into_iter()
+            .map(|key| (key, self.rename(key)))
+            .collect();
+        match self.source.restore(self.filter.current_db(), &keys) {
+            Ok(cmds) => {
+                for restore in cmds {
+                    self.execute(restore, None);
+                }
+            }
+            Err(err) => source::report_dropped(&cmd, &format!("从源Redis读取key失败: {}", err)),
+        }
+    }
+
+    // 事务中的命令都属于同一个分片时, 以MULTI/EXEC原子地写入; 否则只能逐条写入各个分片
+    fn commit(&mut self) {
+        let cmds = match self.transaction.take() {
+            Some(cmds) if !cmds.is_empty() => cmds,
+            _ => return,
+        };
+        if cmds.windows(2).all(|pair| pair[0].0 == pair[1].0) {
+            let node = cmds[0].0.clone();
+            self.send(
+                &node,
+                Message::Transaction(cmds.into_iter().map(|(_, cmd)| cmd).collect(), self.db),
+            );
         } else {
-            None
+            warn!("事务中的命令分布在多个分片中, 将逐条写入, 无法保证原子性");
+            for (node, cmd) in cmds {
+                self.send(&node, Message::Cmd(cmd, self.db));
+            }
         }
     }
 
@@ -151,75 +494,75 @@ impl ShardedEventHandler {
                     cmd.arg(*arg);
                 }
             }
-            if let Err(err) = sender.send(Message::Cmd(cmd)) {
-                panic!("{}", err)
+            if let Err(err) = sender.send(Message::Cmd(cmd, self.db)) {
+                self.abort.abort(&format!("worker已退出: {}", err));
             }
         }
     }
 }
 
-impl Drop for ShardedEventHandler {
-    fn drop(&mut self) {
-        let senders = self.senders.borrow();
-        for (_, sender) in senders.iter() {
-            if let Err(_) = sender.send(Message::Terminate) {}
-        }
-        for worker in self.workers.iter_mut() {
-            if let Some(thread) = worker.thread.take() {
-                if let Err(_) = thread.join() {}
-            }
-        }
+impl Flush for ShardedEventHandler {
+    fn flush(&mut self) {
+        self.mark().wait();
+    }
+
+    fn mark(&mut self) -> Marker {
+        Marker::send(self.senders.borrow().values())
     }
 }
 
 impl CommandConverter for ShardedEventHandler {
     fn execute(&mut self, cmd: Cmd, key: Option<&[u8]>) {
-        let _key;
-        if let Some(the_key) = key {
-            _key = the_key;
-        } else {
-            _key = match cmd.args_iter().skip(1).next() {
-                None => panic!("cmd args is empty"),
-                Some(arg) => match arg {
-                    Arg::Simple(arg) => arg,
-                    Arg::Cursor => panic!("cmd first arg is cursor"),
-                },
-            };
+        // 与key无关的命令写入第一个分片
+        let node = match key.or_else(|| command::routing_key(&cmd)) {
+            Some(key) => self.get_shard(key),
+            None => self.layout.names[0].clone(),
+        };
+        if self.local.as_ref() == Some(&node) {
+            return;
         }
-        let senders = self.senders.borrow();
-        match self.get_shard(&_key) {
-            None => {
-                let first_sender = senders.values().next();
-                let sender = first_sender.unwrap();
-                if let Err(err) = sender.send(Message::Cmd(cmd)) {
-                    panic!("{}", err)
-                }
-            }
-            Some(node) => {
-                let sender = senders.get(&node).unwrap();
-                if let Err(err) = sender.send(Message::Cmd(cmd)) {
-                    panic!("{}", err)
-                }
-            }
+        if let Some(transaction) = self.transaction.as_mut() {
+            transaction.push((node, cmd));
+            return;
         }
+        self.send(&node, Message::Cmd(cmd, self.db));
+    }
+
+    fn filter(&mut self) -> &mut Filter {
+        &mut self.filter
+    }
+
+    fn renamer(&self) -> &Renamer {
+        &self.renamer
+    }
+
+    fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    fn restore(&self) -> Option<&Restore> {
+        self.restore.as_ref()
+    }
+
+    fn conflict(&mut self) -> Option<&mut Conflict> {
+        self.conflict.as_mut()
+    }
+
+    fn source(&mut self) -> Option<&mut SourceReader> {
+        Some(&mut self.source)
     }
 
     fn swap_db(&mut self, db: i32) {
-        let senders = self.senders.borrow();
-        for (_, sender) in senders.iter() {
-            if let Err(err) = sender.send(Message::SwapDb(db as i64)) {
-                panic!("{}", err)
-            }
-        }
+        self.db = Some(db as i64);
     }
 }
 
-pub(crate) fn new_sharded(
-    initial_nodes: Vec<String>, batch_size: i32, flush_interval: u64, control_flag: Arc<AtomicBool>,
-) -> ShardedEventHandler {
-    let mut senders: BTreeMap<String, Sender<Message>> = BTreeMap::new();
-    let mut workers = Vec::new();
-    let mut nodes: BTreeMap<u64, String> = BTreeMap::new();
+// 各个源共用的worker, 每个分片一个, 以分片的名称区分
+pub(crate) fn new_workers(
+    initial_nodes: Vec<String>, batch_size: i32, flush_interval: u64, queue_size: usize, retry: RetryConfig,
+    control_flag: Arc<AtomicBool>,
+) -> Workers {
+    let mut workers = Workers::new();
     let threads = if initial_nodes.len() < 3 {
         initial_nodes.len()
     } else {
@@ -228,34 +571,278 @@ pub(crate) fn new_sharded(
     let thread_pool = Arc::new(ScheduledThreadPool::with_name("r2d2-worker-{}", threads));
 
     for (i, node) in initial_nodes.into_iter().enumerate() {
-        let info = node.as_str().into_connection_info().unwrap();
-        let addr = match *info.addr {
-            ConnectionAddr::Tcp(ref host, port) => format!("{}-{}:{}", i, host, port),
-            ConnectionAddr::TcpTls { ref host, port, .. } => format!("{}-{}:{}", i, host, port),
-            _ => unimplemented!("Only support Tcp"),
-        };
-        for n in 0..160 {
-            let name = format!("SHARD-{}-NODE-{}", i, n);
-            let hash = murmur_hash64a(name.as_bytes(), SEED);
-            nodes.insert(hash, addr.clone());
-        }
-        let (sender, receiver) = mpsc::channel();
-        let worker_name = format!("shard-{}", addr);
+        let name = shard_name(i, &node);
+        let worker_name = format!("shard-{}", name);
+        let (sender, receiver) = channel(&worker_name, queue_size);
         let worker = new_worker(
-            node.clone(),
+            node,
             receiver,
             &worker_name,
             batch_size,
             flush_interval,
+            retry.clone(),
             control_flag.clone(),
             Arc::clone(&thread_pool),
         );
-        senders.insert(addr, sender);
-        workers.push(Worker { thread: Some(worker) });
+        workers.add(&name, sender, worker);
     }
+    workers
+}
+
+pub(crate) fn new_sharded(
+    source: Option<&str>, senders: &BTreeMap<String, MessageSender>, initial_nodes: &[String], filter: Filter,
+    renamer: Renamer, chunk_size: usize, restore: Option<Restore>, conflict: Option<Conflict>, strategy: ShardStrategy,
+    hashing: Hashing, key_tag: Option<String>, reshard: bool, abort: Abort,
+) -> ShardedEventHandler {
+    let layout = Layout::new(initial_nodes, strategy, hashing, key_tag);
+    // 找不到源Redis所在的分片时, 所有的key(包括不需要迁移的)都会被重新写入, 且之后会以错误的归属删除key
+    let local = match source {
+        Some(source) if reshard => match layout.locate(source) {
+            Some(i) => Some(layout.names[i].clone()),
+            None => panic!("--reshard: 源Redis({})不是--target中的任何一个分片", source),
+        },
+        _ => None,
+    };
     ShardedEventHandler {
-        workers,
-        nodes,
-        senders: RefCell::new(senders),
+        layout,
+        local,
+        senders: RefCell::new(senders.clone()),
+        db: None,
+        filter,
+        renamer,
+        chunk_size,
+        restore,
+        conflict,
+        source: SourceReader::new(source),
+        transaction: None,
+        abort,
+    }
+}
+
+// 形如{下标}-{host}:{port}, 用于区分各个分片的worker
+fn shard_name(i: usize, node: &str) -> String {
+    let (host, port) = host_port(node);
+    format!("{}-{}:{}", i, host, port)
+}
+
+// 目的Redis的分片布局: 各个分片的地址, 以及key的分片规则
+pub(crate) struct Layout {
+    // 各个分片的名称, 见shard_name
+    names: Vec<String>,
+    addrs: Vec<String>,
+    urls: Vec<String>,
+    strategy: Box<dyn Strategy>,
+}
+
+impl Layout {
+    pub(crate) fn new(
+        targets: &[String], strategy: ShardStrategy, hashing: Hashing, key_tag: Option<String>,
+    ) -> Layout {
+        let mut names = Vec::new();
+        let mut addrs = Vec::new();
+        let mut urls = Vec::new();
+        let mut shards = Vec::new();
+        for (i, node) in targets.iter().enumerate() {
+            let (host, port) = host_port(node);
+            let (name, weight) = shard_info(node);
+            names.push(shard_name(i, node));
+            addrs.push(format!("{}:{}", host, port));
+            urls.push(node.clone());
+            shards.push(Shard {
+                name,
+                host,
+                port,
+                weight,
+            });
+        }
+        Layout {
+            names,
+            addrs,
+            urls,
+            strategy: strategy.build(&shards, hashing, key_tag),
+        }
+    }
+
+    // key所属分片的下标
+    pub(crate) fn owner(&self, key: &[u8]) -> usize {
+        self.strategy.shard(key)
+    }
+
+    pub(crate) fn addr(&self, i: usize) -> &str {
+        &self.addrs[i]
+    }
+
+    pub(crate) fn url(&self, i: usize) -> &str {
+        &self.urls[i]
+    }
+
+    // node所在分片的下标. 同一个节点的写法可能不同(如localhost与127.0.0.1), 地址不相同时再比较解析后的socket地址
+    pub(crate) fn locate(&self, node: &str) -> Option<usize> {
+        let (host, port) = host_port(node);
+        let addr = format!("{}:{}", host, port);
+        if let Some(i) = self.addrs.iter().position(|shard| *shard == addr) {
+            return Some(i);
+        }
+        let resolved = resolve(&addr);
+        self.addrs
+            .iter()
+            .position(|shard| resolve(shard).iter().any(|addr| resolved.contains(addr)))
+    }
+}
+
+fn resolve(addr: &str) -> Vec<SocketAddr> {
+    addr.to_socket_addrs().map(|addrs| addrs.collect()).unwrap_or_default()
+}
+
+pub(crate) fn host_port(node: &str) -> (String, u16) {
+    let info = node.into_connection_info().unwrap();
+    match *info.addr {
+        ConnectionAddr::Tcp(ref host, port) => (host.clone(), port),
+        ConnectionAddr::TcpTls { ref host, port, .. } => (host.clone(), port),
+        _ => unimplemented!("Only support Tcp"),
+    }
+}
+
+// 通过目的Redis URI中的name与weight参数指定Shard的名称与权重, 如: redis://127.0.0.1:6379?name=shard1&weight=2
+fn shard_info(node: &str) -> (Option<String>, u32) {
+    let url = url::Url::parse(node).expect("解析Target Redis地址失败");
+    let (mut name, mut weight) = (None, 1);
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "name" => name = Some(value.into_owned()),
+            "weight" => {
+                weight = match value.parse::<u32>() {
+                    Ok(weight) if weight > 0 => weight,
+                    _ => panic!("无效的weight: {}", value),
+                }
+            }
+            _ => {}
+        }
+    }
+    (name, weight)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sharding::{shard_info, Hashing, Layout, ShardStrategy};
+
+    // 期望值由jedis(Sharded, Hashing)与twemproxy(nc_ketama.c)对同一组key的计算结果得出
+    const KEYS: [&str; 10] = [
+        "foo",
+        "bar",
+        "user:1000",
+        "user:1001",
+        "order:{42}:items",
+        "{42}:payments",
+        "session:9f8e7d",
+        "ключ",
+        "a",
+        "counter",
+    ];
+
+    fn targets(named: bool) -> Vec<String> {
+        let shards = [("alpha", 1, 6379), ("beta", 2, 6380), ("gamma", 3, 6381)];
+        shards
+            .iter()
+            .map(|(name, weight, port)| {
+                if named {
+                    format!("redis://127.0.0.1:{}?name={}&weight={}", port, name, weight)
+                } else {
+                    format!("redis://127.0.0.1:{}", port)
+                }
+            })
+            .collect()
+    }
+
+    fn owners(strategy: ShardStrategy, hashing: Hashing, named: bool, key_tag: Option<&str>) -> Vec<usize> {
+        let layout = Layout::new(&targets(named), strategy, hashing, key_tag.map(String::from));
+        KEYS.iter().map(|key| layout.owner(key.as_bytes())).collect()
+    }
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(Hashing::Murmur.hash(b"foo"), -7063922479176959649);
+        assert_eq!(Hashing::Murmur.hash(b"SHARD-0-NODE-0"), -4813603235750630532);
+        assert_eq!(Hashing::Murmur.hash("ключ".as_bytes()), -3939723430367030310);
+        assert_eq!(Hashing::Md5.hash(b"foo"), 3675831724);
+        assert_eq!(Hashing::Md5.hash(b"SHARD-0-NODE-0"), 4193559948);
+        assert_eq!(Hashing::Md5.hash("ключ".as_bytes()), 1719363011);
+        assert_eq!(Hashing::Fnv1a64.hash(b"foo"), 4275688823);
+        assert_eq!(Hashing::Fnv1a64.hash(b"127.0.0.1:6379-0"), 712855238);
+        assert_eq!(Hashing::Fnv1a64.hash("ключ".as_bytes()), 2846718081);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Hashing::parse("murmur_hash"), Some(Hashing::Murmur));
+        assert_eq!(Hashing::parse("MD5"), Some(Hashing::Md5));
+        assert_eq!(Hashing::parse("fnv1a_64"), Some(Hashing::Fnv1a64));
+        assert_eq!(Hashing::parse("crc32"), None);
+        assert_eq!(ShardStrategy::parse("Twemproxy"), Some(ShardStrategy::Twemproxy));
+        assert_eq!(shard_info("redis://127.0.0.1:6379"), (None, 1));
+        assert_eq!(
+            shard_info("redis://:pass@127.0.0.1:6379/0?name=beta&weight=2"),
+            (Some("beta".to_string()), 2)
+        );
+    }
+
+    #[test]
+    fn test_locate() {
+        let layout = Layout::new(&targets(false), ShardStrategy::Jedis, Hashing::Murmur, None);
+        assert_eq!(layout.locate("redis://127.0.0.1:6380"), Some(1));
+        assert_eq!(layout.locate("redis://:pass@localhost:6381/0"), Some(2));
+        assert_eq!(layout.locate("redis://127.0.0.1:6382"), None);
+        assert_eq!(layout.locate("redis://localhost:6382"), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_weight() {
+        shard_info("redis://127.0.0.1:6379?weight=0");
+    }
+
+    #[test]
+    fn test_jedis() {
+        let (jedis, tag) = (ShardStrategy::Jedis, Some(ShardStrategy::Jedis.default_key_tag()));
+        assert_eq!(
+            owners(jedis, Hashing::Murmur, false, None),
+            vec![0, 2, 2, 0, 2, 1, 2, 0, 1, 0]
+        );
+        assert_eq!(
+            owners(jedis, Hashing::Murmur, true, tag),
+            vec![2, 1, 2, 2, 2, 2, 2, 2, 1, 2]
+        );
+        assert_eq!(
+            owners(jedis, Hashing::Md5, false, None),
+            vec![2, 2, 2, 0, 1, 2, 2, 1, 0, 1]
+        );
+        assert_eq!(
+            owners(jedis, Hashing::Md5, true, tag),
+            vec![2, 2, 2, 1, 1, 1, 1, 1, 2, 0]
+        );
+    }
+
+    #[test]
+    fn test_twemproxy() {
+        let (twemproxy, tag) = (
+            ShardStrategy::Twemproxy,
+            Some(ShardStrategy::Twemproxy.default_key_tag()),
+        );
+        assert_eq!(
+            owners(twemproxy, Hashing::Fnv1a64, false, tag),
+            vec![2, 0, 2, 2, 2, 2, 1, 1, 0, 0]
+        );
+        assert_eq!(
+            owners(twemproxy, Hashing::Fnv1a64, true, tag),
+            vec![2, 2, 1, 1, 2, 2, 2, 2, 2, 2]
+        );
+        assert_eq!(
+            owners(twemproxy, Hashing::Md5, false, tag),
+            vec![0, 2, 2, 0, 2, 2, 1, 1, 2, 1]
+        );
+        assert_eq!(
+            owners(twemproxy, Hashing::Md5, true, tag),
+            vec![2, 2, 1, 0, 1, 1, 2, 1, 0, 1]
+        );
     }
 }
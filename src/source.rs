@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+
+use log::{error, info, warn};
+use redis::{Cmd, Connection, ErrorKind, RedisError, RedisResult, Value};
+use redis_event::cmd::Command;
+use redis_event::rdb;
+use url::Url;
+
+use crate::command;
+use crate::export::stream_id;
+use crate::metrics;
+
+const PENDING_BATCH: usize = 1000;
+
+// 跨slot/跨分片的命令无法在目的Redis中直接执行, 此时从源Redis读取命令所修改的key的最新值,
+// 再以RESTORE ... REPLACE的方式写入目的Redis. 也用于读取RDB对象中不包含的stream状态
+pub(crate) struct SourceReader {
+    // 离线文件作为源时为None, 此时无法读取
+    url: Option<String>,
+    conn: Option<Connection>,
+    db: Option<i64>,
+}
+
+// 源Redis中stream的当前状态, 来自XINFO及XPENDING. 消息ID均为"<ms>-<seq>"的形式
+pub(crate) struct StreamState {
+    pub(crate) length: u64,
+    pub(crate) last_generated_id: String,
+    pub(crate) last_entry_id: Option<String>,
+    // 以下两项从Redis 7.0开始支持
+    pub(crate) max_deleted_id: Option<String>,
+    pub(crate) entries_added: Option<u64>,
+    pub(crate) groups: Vec<GroupState>,
+}
+
+pub(crate) struct GroupState {
+    pub(crate) name: Vec<u8>,
+    pub(crate) last_delivered_id: String,
+    pub(crate) entries_read: Option<u64>,
+    // 没有pending消息的消费者, 只在Redis 7.0及以上读取
+    pub(crate) idle_consumers: Vec<Vec<u8>>,
+    // 消息ID, 消费者, 空闲时间(毫秒), 投递次数
+    pub(crate) pending: Vec<(String, Vec<u8>, i64, i64)>,
+}
+
+impl StreamState {
+    // RDB生成之后没有新增或删除消息, 即RDB中的stream与源Redis当前的消息一致
+    pub(crate) fn matches(&self, stream: &rdb::Stream) -> bool {
+        let last_entry = stream.entries.keys().map(|id| stream_id(&id.to_string())).max();
+        self.length == stream.entries.len() as u64 && self.last_entry_id.as_deref().map(stream_id) == last_entry
+    }
+}
+
+impl SourceReader {
+    pub(crate) fn new(url: Option<&str>) -> SourceReader {
+        SourceReader {
+            url: url.map(str::to_string),
+            conn: None,
+            db: None,
+        }
+    }
+
+    // 源Cluster发生故障转移后, 改为从新的master读取
+    pub(crate) fn retarget(&mut self, url: &str) {
+        if self.url.is_some() {
+            self.url = Some(url.to_string());
+            self.conn = None;
+            self.db = None;
+        }
+    }
+
+    // 生成将keys中的(源key, 目的key)复制到目的Redis的命令, 源key已不存在时在目的Redis中将其删除
+    pub(crate) fn restore(&mut self, db: i64, keys: &[(&[u8], Vec<u8>)]) -> RedisResult<Vec<Cmd>> {
+        let mut cmds = Vec::with_capacity(keys.len());
+        for (key, new_key) in keys {
+            match self.dump(db, key)? {
+                Some((value, ttl)) => {
+                    let mut cmd = redis::cmd("RESTORE");
+                    cmd.arg(new_key.as_slice()).arg(ttl).arg(value).arg("REPLACE");
+                    cmds.push(cmd);
+                }
+                None => {
+                    let mut cmd = redis::cmd("DEL");
+                    cmd.arg(new_key.as_slice());
+                    cmds.push(cmd);
+                }
+            }
+        }
+        Ok(cmds)
+    }
+
+    // 读取key的序列化值及剩余的过期时间(毫秒, 0表示不过期), 连接中断时重连一次
+    fn dump(&mut self, db: i64, key: &[u8]) -> RedisResult<Option<(Vec<u8>, i64)>> {
+        let mut result = self.try_dump(db, key);
+        if let Err(err) = &result {
+            self.conn = None;
+            if err.is_io_error() {
+                warn!("与源Redis的连接中断, 正在重连: {}", err);
+                result = self.try_dump(db, key);
+            }
+        }
+        result
+    }
+
+    fn try_dump(&mut self, db: i64, key: &[u8]) -> RedisResult<Option<(Vec<u8>, i64)>> {
+        let conn = self.connection(db)?;
+        let (value, ttl): (Option<Vec<u8>>, i64) =
+            redis::pipe().cmd("DUMP").arg(key).cmd("PTTL").arg(key).query(conn)?;
+        Ok(value.map(|value| (value, ttl.max(0))))
+    }
+
+    // 离线文件作为源, 或者key已不存在时返回None
+    pub(crate) fn stream(&mut self, db: i64, key: &[u8]) -> RedisResult<Option<StreamState>> {
+        if self.url.is_none() {
+            return Ok(None);
+        }
+        let result = self.try_stream(db, key);
+        if result.is_err() {
+            self.conn = None;
+        }
+        result
+    }
+
+    fn try_stream(&mut self, db: i64, key: &[u8]) -> RedisResult<Option<StreamState>> {
+        let conn = self.connection(db)?;
+        let info = match redis::cmd("XINFO").arg("STREAM").arg(key).query::<Value>(conn) {
+            Ok(info) => fields(info),
+            Err(err) if err.to_string().contains("no such key") => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let last_entry_id = match info.get("last-entry") {
+            Some(Value::Bulk(entry)) => entry.get(0).map(text),
+            _ => None,
+        };
+        let mut state = StreamState {
+            length: info.get("length").map_or(0, int) as u64,
+            last_generated_id: info.get("last-generated-id").map(text).unwrap_or_default(),
+            last_entry_id,
+            max_deleted_id: info.get("max-deleted-entry-id").map(text),
+            entries_added: info.get("entries-added").map(|value| int(value) as u64),
+            groups: Vec::new(),
+        };
+        let groups: Vec<Value> = redis::cmd("XINFO").arg("GROUPS").arg(key).query(conn)?;
+        for group in groups {
+            let group = fields(group);
+            let name = group.get("name").map(bytes).unwrap_or_default();
+            let mut idle_consumers = Vec::new();
+            if state.entries_added.is_some() {
+                let consumers: Vec<Value> = redis::cmd("XINFO")
+                    .arg("CONSUMERS")
+                    .arg(key)
+                    .arg(name.as_slice())
+                    .query(conn)?;
+                for consumer in consumers {
+                    let consumer = fields(consumer);
+                    if consumer.get("pending").map_or(0, int) == 0 {
+                        idle_consumers.push(consumer.get("name").map(bytes).unwrap_or_default());
+                    }
+                }
+            }
+            let pending = pending(conn, key, &name, group.get("pending").map_or(0, int))?;
+            state.groups.push(GroupState {
+                last_delivered_id: group.get("last-delivered-id").map(text).unwrap_or_default(),
+                entries_read: match group.get("entries-read") {
+                    Some(Value::Int(n)) => Some(*n as u64),
+                    _ => None,
+                },
+                name,
+                idle_consumers,
+                pending,
+            });
+        }
+        Ok(Some(state))
+    }
+
+    // 离线文件作为源时返回错误
+    fn connection(&mut self, db: i64) -> RedisResult<&mut Connection> {
+        if self.conn.is_none() {
+            let url = match &self.url {
+                Some(url) => url,
+                None => {
+                    return Err(RedisError::from((
+                        ErrorKind::ClientError,
+                        "离线文件作为源, 无法读取源Redis",
+                    )))
+                }
+            };
+            self.conn = Some(redis::Client::open(url.as_str())?.get_connection()?);
+            self.db = None;
+        }
+        let conn = self.conn.as_mut().unwrap();
+        if self.db != Some(db) {
+            redis::cmd("SELECT").arg(db).query::<()>(conn)?;
+            self.db = Some(db);
+        }
+        Ok(conn)
+    }
+}
+
+// 消费组的全部pending消息, 每次最多读取PENDING_BATCH条
+fn pending(
+    conn: &mut Connection, key: &[u8], group: &[u8], count: i64,
+) -> RedisResult<Vec<(String, Vec<u8>, i64, i64)>> {
+    let mut pending = Vec::with_capacity(count as usize);
+    let mut start = "-".to_string();
+    while (pending.len() as i64) < count {
+        let batch: Vec<(String, Vec<u8>, i64, i64)> = redis::cmd("XPENDING")
+            .arg(key)
+            .arg(group)
+            .arg(start.as_str())
+            .arg("+")
+            .arg(PENDING_BATCH)
+            .query(conn)?;
+        let last = match batch.last() {
+            Some((id, _, _, _)) => id.clone(),
+            None => break,
+        };
+        let full = batch.len() == PENDING_BATCH;
+        pending.extend(batch);
+        if !full {
+            break;
+        }
+        // 从下一个ID继续读取
+        let mut parts = last.splitn(2, '-');
+        let ms = parts.next().unwrap_or("0");
+        let seq: u64 = parts.next().and_then(|seq| seq.parse().ok()).unwrap_or(0);
+        start = format!("{}-{}", ms, seq + 1);
+    }
+    Ok(pending)
+}
+
+// XINFO的回复为交替的字段名与值
+fn fields(value: Value) -> HashMap<String, Value> {
+    let mut fields = HashMap::new();
+    if let Value::Bulk(items) = value {
+        let mut items = items.into_iter();
+        while let (Some(name), Some(value)) = (items.next(), items.next()) {
+            fields.insert(text(&name), value);
+        }
+    }
+    fields
+}
+
+fn bytes(value: &Value) -> Vec<u8> {
+    match value {
+        Value::Data(data) => data.clone(),
+        Value::Status(status) => status.as_bytes().to_vec(),
+        _ => Vec::new(),
+    }
+}
+
+fn text(value: &Value) -> String {
+    String::from_utf8_lossy(&bytes(value)).into_owned()
+}
+
+fn int(value: &Value) -> i64 {
+    match value {
+        Value::Int(n) => *n,
+        _ => 0,
+    }
+}
+
+// 命令最终无法被复制时, 输出错误日志并计数
+pub(crate) fn report_dropped(cmd: &Command, reason: &str) {
+    let name = command::name(cmd).to_uppercase();
+    let keys: Vec<_> = command::keys(cmd)
+        .iter()
+        .map(|key| String::from_utf8_lossy(key).into_owned())
+        .collect();
+    error!("命令{}无法被复制, 已丢弃: {}, keys: {:?}", name, reason, keys);
+    metrics::DROPPED_COMMANDS.with_label_values(&[name.as_str()]).inc();
+}
+
+// 源Cluster中的节点, 由CLUSTER NODES的输出解析而来
+#[derive(Clone, Debug)]
+pub(crate) struct ClusterNode {
+    pub(crate) url: String,
+    pub(crate) addr: String,
+    pub(crate) master: bool,
+    pub(crate) slots: Vec<(u16, u16)>,
+}
+
+// 以Cluster作为源时, 每个master对应一个listener. 记录该master负责的一个slot, 以便在故障转移后找到新的master
+#[derive(Clone, Debug)]
+pub(crate) struct ClusterSource {
+    pub(crate) slot: u16,
+    pub(crate) nodes: Vec<String>,
+}
+
+impl ClusterSource {
+    // 获取当前负责该slot的master, 与current不同时说明发生了故障转移
+    pub(crate) fn failover(&self, current: &str) -> Option<ClusterNode> {
+        let nodes = match cluster_nodes(&self.nodes) {
+            Ok(nodes) => nodes,
+            Err(err) => {
+                warn!("获取源Cluster的拓扑失败: {}", err);
+                return None;
+            }
+        };
+        nodes
+            .into_iter()
+            .filter(|node| node.master && node.addr != current)
+            .find(|node| {
+                node.slots
+                    .iter()
+                    .any(|(start, end)| *start <= self.slot && self.slot <= *end)
+            })
+    }
+}
+
+// 获取源Cluster中所有负责slot的master, 每个master对应一个需要复制的源
+pub(crate) fn cluster_masters(seed: &str) -> Vec<(String, ClusterSource)> {
+    let nodes = match cluster_nodes(&[seed.to_string()]) {
+        Ok(nodes) => nodes,
+        Err(err) => panic!("获取源Cluster的拓扑失败: {}", err),
+    };
+    // 故障转移之后, 可通过任意一个已知的节点查询新的拓扑
+    let mut known = vec![seed.to_string()];
+    known.extend(nodes.iter().map(|node| node.url.clone()));
+    let masters: Vec<(String, ClusterSource)> = nodes
+        .into_iter()
+        .filter(|node| node.master && !node.slots.is_empty())
+        .map(|node| {
+            let source = ClusterSource {
+                slot: node.slots[0].0,
+                nodes: known.clone(),
+            };
+            (node.url, source)
+        })
+        .collect();
+    if masters.is_empty() {
+        panic!("源Cluster中没有负责slot的master");
+    }
+    info!("源Cluster中的master数量: {}", masters.len());
+    masters
+}
+
+// 依次尝试nodes中的节点执行CLUSTER NODES, 新节点的URL沿用第一个节点URL中的认证与TLS配置
+fn cluster_nodes(nodes: &[String]) -> RedisResult<Vec<ClusterNode>> {
+    let mut last_err = None;
+    for node in nodes {
+        let result = redis::Client::open(node.as_str())
+            .and_then(|client| client.get_connection())
+            .and_then(|mut conn| redis::cmd("CLUSTER").arg("NODES").query::<String>(&mut conn));
+        match result {
+            Ok(output) => return Ok(parse_nodes(&output, &nodes[0])),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| RedisError::from((ErrorKind::ClientError, "没有可用的Cluster节点"))))
+}
+
+// <id> <ip:port@cport[,hostname]> <flags> <master> <ping-sent> <pong-recv> <config-epoch> <link-state> <slot> ...
+fn parse_nodes(output: &str, template: &str) -> Vec<ClusterNode> {
+    let template = Url::parse(template).expect("无效的Redis URL");
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 8 {
+                return None;
+            }
+            let flags: Vec<&str> = fields[2].split(',').collect();
+            if flags
+                .iter()
+                .any(|flag| *flag == "fail" || *flag == "noaddr" || *flag == "handshake")
+            {
+                return None;
+            }
+            let addr = fields[1].split(|c| c == '@' || c == ',').next()?;
+            let i = addr.rfind(':')?;
+            let port = addr[i + 1..].parse::<u16>().ok()?;
+            // 节点不知道自身的IP时地址为空, 此时使用所查询节点的地址
+            let host = match &addr[..i] {
+                "" => template.host_str()?.to_string(),
+                host => host.to_string(),
+            };
+            let mut url = template.clone();
+            url.set_host(Some(&host)).ok()?;
+            url.set_port(Some(port)).ok()?;
+            let slots = fields[8..]
+                .iter()
+                // [slot->-node]表示正在迁移的slot
+                .filter(|slot| !slot.starts_with('['))
+                .filter_map(|slot| match slot.find('-') {
+                    Some(i) => Some((slot[..i].parse().ok()?, slot[i + 1..].parse().ok()?)),
+                    None => slot.parse().ok().map(|slot| (slot, slot)),
+                })
+                .collect();
+            Some(ClusterNode {
+                url: url.to_string(),
+                addr: format!("{}:{}", host, port),
+                master: flags.contains(&"master"),
+                slots,
+            })
+        })
+        .collect()
+}
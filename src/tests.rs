@@ -1,13 +1,26 @@
 #[cfg(test)]
 mod integrate_tests {
+    use std::collections::HashMap;
     use std::process::Command;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
     use std::thread;
     use std::time::Duration;
 
     use r2d2_redis::redis::RedisResult;
-    use redis::Commands;
+    use redis::{Commands, Value};
+    use redis_event::cmd::keys::RENAME;
+    use redis_event::{Event, EventHandler};
 
-    use crate::{run, Opt};
+    use crate::checkpoint::Flush;
+    use crate::conflict;
+    use crate::conflict::Policy;
+    use crate::filter::Filter;
+    use crate::rename::Renamer;
+    use crate::sharding::{Hashing, Layout, ShardStrategy};
+    use crate::worker::RetryConfig;
+    use crate::{repair, reshard, sharding, verify};
+    use crate::{run, Abort, Opt};
 
     #[test]
     fn test_standalone() {
@@ -23,19 +36,7 @@ mod integrate_tests {
 
         let _: () = con_s.set("my_key", 42).unwrap();
 
-        let opt = Opt {
-            source: source.to_string(),
-            targets: vec![target.to_string()],
-            discard_rdb: false,
-            aof: false,
-            log_file: None,
-            sharding: false,
-            cluster: false,
-            batch_size: 100,
-            flush_interval: 100,
-            identity: None,
-            identity_passwd: None,
-        };
+        let opt = new_opt(&[source], &[target]);
         run(opt);
 
         let client_t = redis::Client::open(target).unwrap();
@@ -66,17 +67,8 @@ mod integrate_tests {
         let _: () = con_s.set("test_sharding", 42).unwrap();
 
         let opt = Opt {
-            source: source.to_string(),
-            targets: vec![target.to_string(), target1.to_string()],
-            discard_rdb: false,
-            aof: false,
-            log_file: None,
             sharding: true,
-            cluster: false,
-            batch_size: 100,
-            flush_interval: 100,
-            identity: None,
-            identity_passwd: None,
+            ..new_opt(&[source], &[target, target1])
         };
         run(opt);
 
@@ -100,6 +92,977 @@ mod integrate_tests {
         }
     }
 
+    #[test]
+    fn test_filter() {
+        let redis_source = start_redis_server(16579);
+        let redis_target = start_redis_server(16580);
+        let source = "redis://127.0.0.1:16579";
+        let target = "redis://127.0.0.1:16580";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+
+        let _: () = con_s.set("user:1", 1).unwrap();
+        let _: () = con_s.set("user:1:tmp", 2).unwrap();
+        let _: () = con_s.set("order:1", 3).unwrap();
+        let _: () = con_s.rpush("user:list", "a").unwrap();
+
+        let opt = Opt {
+            include: vec!["user:*".to_string()],
+            exclude: vec!["*:tmp".to_string()],
+            exclude_regex: vec!["list$".to_string()],
+            ..new_opt(&[source], &[target])
+        };
+        run(opt);
+
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let user: RedisResult<i32> = con_t.get("user:1");
+        let tmp: RedisResult<Option<i32>> = con_t.get("user:1:tmp");
+        let order: RedisResult<Option<i32>> = con_t.get("order:1");
+        let list: RedisResult<bool> = con_t.exists("user:list");
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        assert_eq!(user, Ok(1));
+        assert_eq!(tmp, Ok(None));
+        assert_eq!(order, Ok(None));
+        assert_eq!(list, Ok(false));
+    }
+
+    #[test]
+    fn test_rename() {
+        let redis_source = start_redis_server(16779);
+        let redis_target = start_redis_server(16780);
+        let source = "redis://127.0.0.1:16779";
+        let target = "redis://127.0.0.1:16780";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+
+        let _: () = con_s.set("user:1", 1).unwrap();
+        let _: () = con_s.sadd("order:1", "a").unwrap();
+        let _: () = con_s.expire("order:1", 3600).unwrap();
+
+        let opt = Opt {
+            key_prefix: Some("tenantA:".to_string()),
+            rename_regex: Some("^user:(\\d+)$".to_string()),
+            rename_replacement: Some("member:$1".to_string()),
+            ..new_opt(&[source], &[target])
+        };
+        run(opt);
+
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let member: RedisResult<i32> = con_t.get("tenantA:member:1");
+        let order: RedisResult<bool> = con_t.sismember("tenantA:order:1", "a");
+        let ttl: RedisResult<i64> = con_t.ttl("tenantA:order:1");
+        let old: RedisResult<bool> = con_t.exists("user:1");
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        assert_eq!(member, Ok(1));
+        assert_eq!(order, Ok(true));
+        assert!(ttl.unwrap() > 0);
+        assert_eq!(old, Ok(false));
+    }
+
+    #[test]
+    fn test_db_mapping() {
+        let redis_source = start_redis_server(16679);
+        let redis_target = start_redis_server(16680);
+        let target = "redis://127.0.0.1:16680";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open("redis://127.0.0.1:16679/3").unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        let _: () = con_s.set("in_db3", 3).unwrap();
+        let client_s1 = redis::Client::open("redis://127.0.0.1:16679/1").unwrap();
+        let mut con_s1 = client_s1.get_connection().unwrap();
+        let _: () = con_s1.set("in_db1", 1).unwrap();
+
+        let mut db_mapping = HashMap::new();
+        db_mapping.insert(3, 0);
+        let opt = Opt {
+            dbs: Some(vec![3]),
+            db_mapping,
+            ..new_opt(&["redis://127.0.0.1:16679"], &[target])
+        };
+        run(opt);
+
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let in_db3: RedisResult<i32> = con_t.get("in_db3");
+        let client_t1 = redis::Client::open("redis://127.0.0.1:16680/1").unwrap();
+        let mut con_t1 = client_t1.get_connection().unwrap();
+        let in_db1: RedisResult<Option<i32>> = con_t1.get("in_db1");
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        assert_eq!(in_db3, Ok(3));
+        assert_eq!(in_db1, Ok(None));
+    }
+
+    #[test]
+    fn test_multiple_sources() {
+        let redis_source = start_redis_server(16879);
+        let redis_source1 = start_redis_server(16880);
+        let redis_target = start_redis_server(16881);
+        let source = "redis://127.0.0.1:16879";
+        let source1 = "redis://127.0.0.1:16880";
+        let target = "redis://127.0.0.1:16881";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        let _: () = con_s.set("from_shard0", 0).unwrap();
+        let client_s1 = redis::Client::open(source1).unwrap();
+        let mut con_s1 = client_s1.get_connection().unwrap();
+        let _: () = con_s1.set("from_shard1", 1).unwrap();
+
+        let opt = new_opt(&[source, source1], &[target]);
+        run(opt);
+
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let from_shard0: RedisResult<i32> = con_t.get("from_shard0");
+        let from_shard1: RedisResult<i32> = con_t.get("from_shard1");
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_source1);
+        shutdown_redis(redis_target);
+
+        assert_eq!(from_shard0, Ok(0));
+        assert_eq!(from_shard1, Ok(1));
+    }
+
+    #[test]
+    fn test_reshard() {
+        let redis_source = start_redis_server(16979);
+        let redis_target = start_redis_server(16980);
+        let source = "redis://127.0.0.1:16979";
+        let target = "redis://127.0.0.1:16980";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        for i in 0..20 {
+            let _: () = con_s.set(format!("reshard_{}", i), i).unwrap();
+        }
+
+        // 源Redis同时也是新布局中的第一个分片
+        let opt = Opt {
+            sharding: true,
+            reshard: true,
+            reshard_delete: true,
+            ..new_opt(&[source], &[source, target])
+        };
+        run(opt);
+
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let mut results = Vec::new();
+        for i in 0..20 {
+            let key = format!("reshard_{}", i);
+            let in_source: Option<i32> = con_s.get(&key).unwrap();
+            let in_target: Option<i32> = con_t.get(&key).unwrap();
+            results.push((i, in_source, in_target));
+        }
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        // 每个key只保留在其所属的分片中
+        for (i, in_source, in_target) in &results {
+            assert!(*in_source == Some(*i) && *in_target == None || *in_source == None && *in_target == Some(*i));
+        }
+        assert!(results.iter().any(|(_, in_source, _)| in_source.is_some()));
+        assert!(results.iter().any(|(_, _, in_target)| in_target.is_some()));
+    }
+
+    #[test]
+    fn test_reshard_source_alias() {
+        let redis_source = start_redis_server(18479);
+        let redis_target = start_redis_server(18480);
+        // 源Redis在--target中以另一种写法出现
+        let source = "redis://localhost:18479";
+        let targets = ["redis://127.0.0.1:18479", "redis://127.0.0.1:18480"];
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        for i in 0..20 {
+            let _: () = con_s.rpush(format!("reshard_alias_{}", i), i).unwrap();
+        }
+
+        let opt = Opt {
+            sharding: true,
+            reshard: true,
+            reshard_delete: true,
+            ..new_opt(&[source], &targets)
+        };
+        run(opt);
+
+        let client_t = redis::Client::open(targets[1]).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let mut results = Vec::new();
+        for i in 0..20 {
+            let key = format!("reshard_alias_{}", i);
+            let in_source: Vec<i32> = con_s.lrange(&key, 0, -1).unwrap();
+            let in_target: Vec<i32> = con_t.lrange(&key, 0, -1).unwrap();
+            results.push((i, in_source, in_target));
+        }
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        // 仍属于源分片的list不会被重新追加, 已迁移的list从源Redis中删除
+        for (i, in_source, in_target) in &results {
+            assert!(*in_source == vec![*i] && in_target.is_empty() || in_source.is_empty() && *in_target == vec![*i]);
+        }
+        assert!(results.iter().any(|(_, in_source, _)| !in_source.is_empty()));
+        assert!(results.iter().any(|(_, _, in_target)| !in_target.is_empty()));
+    }
+
+    #[test]
+    fn test_reshard_collections() {
+        let redis_source = start_redis_server(18279);
+        let redis_target = start_redis_server(18280);
+        let source = "redis://127.0.0.1:18279";
+        let target = "redis://127.0.0.1:18280";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        // 目的Redis使用不同的编码方式, 使得相同的值DUMP的结果不同
+        for (name, value) in &[
+            ("hash-max-ziplist-entries", "0"),
+            ("set-max-intset-entries", "0"),
+            ("zset-max-ziplist-entries", "0"),
+        ] {
+            let _: () = redis::cmd("CONFIG")
+                .arg("SET")
+                .arg(*name)
+                .arg(*value)
+                .query(&mut con_t)
+                .unwrap();
+        }
+
+        let layout = Layout::new(
+            &[source.to_string(), target.to_string()],
+            ShardStrategy::Jedis,
+            Hashing::Murmur,
+            None,
+        );
+        // 属于目的分片的key
+        let key = |prefix: &str| {
+            (0..)
+                .map(|i| format!("{}_{}", prefix, i))
+                .find(|key| layout.owner(key.as_bytes()) == 1)
+                .unwrap()
+        };
+        let (hash, set, zset, differ) = (
+            key("reshard_hash"),
+            key("reshard_set"),
+            key("reshard_zset"),
+            key("reshard_differ"),
+        );
+        let _: () = con_s.hset_multiple(&hash, &[("a", "1"), ("b", "2")]).unwrap();
+        let _: () = con_t.hset_multiple(&hash, &[("b", "2"), ("a", "1")]).unwrap();
+        let _: () = con_s.sadd(&set, vec![1, 2, 3]).unwrap();
+        let _: () = con_t.sadd(&set, vec![3, 2, 1]).unwrap();
+        let _: () = con_s.zadd_multiple(&zset, &[(1, "a"), (2, "b")]).unwrap();
+        let _: () = con_t.zadd_multiple(&zset, &[(2, "b"), (1, "a")]).unwrap();
+        let _: () = con_s.hset(&differ, "a", "1").unwrap();
+        let _: () = con_t.hset(&differ, "a", "2").unwrap();
+
+        reshard::delete_moved(source, &layout, &Filter::default(), &Renamer::default());
+        let remained: Vec<bool> = [&hash, &set, &zset, &differ]
+            .iter()
+            .map(|key| con_s.exists(key.as_str()).unwrap())
+            .collect();
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        // 值一致的集合类型key已删除, 值不一致的key保留
+        assert_eq!(remained, vec![false, false, false, true]);
+    }
+
+    #[test]
+    fn test_verify() {
+        let redis_source = start_redis_server(17079);
+        let redis_target = start_redis_server(17080);
+        let source = "redis://127.0.0.1:17079";
+        let target = "redis://127.0.0.1:17080";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        let _: () = con_s.set("verify_string", "value").unwrap();
+        let _: () = con_s.hset("verify_hash", "field", "value").unwrap();
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let _: () = con_t.set("verify_string", "value").unwrap();
+        let _: () = con_t.hset("verify_hash", "field", "other").unwrap();
+
+        let opt = new_opt(&[source], &[target]);
+        let differ = verify::run(&opt);
+        let _: () = con_t.hset("verify_hash", "field", "value").unwrap();
+        let consistent = verify::run(&opt);
+        let _: () = con_t.set("verify_extra", "value").unwrap();
+        let extra = verify::run(&opt);
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        assert!(!differ);
+        assert!(consistent);
+        assert!(!extra);
+    }
+
+    #[test]
+    fn test_verify_scope() {
+        let redis_source = start_redis_server(18379);
+        let redis_target = start_redis_server(18380);
+        let source = "redis://127.0.0.1:18379";
+        let target = "redis://127.0.0.1:18380";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        let _: () = con_s.set("scope_key", "value").unwrap();
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let _: () = con_t.set("copy:scope_key", "value").unwrap();
+        // 目的Redis中不属于本次复制的数据: 没有前缀的key、不满足include的key, 以及其他db中的key
+        let _: () = con_t.set("scope_key", "value").unwrap();
+        let _: () = con_t.set("copy:other_key", "value").unwrap();
+        let _: () = redis::cmd("SELECT").arg(1).query(&mut con_t).unwrap();
+        let _: () = con_t.set("copy:scope_other_db", "value").unwrap();
+        let _: () = redis::cmd("SELECT").arg(0).query(&mut con_t).unwrap();
+
+        let opt = Opt {
+            include: vec!["scope_*".to_string()],
+            dbs: Some(vec![0]),
+            key_prefix: Some("copy:".to_string()),
+            ..new_opt(&[source], &[target])
+        };
+        let consistent = verify::run(&opt);
+        let _: () = con_t.set("copy:scope_extra", "value").unwrap();
+        let extra = verify::run(&opt);
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        assert!(consistent);
+        assert!(!extra);
+    }
+
+    #[test]
+    fn test_repair() {
+        let redis_source = start_redis_server(17179);
+        let redis_target = start_redis_server(17180);
+        let source = "redis://127.0.0.1:17179";
+        let target = "redis://127.0.0.1:17180";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        let _: () = con_s.set("repair_string", "value").unwrap();
+        let _: () = con_s.hset("repair_hash", "field", "value").unwrap();
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let _: () = con_s.set_ex("repair_expire", "value", 3600).unwrap();
+        let _: () = con_t.hset("repair_hash", "field", "other").unwrap();
+        let _: () = con_t.set("repair_extra", "value").unwrap();
+        let _: () = con_t.set("unrelated", "value").unwrap();
+
+        let opt = Opt {
+            include: vec!["repair_*".to_string()],
+            ..new_opt(&[source], &[target])
+        };
+        // 默认只重新复制, 不删除多余的key
+        let repaired = repair::run(&opt);
+        let copied = verify::run(&opt);
+        let ttl: RedisResult<i64> = con_t.ttl("repair_expire");
+        let opt = Opt {
+            repair_delete: true,
+            ..opt
+        };
+        let deleted = repair::run(&opt);
+        let consistent = verify::run(&opt);
+        // 修复文件中复制范围之外的key不会被删除
+        let path = std::env::temp_dir().join("copy_redis_repair.txt");
+        std::fs::write(&path, "delete 127.0.0.1:17180 0 unrelated\n").unwrap();
+        let opt = Opt {
+            repair_file: Some(path.to_string_lossy().into_owned()),
+            ..opt
+        };
+        repair::run(&opt);
+        let unrelated: bool = con_t.exists("unrelated").unwrap();
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        assert!(repaired);
+        assert!(!copied);
+        assert!(ttl.unwrap() > 3500);
+        assert!(deleted);
+        assert!(consistent);
+        assert!(unrelated);
+    }
+
+    #[test]
+    fn test_source_rdb() {
+        let redis_source = start_redis_server(17279);
+        let redis_target = start_redis_server(17280);
+        let source = "redis://127.0.0.1:17279";
+        let target = "redis://127.0.0.1:17280";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        let _: () = con_s.set("from_rdb", 42).unwrap();
+        let _: () = con_s.rpush("from_rdb_list", &[1, 2, 3]).unwrap();
+        let _: () = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("dbfilename")
+            .arg("17279.rdb")
+            .query(&mut con_s)
+            .unwrap();
+        let _: () = redis::cmd("SAVE").query(&mut con_s).unwrap();
+        let (_, dir): (String, String) = redis::cmd("CONFIG").arg("GET").arg("dir").query(&mut con_s).unwrap();
+        let rdb = format!("{}/17279.rdb", dir);
+
+        let opt = Opt {
+            source_rdb: Some(rdb.clone()),
+            ..new_opt(&[], &[target])
+        };
+        run(opt);
+
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let value: RedisResult<i32> = con_t.get("from_rdb");
+        let list: RedisResult<Vec<i32>> = con_t.lrange("from_rdb_list", 0, -1);
+
+        let _ = std::fs::remove_file(&rdb);
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        assert_eq!(value, Ok(42));
+        assert_eq!(list, Ok(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_restore() {
+        let redis_source = start_redis_server(17779);
+        let redis_target = start_redis_server(17780);
+        let source = "redis://127.0.0.1:17779";
+        let target = "redis://127.0.0.1:17780";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        let _: () = con_s.set("restore_string", "value").unwrap();
+        let _: () = con_s.rpush("restore_list", &[1, 2, 3]).unwrap();
+        let _: () = con_s.zadd("restore_zset", "member", 1.5).unwrap();
+        let _: () = con_s.hset("restore_hash", "field", "value").unwrap();
+        let _: () = con_s.expire("restore_hash", 3600).unwrap();
+        let _: () = redis::cmd("XADD")
+            .arg("restore_stream")
+            .arg("1-1")
+            .arg("field")
+            .arg("value")
+            .query(&mut con_s)
+            .unwrap();
+
+        let opt = Opt {
+            restore: true,
+            ..new_opt(&[source], &[target])
+        };
+        run(opt);
+
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let string: RedisResult<String> = con_t.get("restore_string");
+        let list: RedisResult<Vec<i32>> = con_t.lrange("restore_list", 0, -1);
+        let score: RedisResult<f64> = con_t.zscore("restore_zset", "member");
+        let field: RedisResult<String> = con_t.hget("restore_hash", "field");
+        let ttl: RedisResult<i64> = con_t.ttl("restore_hash");
+        let stream: RedisResult<i64> = redis::cmd("XLEN").arg("restore_stream").query(&mut con_t);
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        assert_eq!(string, Ok("value".to_string()));
+        assert_eq!(list, Ok(vec![1, 2, 3]));
+        assert_eq!(score, Ok(1.5));
+        assert_eq!(field, Ok("value".to_string()));
+        assert!(ttl.unwrap() > 3500);
+        assert_eq!(stream, Ok(1));
+    }
+
+    #[test]
+    fn test_flush_target() {
+        let redis_source = start_redis_server(17979);
+        let redis_target = start_redis_server(17980);
+        let source = "redis://127.0.0.1:17979";
+        let target = "redis://127.0.0.1:17980";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        let _: () = con_s.set("fresh", "value").unwrap();
+
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let _: () = con_t.set("stale", "value").unwrap();
+
+        let opt = Opt {
+            flush_target: true,
+            ..new_opt(&[source], &[target])
+        };
+        run(opt);
+
+        let stale: RedisResult<bool> = con_t.exists("stale");
+        let fresh: RedisResult<String> = con_t.get("fresh");
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        assert_eq!(stale, Ok(false));
+        assert_eq!(fresh, Ok("value".to_string()));
+    }
+
+    #[test]
+    fn test_conflict() {
+        let redis_source = start_redis_server(17879);
+        let redis_target = start_redis_server(17880);
+        let source = "redis://127.0.0.1:17879";
+        let target = "redis://127.0.0.1:17880";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        let _: () = con_s.rpush("conflict_list", &["a", "b"]).unwrap();
+        let _: () = con_s.set("conflict_new", "new").unwrap();
+
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let _: () = con_t.rpush("conflict_list", "x").unwrap();
+
+        let opt = |conflict: Policy| Opt {
+            conflict,
+            ..new_opt(&[source], &[target])
+        };
+        run(opt(Policy::Skip));
+        let skipped: RedisResult<Vec<String>> = con_t.lrange("conflict_list", 0, -1);
+        let new: RedisResult<String> = con_t.get("conflict_new");
+
+        run(opt(Policy::Replace));
+        let replaced: RedisResult<Vec<String>> = con_t.lrange("conflict_list", 0, -1);
+
+        // error: 遇到已存在的key时停止复制, 之后的key都不再写入
+        let running = Arc::new(AtomicBool::new(true));
+        let abort = Abort::new(&running);
+        let mut error = conflict::new(&opt(Policy::Error), &abort).unwrap();
+        let absent = error.accept(0, b"conflict_absent");
+        let existing = error.accept(0, b"conflict_list");
+        let after = error.accept(0, b"conflict_absent");
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        assert_eq!(skipped, Ok(vec!["x".to_string()]));
+        assert_eq!(new, Ok("new".to_string()));
+        assert_eq!(replaced, Ok(vec!["a".to_string(), "b".to_string()]));
+        assert!(absent);
+        assert!(!existing);
+        assert!(!after);
+        assert!(abort.is_aborted());
+        assert!(!running.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_source_aof() {
+        let redis_target = start_redis_server(17380);
+        let target = "redis://127.0.0.1:17380";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let aof = "17379.aof";
+        let commands = "#TS:100\r\n*3\r\n$3\r\nSET\r\n$8\r\nfrom_aof\r\n$2\r\n42\r\n\
+                        #TS:200\r\n*3\r\n$3\r\nSET\r\n$10\r\nafter_time\r\n$1\r\n1\r\n";
+        std::fs::write(aof, commands).unwrap();
+
+        let opt = Opt {
+            source_aof: Some(aof.to_string()),
+            until_timestamp: Some(150),
+            aof: true,
+            ..new_opt(&[], &[target])
+        };
+        run(opt);
+
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let value: RedisResult<i32> = con_t.get("from_aof");
+        let after: RedisResult<bool> = con_t.exists("after_time");
+
+        let _ = std::fs::remove_file(aof);
+        shutdown_redis(redis_target);
+
+        assert_eq!(value, Ok(42));
+        assert_eq!(after, Ok(false));
+    }
+
+    #[test]
+    fn test_output_dir() {
+        let redis_source = start_redis_server(17479);
+        let redis_target = start_redis_server(17480);
+        let source = "redis://127.0.0.1:17479";
+        let target = "redis://127.0.0.1:17480";
+        let dir = "17479-backup";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        let _: () = con_s.set("backup_string", 42).unwrap();
+        let _: () = con_s.expire("backup_string", 3600).unwrap();
+        let _: () = con_s.rpush("backup_list", &[1, 2, 3]).unwrap();
+        let _: () = con_s.hset("backup_hash", "field", "value").unwrap();
+        let _: () = con_s.zadd("backup_zset", "member", 1.5).unwrap();
+        let _: () = redis::cmd("XADD")
+            .arg("backup_stream")
+            .arg("1-1")
+            .arg("field")
+            .arg("value")
+            .query(&mut con_s)
+            .unwrap();
+
+        let opt = Opt {
+            output_dir: Some(dir.to_string()),
+            ..new_opt(&[source], &[])
+        };
+        run(opt);
+
+        // 将保存的RDB文件作为源, 写入目的Redis
+        let rdb = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().map_or(false, |ext| ext == "rdb"))
+            .expect("没有生成RDB文件");
+        let opt = Opt {
+            source_rdb: Some(rdb.to_string_lossy().to_string()),
+            ..new_opt(&[], &[target])
+        };
+        run(opt);
+
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let value: RedisResult<i32> = con_t.get("backup_string");
+        let ttl: RedisResult<i64> = con_t.ttl("backup_string");
+        let list: RedisResult<Vec<i32>> = con_t.lrange("backup_list", 0, -1);
+        let hash: RedisResult<String> = con_t.hget("backup_hash", "field");
+        let score: RedisResult<f64> = con_t.zscore("backup_zset", "member");
+        let stream: RedisResult<i64> = redis::cmd("XLEN").arg("backup_stream").query(&mut con_t);
+
+        let _ = std::fs::remove_dir_all(dir);
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        assert_eq!(value, Ok(42));
+        assert!(ttl.unwrap() > 0);
+        assert_eq!(list, Ok(vec![1, 2, 3]));
+        assert_eq!(hash, Ok("value".to_string()));
+        assert_eq!(score, Ok(1.5));
+        assert_eq!(stream, Ok(1));
+    }
+
+    #[test]
+    fn test_output_json() {
+        let redis_source = start_redis_server(17579);
+        let source = "redis://127.0.0.1:17579";
+        let output = "17579.jsonl";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        let _: () = con_s.set("json_key", "json\"value").unwrap();
+
+        let opt = Opt {
+            output_json: Some(output.to_string()),
+            ..new_opt(&[source], &[])
+        };
+        run(opt);
+
+        let lines = std::fs::read_to_string(output).unwrap();
+        let _ = std::fs::remove_file(output);
+        shutdown_redis(redis_source);
+
+        assert!(lines.contains(r#""command":"SET","key":"json_key","args":["json_key","json\"value"]"#));
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        let redis_source = start_redis_server(17679);
+        let source = "redis://127.0.0.1:17679";
+        let output = "17679.jsonl";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        let _: () = con_s.rpush("chunk_list", &["a", "b", "c", "d", "e"]).unwrap();
+        let _: () = con_s.expire("chunk_list", 3600).unwrap();
+
+        let opt = Opt {
+            output_json: Some(output.to_string()),
+            chunk_size: 2,
+            ..new_opt(&[source], &[])
+        };
+        run(opt);
+
+        let lines = std::fs::read_to_string(output).unwrap();
+        let _ = std::fs::remove_file(output);
+        shutdown_redis(redis_source);
+
+        // 5个元素拆分为3条RPUSH, 过期时间在最后一条之后设置
+        let commands: Vec<&str> = lines
+            .lines()
+            .filter(|line| line.contains(r#""key":"chunk_list""#))
+            .filter_map(|line| line.split(r#""command":""#).nth(1))
+            .filter_map(|rest| rest.split('"').next())
+            .collect();
+        assert_eq!(commands, vec!["RPUSH", "RPUSH", "RPUSH", "PEXPIREAT"]);
+        assert!(lines.contains(r#""args":["chunk_list","e"]"#));
+    }
+
+    #[test]
+    fn test_stream_state() {
+        let redis_source = start_redis_server(18079);
+        let redis_target = start_redis_server(18080);
+        let source = "redis://127.0.0.1:18079";
+        let target = "redis://127.0.0.1:18080";
+
+        thread::sleep(Duration::from_secs(5));
+
+        let client_s = redis::Client::open(source).unwrap();
+        let mut con_s = client_s.get_connection().unwrap();
+        for id in &["1-1", "2-1", "3-1"] {
+            let _: String = redis::cmd("XADD")
+                .arg("stream")
+                .arg(*id)
+                .arg("f")
+                .arg("v")
+                .query(&mut con_s)
+                .unwrap();
+        }
+        let _: () = redis::cmd("XDEL").arg("stream").arg("3-1").query(&mut con_s).unwrap();
+        let _: () = redis::cmd("XGROUP")
+            .arg("CREATE")
+            .arg("stream")
+            .arg("group")
+            .arg("0")
+            .query(&mut con_s)
+            .unwrap();
+        let _: Value = redis::cmd("XREADGROUP")
+            .arg("GROUP")
+            .arg("group")
+            .arg("consumer")
+            .arg("COUNT")
+            .arg(1)
+            .arg("STREAMS")
+            .arg("stream")
+            .arg(">")
+            .query(&mut con_s)
+            .unwrap();
+        // 第二次投递
+        let _: Value = redis::cmd("XCLAIM")
+            .arg("stream")
+            .arg("group")
+            .arg("consumer")
+            .arg(0)
+            .arg("1-1")
+            .query(&mut con_s)
+            .unwrap();
+
+        let opt = new_opt(&[source], &[target]);
+        run(opt);
+
+        let client_t = redis::Client::open(target).unwrap();
+        let mut con_t = client_t.get_connection().unwrap();
+        let pending: RedisResult<Vec<(String, String, i64, i64)>> = redis::cmd("XPENDING")
+            .arg("stream")
+            .arg("group")
+            .arg("-")
+            .arg("+")
+            .arg(10)
+            .query(&mut con_t);
+        // stream的last id为已删除的3-1
+        let readd: RedisResult<String> = redis::cmd("XADD")
+            .arg("stream")
+            .arg("3-1")
+            .arg("f")
+            .arg("v")
+            .query(&mut con_t);
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+
+        let pending = pending.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].0, "1-1");
+        assert_eq!(pending[0].1, "consumer");
+        assert_eq!(pending[0].3, 2);
+        assert!(readd.is_err());
+    }
+
+    #[test]
+    fn test_cross_shard_rename() {
+        let redis_source = start_redis_server(18179);
+        let redis_target = start_redis_server(18180);
+        let redis_target1 = start_redis_server(18181);
+        let source = "redis://127.0.0.1:18179";
+        let targets = vec![
+            "redis://127.0.0.1:18180".to_string(),
+            "redis://127.0.0.1:18181".to_string(),
+        ];
+
+        thread::sleep(Duration::from_secs(5));
+
+        // RENAME的两个key属于不同的分片, 无法直接执行, 需从源Redis读取key的最新值
+        let layout = Layout::new(&targets, ShardStrategy::Jedis, Hashing::Murmur, None);
+        let key = "rename_from";
+        let new_key = (0..)
+            .map(|i| format!("rename_to_{}", i))
+            .find(|new_key| layout.owner(new_key.as_bytes()) != layout.owner(key.as_bytes()))
+            .unwrap();
+        let mut con_s = redis::Client::open(source).unwrap().get_connection().unwrap();
+        let _: () = con_s.set(&new_key, "value").unwrap();
+        let mut con_t: Vec<redis::Connection> = targets
+            .iter()
+            .map(|target| redis::Client::open(target.as_str()).unwrap().get_connection().unwrap())
+            .collect();
+        let _: () = con_t[layout.owner(key.as_bytes())].set(key, "value").unwrap();
+
+        let retry = RetryConfig {
+            max_retries: 0,
+            interval: 0,
+            dead_letter_dir: None,
+        };
+        let running = Arc::new(AtomicBool::new(true));
+        let workers = sharding::new_workers(targets.clone(), 100, 100, 10000, retry, Arc::clone(&running));
+        let mut handler = sharding::new_sharded(
+            Some(source),
+            &workers.senders,
+            &targets,
+            Filter::default(),
+            Renamer::default(),
+            1000,
+            None,
+            None,
+            ShardStrategy::Jedis,
+            Hashing::Murmur,
+            None,
+            false,
+            Abort::new(&running),
+        );
+        handler.handle(Event::AOF(redis_event::cmd::Command::RENAME(RENAME {
+            key: key.as_bytes(),
+            new_key: new_key.as_bytes(),
+        })));
+        handler.flush();
+        drop(handler);
+        drop(workers);
+
+        let old: RedisResult<bool> = con_t[layout.owner(key.as_bytes())].exists(key);
+        let new: RedisResult<String> = con_t[layout.owner(new_key.as_bytes())].get(&new_key);
+
+        shutdown_redis(redis_source);
+        shutdown_redis(redis_target);
+        shutdown_redis(redis_target1);
+
+        assert_eq!(old, Ok(false));
+        assert_eq!(new, Ok("value".to_string()));
+    }
+
+    // 各选项均为默认值的Opt, 测试中只需覆盖用到的选项
+    fn new_opt(sources: &[&str], targets: &[&str]) -> Opt {
+        Opt {
+            sources: sources.iter().map(|source| source.to_string()).collect(),
+            source_cluster: false,
+            source_rdb: None,
+            source_aof: None,
+            until_timestamp: None,
+            targets: targets.iter().map(|target| target.to_string()).collect(),
+            output_dir: None,
+            output_json: None,
+            json_base64: false,
+            rotate_size: 0,
+            rotate_interval: 0,
+            discard_rdb: false,
+            aof: false,
+            log_file: None,
+            sharding: false,
+            shard_strategy: ShardStrategy::Jedis,
+            shard_hash: Hashing::Murmur,
+            shard_key_tag: None,
+            reshard: false,
+            reshard_delete: false,
+            verify_dump: false,
+            repair_file: None,
+            repair_delete: false,
+            cluster: false,
+            batch_size: 100,
+            flush_interval: 100,
+            queue_size: 10000,
+            chunk_size: 1000,
+            restore: false,
+            conflict: Policy::Append,
+            flush_target: false,
+            max_retries: 3,
+            retry_interval: 500,
+            dead_letter: None,
+            checkpoint_interval: 60,
+            include: vec![],
+            exclude: vec![],
+            include_regex: vec![],
+            exclude_regex: vec![],
+            dbs: None,
+            db_mapping: HashMap::new(),
+            key_prefix: None,
+            rename_regex: None,
+            rename_replacement: None,
+            metrics: None,
+            identity: None,
+            identity_passwd: None,
+        }
+    }
+
     fn start_redis_server(port: u16) -> u32 {
         // redis-server --port 6379 --daemonize no --dbfilename rdb --dir ./tests/rdb
         let child = Command::new("redis-server")
@@ -124,3 +1087,89 @@ mod integrate_tests {
             .expect("kill redis failed");
     }
 }
+
+// 按照脚本回复命令的模拟Redis, 用于构造难以在真实Redis中出现的响应, 如可重试的错误、MOVED/ASK重定向及连接中断
+#[cfg(test)]
+pub(crate) mod fake_redis {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    pub(crate) struct FakeRedis {
+        pub(crate) addr: String,
+        // 收到的命令(不包括PING及SELECT)及其所在的db
+        pub(crate) received: Arc<Mutex<Vec<(i64, Vec<String>)>>>,
+    }
+
+    // PING与SELECT由模拟的Redis自行回复, 其他命令以respond返回的RESP作为响应, 返回None时关闭连接
+    pub(crate) fn start<F>(respond: F) -> FakeRedis
+    where
+        F: FnMut(i64, &[String]) -> Option<String> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let respond = Arc::new(Mutex::new(respond));
+        let commands = Arc::clone(&received);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let respond = Arc::clone(&respond);
+                        let received = Arc::clone(&commands);
+                        thread::spawn(move || serve(stream, respond, received));
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+        FakeRedis { addr, received }
+    }
+
+    fn serve<F>(stream: TcpStream, respond: Arc<Mutex<F>>, received: Arc<Mutex<Vec<(i64, Vec<String>)>>>)
+    where
+        F: FnMut(i64, &[String]) -> Option<String>,
+    {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut db = 0;
+        while let Some(args) = read_command(&mut reader) {
+            let reply = match args[0].to_ascii_uppercase().as_str() {
+                "PING" => Some("+PONG\r\n".to_string()),
+                "SELECT" => {
+                    db = args[1].parse().unwrap();
+                    Some("+OK\r\n".to_string())
+                }
+                _ => {
+                    received.lock().unwrap().push((db, args.clone()));
+                    let mut respond = respond.lock().unwrap();
+                    (*respond)(db, &args)
+                }
+            };
+            match reply {
+                Some(reply) if writer.write_all(reply.as_bytes()).is_ok() => {}
+                _ => break,
+            }
+        }
+    }
+
+    fn read_command(reader: &mut impl BufRead) -> Option<Vec<String>> {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let n: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+        let mut args = Vec::with_capacity(n);
+        for _ in 0..n {
+            line.clear();
+            reader.read_line(&mut line).ok()?;
+            let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg).ok()?;
+            arg.truncate(len);
+            args.push(String::from_utf8(arg).ok()?);
+        }
+        Some(args)
+    }
+}
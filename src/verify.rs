@@ -0,0 +1,485 @@
+use std::collections::{HashMap, HashSet};
+
+use log::{error, info, warn};
+use redis::{Connection, ErrorKind, RedisError, RedisResult, Value};
+
+use crate::cluster::ClusterWriter;
+use crate::filter::Filter;
+use crate::rename::Renamer;
+use crate::repair;
+use crate::repair::Action;
+use crate::reshard;
+use crate::sharding;
+use crate::sharding::Layout;
+use crate::source;
+use crate::Opt;
+
+// 源与目的Redis中key的剩余过期时间之差在此范围内时视为一致(毫秒)
+const TTL_TOLERANCE: i64 = 1000;
+
+// copy-redis verify: 以SCAN遍历源Redis, 按照与复制时相同的规则(过滤、db映射、重命名、分片)在目的Redis中找到对应的key,
+// 比较类型、过期时间及值, 输出缺失、多余及不一致的key. 所有key均一致时返回true
+pub(crate) fn run(opt: &Opt) -> bool {
+    let (divergences, completed) = diff(opt);
+    if let Some(path) = &opt.repair_file {
+        let actions: Vec<Action> = divergences.iter().map(Divergence::action).collect();
+        match repair::write_repair_file(path, &actions) {
+            Ok(_) => info!("修复命令已写入: {}", path),
+            Err(err) => error!("写入修复命令失败: {}", err),
+        }
+    }
+    completed && divergences.is_empty()
+}
+
+// 返回所有不一致的key, 以及校验是否完整地进行
+pub(crate) fn diff(opt: &Opt) -> (Vec<Divergence>, bool) {
+    let mut verifier = Verifier {
+        dump: opt.verify_dump,
+        filter: Filter::new(
+            &opt.include,
+            &opt.exclude,
+            &opt.include_regex,
+            &opt.exclude_regex,
+            opt.dbs.clone(),
+            opt.db_mapping.clone(),
+        ),
+        renamer: Renamer::new(
+            opt.key_prefix.clone(),
+            opt.rename_regex.clone(),
+            opt.rename_replacement.clone(),
+        ),
+        targets: Targets::new(opt),
+        seen: HashMap::new(),
+        checked: 0,
+        divergences: Vec::new(),
+    };
+    let mut completed = true;
+    for source in sources(opt) {
+        if let Err(err) = verifier.verify_source(&source) {
+            error!("校验源Redis({})失败: {}", addr(&source), err);
+            completed = false;
+        }
+    }
+    // 只有所有源都校验完成时, 才能确定目的Redis中哪些key是多余的
+    if completed {
+        if let Err(err) = verifier.find_extra() {
+            error!("查找目的Redis中多余的key失败: {}", err);
+            completed = false;
+        }
+    }
+    let (mut missing, mut differ, mut extra) = (0, 0, 0);
+    for divergence in &verifier.divergences {
+        match divergence.diff {
+            Diff::Missing => missing += 1,
+            Diff::Differ(_) => differ += 1,
+            Diff::Extra => extra += 1,
+        }
+    }
+    info!(
+        "校验完成, 已校验的key: {}, 缺失: {}, 不一致: {}, 多余: {}",
+        verifier.checked, missing, differ, extra
+    );
+    (verifier.divergences, completed)
+}
+
+pub(crate) fn sources(opt: &Opt) -> Vec<String> {
+    let mut sources = Vec::new();
+    for source in &opt.sources {
+        if opt.source_cluster {
+            sources.extend(source::cluster_masters(source).into_iter().map(|(url, _)| url));
+        } else {
+            sources.push(source.clone());
+        }
+    }
+    sources
+}
+
+pub(crate) fn addr(url: &str) -> String {
+    let (host, port) = sharding::host_port(url);
+    format!("{}:{}", host, port)
+}
+
+enum Diff {
+    Missing,
+    Differ(&'static str),
+    Extra,
+}
+
+// addr为key所在的源Redis(多余的key则为目的Redis)的地址, db与key同样对应于该Redis
+pub(crate) struct Divergence {
+    diff: Diff,
+    addr: String,
+    db: i64,
+    key: Vec<u8>,
+}
+
+impl Divergence {
+    // 修复该不一致所需的操作
+    pub(crate) fn action(&self) -> Action {
+        match self.diff {
+            Diff::Extra => Action::Delete {
+                target: self.addr.clone(),
+                db: self.db,
+                key: self.key.clone(),
+            },
+            _ => Action::Copy {
+                source: self.addr.clone(),
+                db: self.db,
+                key: self.key.clone(),
+            },
+        }
+    }
+
+    fn report(&self) {
+        let key = String::from_utf8_lossy(&self.key);
+        match self.diff {
+            Diff::Missing => warn!("目的Redis中缺少key, 源: {}, db: {}, key: {}", self.addr, self.db, key),
+            Diff::Differ(reason) => warn!("key{}, 源: {}, db: {}, key: {}", reason, self.addr, self.db, key),
+            Diff::Extra => warn!(
+                "目的Redis中多余的key, 目的: {}, db: {}, key: {}",
+                self.addr, self.db, key
+            ),
+        }
+    }
+}
+
+struct Verifier {
+    dump: bool,
+    filter: Filter,
+    renamer: Renamer,
+    targets: Targets,
+    // 源Redis中的key在目的Redis中对应的(节点, db)及key的摘要, 用于查找多余的key
+    seen: HashMap<(String, i64), HashSet<[u8; 16]>>,
+    checked: u64,
+    divergences: Vec<Divergence>,
+}
+
+impl Verifier {
+    fn verify_source(&mut self, url: &str) -> RedisResult<()> {
+        let addr = addr(url);
+        info!("开始校验源Redis({})", addr);
+        let mut conn = redis::Client::open(url)?.get_connection()?;
+        let mut divergences = Vec::new();
+        for db in reshard::keyspace(&mut conn)? {
+            if !self.filter.accept_db(db) {
+                continue;
+            }
+            redis::cmd("SELECT").arg(db).query::<()>(&mut conn)?;
+            let mut cursor = 0u64;
+            loop {
+                let (next, keys): (u64, Vec<Vec<u8>>) =
+                    redis::cmd("SCAN").arg(cursor).arg("COUNT").arg(1000).query(&mut conn)?;
+                for key in keys {
+                    if !self.filter.accept(&key) {
+                        continue;
+                    }
+                    self.checked += 1;
+                    if let Some(diff) = self.compare(&mut conn, db, &key)? {
+                        divergences.push(Divergence {
+                            diff,
+                            addr: addr.clone(),
+                            db,
+                            key,
+                        });
+                    }
+                }
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+        }
+        // 复制仍在进行时, 不一致可能只是暂时的, 因此再比较一次
+        self.targets.refresh();
+        for divergence in divergences {
+            redis::cmd("SELECT").arg(divergence.db).query::<()>(&mut conn)?;
+            if let Some(diff) = self.compare(&mut conn, divergence.db, &divergence.key)? {
+                let divergence = Divergence { diff, ..divergence };
+                divergence.report();
+                self.divergences.push(divergence);
+            }
+        }
+        Ok(())
+    }
+
+    fn compare(&mut self, conn: &mut Connection, db: i64, key: &[u8]) -> RedisResult<Option<Diff>> {
+        let source = match snapshot(conn, key, self.dump)? {
+            Some(source) => source,
+            // 已被删除或已过期
+            None => return Ok(None),
+        };
+        let new_key = self.renamer.rename(key);
+        let db = self.filter.map_db(db);
+        let node = self.targets.owner(&new_key)?;
+        self.seen
+            .entry((node.clone(), db))
+            .or_insert_with(HashSet::new)
+            .insert(md5::compute(&new_key).0);
+        let target = snapshot(self.targets.connection(&node, db)?, &new_key, self.dump)?;
+        Ok(match target {
+            None => Some(Diff::Missing),
+            Some(target) if target.kind != source.kind => Some(Diff::Differ("类型不一致")),
+            Some(target) if target.digest != source.digest => Some(Diff::Differ("值不一致")),
+            Some(target) if !ttl_match(source.ttl, target.ttl) => Some(Diff::Differ("过期时间不一致")),
+            _ => None,
+        })
+    }
+
+    // 遍历目的Redis中复制范围之内的key, 不对应于任何源key的即为多余的key. 目的Redis可能同时保存了其他数据,
+    // 因此只检查需要复制的源db所映射的db, 且只检查按照过滤与重命名规则可能由复制产生的key
+    fn find_extra(&mut self) -> RedisResult<()> {
+        for node in self.targets.nodes()? {
+            for db in reshard::keyspace(self.targets.connection(&node, 0)?)? {
+                if !self.filter.accept_target_db(db) {
+                    continue;
+                }
+                let (filter, renamer) = (&self.filter, &self.renamer);
+                let seen = self.seen.get(&(node.clone(), db));
+                let conn = self.targets.connection(&node, db)?;
+                let mut cursor = 0u64;
+                loop {
+                    let (next, keys): (u64, Vec<Vec<u8>>) =
+                        redis::cmd("SCAN").arg(cursor).arg("COUNT").arg(1000).query(conn)?;
+                    for key in keys {
+                        if !renamer.may_produce(&key, |origin| filter.accept(origin)) {
+                            continue;
+                        }
+                        if seen.map_or(true, |seen| !seen.contains(&md5::compute(&key).0)) {
+                            let divergence = Divergence {
+                                diff: Diff::Extra,
+                                addr: node.clone(),
+                                db,
+                                key,
+                            };
+                            divergence.report();
+                            self.divergences.push(divergence);
+                        }
+                    }
+                    if next == 0 {
+                        break;
+                    }
+                    cursor = next;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn ttl_match(source: i64, target: i64) -> bool {
+    if source < 0 || target < 0 {
+        source < 0 && target < 0
+    } else {
+        (source - target).abs() <= TTL_TOLERANCE
+    }
+}
+
+// key的类型、剩余的过期时间(毫秒, 负数表示不过期)及值的摘要
+pub(crate) struct Snapshot {
+    kind: String,
+    ttl: i64,
+    digest: [u8; 16],
+}
+
+impl Snapshot {
+    // 类型、值及过期时间均一致, 不受编码方式(如listpack与hashtable)及元素顺序的影响
+    pub(crate) fn matches(&self, other: &Snapshot) -> bool {
+        self.kind == other.kind && self.digest == other.digest && ttl_match(self.ttl, other.ttl)
+    }
+}
+
+// 读取key的值并计算摘要, 集合类型的元素排序之后再计算. dump为true时使用DUMP的结果计算摘要
+pub(crate) fn snapshot(conn: &mut Connection, key: &[u8], dump: bool) -> RedisResult<Option<Snapshot>> {
+    let (kind, ttl): (String, i64) = redis::pipe().cmd("TYPE").arg(key).cmd("PTTL").arg(key).query(conn)?;
+    if kind == "none" {
+        return Ok(None);
+    }
+    let mut context = md5::Context::new();
+    match kind.as_str() {
+        _ if dump => {
+            let value: Option<Vec<u8>> = redis::cmd("DUMP").arg(key).query(conn)?;
+            feed(&mut context, &value.unwrap_or_default());
+        }
+        "string" => {
+            let value: Vec<u8> = redis::cmd("GET").arg(key).query(conn)?;
+            feed(&mut context, &value);
+        }
+        "list" | "set" | "zset" => {
+            let mut values: Vec<Vec<u8>> = match kind.as_str() {
+                "list" => redis::cmd("LRANGE").arg(key).arg(0).arg(-1).query(conn)?,
+                "set" => redis::cmd("SMEMBERS").arg(key).query(conn)?,
+                _ => redis::cmd("ZRANGE")
+                    .arg(key)
+                    .arg(0)
+                    .arg(-1)
+                    .arg("WITHSCORES")
+                    .query(conn)?,
+            };
+            if kind == "set" {
+                values.sort();
+            }
+            values.iter().for_each(|value| feed(&mut context, value));
+        }
+        "hash" => {
+            let values: Vec<Vec<u8>> = redis::cmd("HGETALL").arg(key).query(conn)?;
+            let mut pairs: Vec<&[Vec<u8>]> = values.chunks(2).collect();
+            pairs.sort();
+            pairs.concat().iter().for_each(|value| feed(&mut context, value));
+        }
+        "stream" => {
+            let value: Value = redis::cmd("XRANGE").arg(key).arg("-").arg("+").query(conn)?;
+            feed_value(&mut context, &value);
+        }
+        // 无法读取的类型(如module类型)比较DUMP的结果
+        _ => {
+            let value: Option<Vec<u8>> = redis::cmd("DUMP").arg(key).query(conn)?;
+            feed(&mut context, &value.unwrap_or_default());
+        }
+    }
+    Ok(Some(Snapshot {
+        kind,
+        ttl,
+        digest: context.compute().0,
+    }))
+}
+
+// 在每个元素之前写入其长度, 避免不同的元素拼接之后相同
+fn feed(context: &mut md5::Context, data: &[u8]) {
+    context.consume((data.len() as u64).to_le_bytes());
+    context.consume(data);
+}
+
+fn feed_value(context: &mut md5::Context, value: &Value) {
+    match value {
+        Value::Data(data) => feed(context, data),
+        Value::Bulk(items) => {
+            feed(context, &(items.len() as u64).to_le_bytes());
+            items.iter().for_each(|item| feed_value(context, item));
+        }
+        Value::Int(n) => feed(context, &n.to_le_bytes()),
+        Value::Status(status) => feed(context, status.as_bytes()),
+        Value::Nil | Value::Okay => feed(context, &[]),
+    }
+}
+
+// 目的Redis, 按照与复制时相同的方式(普通/Sharding/Cluster模式)定位key所在的节点. 节点以host:port表示
+pub(crate) struct Targets {
+    layout: Option<Layout>,
+    cluster: Option<ClusterWriter>,
+    nodes: Vec<(String, String)>,
+    conns: HashMap<(String, i64), Connection>,
+}
+
+impl Targets {
+    pub(crate) fn new(opt: &Opt) -> Targets {
+        if opt.sharding && opt.cluster {
+            panic!("不能同时指定sharding与cluster")
+        }
+        let layout = if opt.sharding {
+            Some(Layout::new(
+                &opt.targets,
+                opt.shard_strategy,
+                opt.shard_hash,
+                opt.shard_key_tag.clone(),
+            ))
+        } else {
+            None
+        };
+        let cluster = if opt.cluster {
+            Some(ClusterWriter::new(&opt.targets, "copy_redis::verify"))
+        } else {
+            None
+        };
+        let mut nodes: Vec<(String, String)> = opt.targets.iter().map(|url| (addr(url), url.clone())).collect();
+        if !opt.sharding {
+            nodes.truncate(1);
+        }
+        Targets {
+            layout,
+            cluster,
+            nodes,
+            conns: HashMap::new(),
+        }
+    }
+
+    fn owner(&mut self, key: &[u8]) -> RedisResult<String> {
+        if let Some(cluster) = self.cluster.as_mut() {
+            return cluster.owner(key);
+        }
+        let i = match &self.layout {
+            Some(layout) => layout.owner(key),
+            None => 0,
+        };
+        Ok(self.nodes[i].0.clone())
+    }
+
+    fn nodes(&mut self) -> RedisResult<Vec<String>> {
+        match self.cluster.as_mut() {
+            Some(cluster) => {
+                cluster.refresh_slots()?;
+                Ok(cluster.masters())
+            }
+            None => Ok(self.nodes.iter().map(|(addr, _)| addr.clone()).collect()),
+        }
+    }
+
+    // Cluster只有db0, 因此忽略db
+    pub(crate) fn connection(&mut self, node: &str, db: i64) -> RedisResult<&mut Connection> {
+        if let Some(cluster) = self.cluster.as_mut() {
+            return cluster.connection(node);
+        }
+        let key = (node.to_string(), db);
+        if !self.conns.contains_key(&key) {
+            let url = match self.nodes.iter().find(|(addr, _)| addr == node) {
+                Some((_, url)) => url,
+                None => return Err(RedisError::from((ErrorKind::ClientError, "未通过--target指定的节点"))),
+            };
+            let mut conn = redis::Client::open(url.as_str())?.get_connection()?;
+            redis::cmd("SELECT").arg(db).query::<()>(&mut conn)?;
+            self.conns.insert(key.clone(), conn);
+        }
+        Ok(self.conns.get_mut(&key).unwrap())
+    }
+
+    // 清空所有节点(所有分片, 或Cluster的所有master), 返回清空的节点. dbs为None时FLUSHALL, 否则FLUSHDB其中的每个db
+    pub(crate) fn flush(&mut self, dbs: Option<&[i64]>) -> RedisResult<Vec<String>> {
+        let nodes = self.nodes()?;
+        for node in &nodes {
+            match dbs {
+                None => redis::cmd("FLUSHALL").query::<()>(self.connection(node, 0)?)?,
+                Some(dbs) => {
+                    for db in dbs {
+                        redis::cmd("FLUSHDB").query::<()>(self.connection(node, *db)?)?;
+                    }
+                }
+            }
+        }
+        Ok(nodes)
+    }
+
+    // key是否已存在于其所在的节点, 失败时重新连接(Cluster模式下刷新slot分布)后重试一次
+    pub(crate) fn exists(&mut self, db: i64, key: &[u8]) -> RedisResult<bool> {
+        let mut result = self.try_exists(db, key);
+        if result.is_err() {
+            self.conns.clear();
+            self.refresh();
+            result = self.try_exists(db, key);
+        }
+        result
+    }
+
+    fn try_exists(&mut self, db: i64, key: &[u8]) -> RedisResult<bool> {
+        let node = self.owner(key)?;
+        let conn = self.connection(&node, db)?;
+        redis::cmd("EXISTS").arg(key).query(conn)
+    }
+
+    // Cluster的slot分布可能已经变化
+    fn refresh(&mut self) {
+        if let Some(cluster) = self.cluster.as_mut() {
+            if let Err(err) = cluster.refresh_slots() {
+                warn!("获取Cluster的slot分布失败: {}", err);
+            }
+        }
+    }
+}
@@ -1,29 +1,140 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
 use std::error;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
 use std::ops::DerefMut;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SendError, Sender, SyncSender, TrySendError};
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use log::{error, info};
+use log::{error, info, warn};
+use prometheus::IntGauge;
 use r2d2_redis::r2d2::{CustomizeConnection, HandleError};
 use r2d2_redis::redis::{Connection, IntoConnectionInfo};
 use r2d2_redis::{r2d2, RedisConnectionManager};
+use redis::{Cmd, ErrorKind, RedisError};
 use scheduled_thread_pool::ScheduledThreadPool;
 
-pub(crate) struct Worker {
-    pub(crate) thread: Option<thread::JoinHandle<()>>,
+use crate::metrics;
+
+// 写入目的Redis的worker, 在程序启动时创建一次, 由各个源的handler共用.
+// 各个源的命令交替发送至同一个worker, 因此每条命令都带有其所属的db(为None时使用目的Redis地址中的db)
+pub(crate) struct Workers {
+    pub(crate) senders: BTreeMap<String, MessageSender>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl Workers {
+    pub(crate) fn new() -> Workers {
+        Workers {
+            senders: BTreeMap::new(),
+            threads: Vec::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, name: &str, sender: MessageSender, thread: thread::JoinHandle<()>) {
+        self.senders.insert(name.to_string(), sender);
+        self.threads.push(thread);
+    }
+}
+
+// 所有源的listener都结束之后, 等待worker写入剩余的命令再退出
+impl Drop for Workers {
+    fn drop(&mut self) {
+        for sender in self.senders.values() {
+            if let Err(_) = sender.send(Message::Terminate) {}
+        }
+        for thread in self.threads.drain(..) {
+            if let Err(_) = thread.join() {}
+        }
+    }
 }
 
 pub(crate) enum Message {
-    Cmd(redis::Cmd),
-    SwapDb(i64),
+    Cmd(redis::Cmd, Option<i64>),
+    // 需要在同一个连接上通过MULTI/EXEC原子执行的一组命令
+    Transaction(Vec<redis::Cmd>, Option<i64>),
+    // 立即写入已接收的命令, 完成后通过Sender进行通知
+    Flush(Sender<()>),
     Terminate,
 }
 
+// 在SyncSender的基础上记录channel中尚未被worker处理的消息数量.
+// 队列已满时send会阻塞, 从而暂停从源Redis读取数据, 直到worker处理完积压的命令
+#[derive(Clone)]
+pub(crate) struct MessageSender {
+    sender: SyncSender<Message>,
+    name: String,
+    capacity: usize,
+    depth: IntGauge,
+    last_warned: Cell<Option<Instant>>,
+}
+
+impl MessageSender {
+    pub(crate) fn send(&self, message: Message) -> Result<(), SendError<Message>> {
+        self.depth.inc();
+        let result = match self.sender.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Disconnected(message)) => Err(SendError(message)),
+            Err(TrySendError::Full(message)) => {
+                metrics::BACKPRESSURE.with_label_values(&[&self.name]).inc();
+                let warned = self.last_warned.get();
+                if warned.map_or(true, |warned| warned.elapsed() >= Duration::from_secs(10)) {
+                    warn!(
+                        "{}的队列已满(容量: {}), 等待目的Redis写入积压的命令, 暂停读取源Redis",
+                        self.name, self.capacity
+                    );
+                    self.last_warned.set(Some(Instant::now()));
+                }
+                self.sender.send(message)
+            }
+        };
+        if result.is_err() {
+            self.depth.dec();
+        }
+        result
+    }
+}
+
+// 创建worker所使用的channel, name与worker线程的名称一致
+pub(crate) fn channel(name: &str, capacity: usize) -> (MessageSender, Receiver<Message>) {
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    let sender = MessageSender {
+        sender,
+        name: name.to_string(),
+        capacity,
+        depth: metrics::QUEUE_DEPTH.with_label_values(&[name]),
+        last_warned: Cell::new(None),
+    };
+    (sender, receiver)
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct RetryConfig {
+    // 写入失败时的最大重试次数
+    pub(crate) max_retries: u32,
+    // 首次重试前的等待时间(毫秒), 之后每次翻倍
+    pub(crate) interval: u64,
+    // 重试后仍然失败的命令所保存的目录
+    pub(crate) dead_letter_dir: Option<String>,
+}
+
+impl RetryConfig {
+    // 第attempt次重试前的等待时间
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        Duration::from_millis(self.interval.saturating_mul(1 << (attempt - 1).min(10)))
+    }
+}
+
 pub(crate) fn new_worker(
-    target: String, receiver: Receiver<Message>, name: &str, batch_size: i32, flush_interval: u64,
+    target: String, receiver: Receiver<Message>, name: &str, batch_size: i32, flush_interval: u64, retry: RetryConfig,
     control_flag: Arc<AtomicBool>, thread_pool: Arc<ScheduledThreadPool>,
 ) -> thread::JoinHandle<()> {
     let builder = thread::Builder::new().name(name.into());
@@ -36,7 +147,8 @@ pub(crate) fn new_worker(
                 .as_str()
                 .into_connection_info()
                 .expect("解析Target Redis地址失败");
-            let db: Arc<AtomicI64> = Arc::new(AtomicI64::new(conn_info.db));
+            let default_db = conn_info.db;
+            let db: Arc<AtomicI64> = Arc::new(AtomicI64::new(default_db));
 
             let manager = RedisConnectionManager::new(target).unwrap();
             let pool = r2d2::Pool::builder()
@@ -53,7 +165,10 @@ pub(crate) fn new_worker(
                 .build(manager)
                 .unwrap();
 
-            let mut pipeline = redis::pipe();
+            let mut dead_letter = DeadLetter::new(&retry.dead_letter_dir, t_name);
+            let depth = metrics::QUEUE_DEPTH.with_label_values(&[t_name]);
+            // 每条命令(或事务)都记录其所属的db, 以便重试及写入死信文件时能恢复到正确的db
+            let mut batch: Vec<(Unit, i64)> = Vec::new();
             let mut count = 0;
             let mut timer = Instant::now();
             let interval = Duration::from_millis(flush_interval);
@@ -61,41 +176,43 @@ pub(crate) fn new_worker(
 
             loop {
                 if (batch_size < 0) || (count < batch_size) {
-                    match receiver.recv_timeout(Duration::from_millis(10)) {
-                        Ok(Message::Cmd(cmd)) => {
-                            pipeline.add_command(cmd);
-                            db.load(Ordering::Relaxed);
+                    let message = receiver.recv_timeout(Duration::from_millis(10));
+                    if message.is_ok() {
+                        depth.dec();
+                    }
+                    match message {
+                        // 写入时按照每条命令的db切换, 连接重建时切换至最近一条命令的db
+                        Ok(Message::Cmd(cmd, _db)) => {
+                            let _db = _db.unwrap_or(default_db);
+                            db.store(_db, Ordering::SeqCst);
+                            batch.push((Unit::Cmd(cmd), _db));
                             count += 1;
                         }
+                        Ok(Message::Transaction(cmds, _db)) => {
+                            let _db = _db.unwrap_or(default_db);
+                            db.store(_db, Ordering::SeqCst);
+                            count += cmds.len() as i32;
+                            batch.push((Unit::Transaction(cmds), _db));
+                        }
                         Ok(Message::Terminate) => {
                             shutdown = true;
                         }
-                        Ok(Message::SwapDb(_db)) => {
-                            db.store(_db, Ordering::SeqCst);
+                        Ok(Message::Flush(ack)) => {
+                            if count > 0 {
+                                flush(&pool, &mut batch, &retry, &mut dead_letter, t_name);
+                                timer = Instant::now();
+                                count = 0;
+                            }
+                            if let Err(_) = ack.send(()) {}
                         }
                         Err(_) => {}
                     }
                 }
                 let elapsed = timer.elapsed();
                 if (elapsed.ge(&interval) || shutdown) && count > 0 {
-                    match pool.get() {
-                        Ok(mut conn) => {
-                            match pipeline.query(conn.deref_mut()) {
-                                Err(err) => {
-                                    error!(target: t_name, "数据写入失败: {}", err);
-                                }
-                                Ok(()) => {
-                                    info!(target: t_name, "写入成功: {}", count);
-                                }
-                            };
-                            timer = Instant::now();
-                            pipeline.clear();
-                            count = 0;
-                        }
-                        Err(err) => {
-                            error!(target: t_name, "{}", err);
-                        }
-                    }
+                    flush(&pool, &mut batch, &retry, &mut dead_letter, t_name);
+                    timer = Instant::now();
+                    count = 0;
                 }
                 if shutdown {
                     break;
@@ -104,7 +221,261 @@ pub(crate) fn new_worker(
             info!(target: t_name, "Worker thread terminated");
         })
         .unwrap();
-    return worker;
+    worker
+}
+
+// 将batch中的命令写入目的Redis, 失败的命令按照RetryConfig进行重试, 最终仍失败的命令写入死信文件.
+// 事务作为一个整体进行重试及写入死信文件
+fn flush(
+    pool: &r2d2::Pool<RedisConnectionManager>, batch: &mut Vec<(Unit, i64)>, retry: &RetryConfig,
+    dead_letter: &mut DeadLetter, t_name: &str,
+) {
+    let mut pending: Vec<(Unit, i64)> = batch.drain(..).collect();
+    let mut attempts = 0;
+    while !pending.is_empty() {
+        if attempts > 0 {
+            let backoff = retry.backoff(attempts);
+            warn!(target: t_name, "{}ms后进行第{}次重试, 命令数量: {}", backoff.as_millis(), attempts, pending.len());
+            thread::sleep(backoff);
+        }
+        let can_retry = attempts < retry.max_retries;
+        let outcome = match pool.get() {
+            Ok(mut conn) => {
+                let timer = metrics::FLUSH_LATENCY.with_label_values(&[t_name]).start_timer();
+                let outcome = write_batch(conn.deref_mut(), &pending, t_name);
+                timer.observe_duration();
+                outcome
+            }
+            Err(err) => {
+                error!(target: t_name, "{}", err);
+                Outcome {
+                    failed: Vec::new(),
+                    unanswered: Some(0),
+                }
+            }
+        };
+        let answered = outcome.unanswered.unwrap_or(pending.len());
+
+        let mut succeeded = 0;
+        let mut retries = Vec::new();
+        let mut failed = outcome.failed.into_iter().peekable();
+        for (i, (unit, db)) in pending.into_iter().enumerate() {
+            if i >= answered {
+                if can_retry {
+                    retries.push((unit, db));
+                } else {
+                    unit.write_dead_letter(db, dead_letter);
+                }
+            } else if let Some((_, err)) = failed.next_if(|(idx, _)| *idx == i) {
+                if can_retry && is_retryable(&err) {
+                    warn!(target: t_name, "命令写入失败, 稍后重试: {}", err);
+                    retries.push((unit, db));
+                } else {
+                    error!(target: t_name, "数据写入失败: {}", err);
+                    unit.write_dead_letter(db, dead_letter);
+                }
+            } else {
+                succeeded += unit.len();
+            }
+        }
+        if succeeded > 0 {
+            info!(target: t_name, "写入成功: {}", succeeded);
+            metrics::COMMANDS_WRITTEN
+                .with_label_values(&[t_name])
+                .inc_by(succeeded as u64);
+        }
+        pending = retries;
+        attempts += 1;
+    }
+}
+
+// 写入的最小单位: 单条命令, 或需要在同一个连接上以MULTI/EXEC原子执行的事务
+pub(crate) enum Unit {
+    Cmd(Cmd),
+    Transaction(Vec<Cmd>),
+}
+
+impl Unit {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Unit::Cmd(_) => 1,
+            Unit::Transaction(cmds) => cmds.len(),
+        }
+    }
+
+    // 写入后需要读取的响应数量
+    pub(crate) fn replies(&self) -> usize {
+        match self {
+            Unit::Cmd(_) => 1,
+            Unit::Transaction(cmds) => cmds.len() + 2,
+        }
+    }
+
+    pub(crate) fn pack(&self, packed: &mut Vec<u8>) {
+        match self {
+            Unit::Cmd(cmd) => packed.extend(cmd.get_packed_command()),
+            Unit::Transaction(cmds) => {
+                packed.extend(redis::cmd("MULTI").get_packed_command());
+                for cmd in cmds {
+                    packed.extend(cmd.get_packed_command());
+                }
+                packed.extend(redis::cmd("EXEC").get_packed_command());
+            }
+        }
+    }
+
+    pub(crate) fn write_dead_letter(&self, db: i64, dead_letter: &mut DeadLetter) {
+        match self {
+            Unit::Cmd(cmd) => dead_letter.write(db, cmd),
+            Unit::Transaction(cmds) => {
+                dead_letter.write(db, &redis::cmd("MULTI"));
+                for cmd in cmds {
+                    dead_letter.write(db, cmd);
+                }
+                dead_letter.write(db, &redis::cmd("EXEC"));
+            }
+        }
+    }
+}
+
+struct Outcome {
+    // 返回错误的命令(或事务)及其下标
+    failed: Vec<(usize, RedisError)>,
+    // 连接中断时, 尚未收到全部响应的第一条命令(或事务)的下标, 从此往后的命令是否已执行无法确定
+    unanswered: Option<usize>,
+}
+
+// 以pipeline的方式发送命令, 但逐条读取响应, 以免个别命令的错误导致整批命令被当作失败. 事务中的命令在排队时出错会导致EXEC失败,
+// 此时以第一个错误作为整个事务的结果.
+// 连接可能已被重建(此时所在的db为最新的db), 且重试时只会发送其中一部分命令, 因此在第一条命令之前, 以及db发生变化时都先切换db
+fn write_batch(conn: &mut Connection, units: &[(Unit, i64)], t_name: &str) -> Outcome {
+    let mut packed = Vec::new();
+    let mut selects = Vec::with_capacity(units.len());
+    let mut current = None;
+    for (unit, db) in units {
+        let select = current != Some(*db);
+        if select {
+            packed.extend(redis::cmd("SELECT").arg(*db).get_packed_command());
+            current = Some(*db);
+        }
+        selects.push(select);
+        unit.pack(&mut packed);
+    }
+    let mut outcome = Outcome {
+        failed: Vec::new(),
+        unanswered: None,
+    };
+    if let Err(err) = conn.send_packed_command(&packed) {
+        error!(target: t_name, "数据写入失败: {}", err);
+        outcome.unanswered = Some(0);
+        return outcome;
+    }
+    // 切换db失败时, 该db的命令已在其他db中执行, 均视为失败
+    let mut select_err = None;
+    for (i, ((unit, _), select)) in units.iter().zip(selects).enumerate() {
+        if select {
+            select_err = match conn.recv_response() {
+                Ok(_) => None,
+                Err(err) if err.is_io_error() => {
+                    error!(target: t_name, "切换db失败: {}", err);
+                    outcome.unanswered = Some(i);
+                    return outcome;
+                }
+                Err(err) => {
+                    error!(target: t_name, "切换db失败: {}", err);
+                    Some(err.kind())
+                }
+            };
+        }
+        let mut result = Ok(());
+        for _ in 0..unit.replies() {
+            match conn.recv_response() {
+                Err(err) if err.is_io_error() => {
+                    error!(target: t_name, "数据写入失败: {}", err);
+                    outcome.unanswered = Some(i);
+                    return outcome;
+                }
+                Err(err) if result.is_ok() => result = Err(err),
+                _ => {}
+            }
+        }
+        match (result, select_err) {
+            (Err(err), _) => outcome.failed.push((i, err)),
+            (Ok(()), Some(kind)) => outcome.failed.push((i, RedisError::from((kind, "切换db失败")))),
+            (Ok(()), None) => {}
+        }
+    }
+    outcome
+}
+
+pub(crate) fn is_retryable(err: &RedisError) -> bool {
+    if err.is_io_error() {
+        return true;
+    }
+    match err.kind() {
+        ErrorKind::BusyLoadingError | ErrorKind::TryAgain | ErrorKind::ClusterDown => true,
+        ErrorKind::ExtensionError => {
+            let err = err.to_string();
+            err.starts_with("BUSY") || err.starts_with("MASTERDOWN") || err.starts_with("OOM")
+        }
+        _ => false,
+    }
+}
+
+// 以RESP格式保存最终写入失败的命令, 可通过`redis-cli --pipe`重放
+pub(crate) struct DeadLetter {
+    thread_name: String,
+    path: Option<PathBuf>,
+    file: Option<File>,
+    db: Option<i64>,
+}
+
+impl DeadLetter {
+    pub(crate) fn new(dir: &Option<String>, worker_name: &str) -> DeadLetter {
+        let path = dir.as_ref().map(|dir| {
+            let name: String = worker_name
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+                .collect();
+            PathBuf::from(dir).join(format!("{}.aof", name))
+        });
+        DeadLetter {
+            thread_name: worker_name.to_string(),
+            path,
+            file: None,
+            db: None,
+        }
+    }
+
+    pub(crate) fn write(&mut self, db: i64, cmd: &Cmd) {
+        metrics::COMMANDS_FAILED.with_label_values(&[&self.thread_name]).inc();
+        if self.path.is_none() {
+            return;
+        }
+        if let Err(err) = self.append(db, cmd) {
+            let path = self.path.as_ref().unwrap();
+            error!(target: &self.thread_name, "写入死信文件{}失败: {}", path.display(), err);
+        }
+    }
+
+    fn append(&mut self, db: i64, cmd: &Cmd) -> io::Result<()> {
+        if self.file.is_none() {
+            let path = self.path.as_ref().unwrap();
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            self.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        }
+        let file = self.file.as_mut().unwrap();
+        if self.db != Some(db) {
+            let mut select = redis::cmd("SELECT");
+            select.arg(db);
+            file.write_all(&select.get_packed_command())?;
+            self.db = Some(db);
+        }
+        file.write_all(&cmd.get_packed_command())?;
+        file.flush()
+    }
 }
 
 #[derive(Debug)]
@@ -146,3 +517,218 @@ impl CustomizeConnection<Connection, r2d2_redis::Error> for ConnectionCustomizer
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use r2d2_redis::{r2d2, RedisConnectionManager};
+    use redis::Cmd;
+
+    use crate::tests::fake_redis;
+    use crate::worker::{flush, DeadLetter, RetryConfig, Unit};
+
+    fn pool(addr: &str) -> r2d2::Pool<RedisConnectionManager> {
+        let manager = RedisConnectionManager::new(format!("redis://{}", addr).as_str()).unwrap();
+        r2d2::Pool::builder().max_size(1).build(manager).unwrap()
+    }
+
+    fn cmd(key: &str) -> Cmd {
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(key).arg(1);
+        cmd
+    }
+
+    fn set(key: &str) -> Unit {
+        Unit::Cmd(cmd(key))
+    }
+
+    // 模拟的Redis对事务中的命令回复QUEUED
+    fn queue(in_multi: &mut bool, args: &[String], reply: &str) -> Option<String> {
+        match args[0].as_str() {
+            "MULTI" => {
+                *in_multi = true;
+                Some("+OK\r\n".to_string())
+            }
+            "EXEC" => {
+                *in_multi = false;
+                Some(reply.to_string())
+            }
+            _ if *in_multi => Some("+QUEUED\r\n".to_string()),
+            _ => Some("+OK\r\n".to_string()),
+        }
+    }
+
+    fn retry(dead_letter_dir: Option<String>) -> RetryConfig {
+        RetryConfig {
+            max_retries: 2,
+            interval: 1,
+            dead_letter_dir,
+        }
+    }
+
+    fn written(received: &[(i64, Vec<String>)]) -> Vec<(i64, String)> {
+        received.iter().map(|(db, args)| (*db, args[1].clone())).collect()
+    }
+
+    #[test]
+    fn test_retry_across_db_switch() {
+        // 第一次写入db 1与db 2中的key时返回可重试的错误
+        let mut failed = Vec::new();
+        let redis = fake_redis::start(move |_, args| {
+            let key = args[1].clone();
+            if key != "in_db0" && !failed.contains(&key) {
+                failed.push(key);
+                return Some("-TRYAGAIN Multiple keys request during rehashing of slot\r\n".to_string());
+            }
+            Some("+OK\r\n".to_string())
+        });
+        let mut batch = vec![(set("in_db0"), 0), (set("in_db1"), 1), (set("in_db2"), 2)];
+        let mut dead_letter = DeadLetter::new(&None, "worker_test");
+        flush(
+            &pool(&redis.addr),
+            &mut batch,
+            &retry(None),
+            &mut dead_letter,
+            "worker_test",
+        );
+
+        let received = redis.received.lock().unwrap();
+        // 重试的命令仍写入各自的db
+        assert_eq!(
+            written(&received[3..]),
+            vec![(1, "in_db1".to_string()), (2, "in_db2".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_retry_after_connection_closed() {
+        // 写入in_db2时连接中断, 之后的命令是否已执行无法确定, 重新连接后从in_db2开始重试
+        let mut closed = false;
+        let redis = fake_redis::start(move |_, args| {
+            if args[1] == "in_db2" && !closed {
+                closed = true;
+                return None;
+            }
+            Some("+OK\r\n".to_string())
+        });
+        let mut batch = vec![
+            (set("in_db1"), 1),
+            (set("in_db2"), 2),
+            (set("in_db2_1"), 2),
+            (set("in_db1_1"), 1),
+        ];
+        let mut dead_letter = DeadLetter::new(&None, "worker_test");
+        flush(
+            &pool(&redis.addr),
+            &mut batch,
+            &retry(None),
+            &mut dead_letter,
+            "worker_test",
+        );
+
+        let received = redis.received.lock().unwrap();
+        assert_eq!(
+            written(&received[2..]),
+            vec![
+                (2, "in_db2".to_string()),
+                (2, "in_db2_1".to_string()),
+                (1, "in_db1_1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dead_letter() {
+        let redis = fake_redis::start(|_, args| match args[1].as_str() {
+            "wrong_type" => Some("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+            "busy" => Some("-BUSY Redis is busy running a script\r\n".to_string()),
+            _ => Some("+OK\r\n".to_string()),
+        });
+        let dir = "dead_letter_test";
+        let retry = retry(Some(dir.to_string()));
+        let mut dead_letter = DeadLetter::new(&retry.dead_letter_dir, "worker::test");
+        let mut batch = vec![
+            (set("ok"), 0),
+            (set("wrong_type"), 3),
+            (set("busy"), 3),
+            (set("busy"), 0),
+        ];
+        flush(&pool(&redis.addr), &mut batch, &retry, &mut dead_letter, "worker::test");
+
+        let content = fs::read(PathBuf::from(dir).join("worker__test.aof")).unwrap();
+        let _ = fs::remove_dir_all(dir);
+        // 不可重试的命令立即写入, 可重试的命令在重试次数用尽后写入, 每次db发生变化时先写入SELECT
+        let expected = "*2\r\n$6\r\nSELECT\r\n$1\r\n3\r\n*3\r\n$3\r\nSET\r\n$10\r\nwrong_type\r\n$1\r\n1\r\n\
+                        *3\r\n$3\r\nSET\r\n$4\r\nbusy\r\n$1\r\n1\r\n\
+                        *2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*3\r\n$3\r\nSET\r\n$4\r\nbusy\r\n$1\r\n1\r\n";
+        assert_eq!(String::from_utf8(content).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_retry_transaction() {
+        // 第一次EXEC时返回可重试的错误, 整个事务(而不是其中的一部分命令)被重新发送
+        let mut in_multi = false;
+        let mut failed = false;
+        let redis = fake_redis::start(move |_, args| {
+            if args[0] == "EXEC" && !failed {
+                failed = true;
+                return queue(
+                    &mut in_multi,
+                    args,
+                    "-TRYAGAIN Multiple keys request during rehashing of slot\r\n",
+                );
+            }
+            queue(&mut in_multi, args, "*2\r\n+OK\r\n+OK\r\n")
+        });
+        let mut batch = vec![
+            (set("before"), 0),
+            (Unit::Transaction(vec![cmd("{tx}1"), cmd("{tx}2")]), 1),
+            (set("after"), 0),
+        ];
+        let mut dead_letter = DeadLetter::new(&None, "worker_test");
+        flush(
+            &pool(&redis.addr),
+            &mut batch,
+            &retry(None),
+            &mut dead_letter,
+            "worker_test",
+        );
+
+        let received = redis.received.lock().unwrap();
+        let retried: Vec<(i64, String)> = received[6..].iter().map(|(db, args)| (*db, args.join(" "))).collect();
+        assert_eq!(
+            retried,
+            vec![
+                (1, "MULTI".to_string()),
+                (1, "SET {tx}1 1".to_string()),
+                (1, "SET {tx}2 1".to_string()),
+                (1, "EXEC".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transaction_dead_letter() {
+        // 连接在事务中途中断, 重试次数用尽后整个事务写入死信文件
+        let redis = fake_redis::start(|_, args| {
+            if args[0] == "EXEC" {
+                None
+            } else {
+                Some("+OK\r\n".to_string())
+            }
+        });
+        let dir = "dead_letter_transaction_test";
+        let retry = retry(Some(dir.to_string()));
+        let mut dead_letter = DeadLetter::new(&retry.dead_letter_dir, "worker_test");
+        let mut batch = vec![(Unit::Transaction(vec![cmd("a")]), 2)];
+        flush(&pool(&redis.addr), &mut batch, &retry, &mut dead_letter, "worker_test");
+
+        let content = fs::read(PathBuf::from(dir).join("worker_test.aof")).unwrap();
+        let _ = fs::remove_dir_all(dir);
+        let expected = "*2\r\n$6\r\nSELECT\r\n$1\r\n2\r\n*1\r\n$5\r\nMULTI\r\n\
+                        *3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n*1\r\n$4\r\nEXEC\r\n";
+        assert_eq!(String::from_utf8(content).unwrap(), expected);
+    }
+}